@@ -0,0 +1,984 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper1::body::{Body, Frame, Incoming, SizeHint};
+use hyper1::server::conn::http1;
+use hyper1::service::service_fn;
+use hyper_util::rt::TokioIo;
+use parking_lot::{Mutex, RwLock};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::clip::{self_signed_tls_config, start_clip_v2_https_server};
+use crate::http::{HttpRequest, HttpResponse, HttpServer, HttpService};
+use crate::mdns::HueBridgeAdvertisement;
+use crate::registry::DeviceRegistry;
+use crate::sensors::VirtualSensorsList;
+use crate::ssdp::{
+    bind_address, bind_multicast_listener, bind_multicast_sender, multicast_address, notify_byebye,
+    Announcement, NetworkInterface, SsdpListener, ANNOUNCEMENT_BURST, BELKIN_MAX_AGE, HUE_MAX_AGE,
+};
+use crate::upnp::{hue_serial, HueBridgeHttpServerHandler, HueGroupKind, SharedDeviceRoutes};
+use crate::virtual_device::wrappers::*;
+use crate::virtual_device::*;
+use crate::whitelist::HueWhitelist;
+use crate::{
+    add_hosted_device, add_hosted_group, add_sensor, add_shared_device, remove_hosted_device,
+    remove_sensor, rename_device, DeviceHost, DeviceRenamer, HueBridgeConfig, RustmoDevice,
+    RustmoDeviceInfo, RustmoError, VirtualDevicesList,
+};
+
+/// how many `VirtualDevice` calls may be in flight at once unless told otherwise
+pub const DEFAULT_MAX_BLOCKING_CALLS: usize = 16;
+
+///
+/// `AsyncRustmoServer` is a `RustmoServer` built on tokio and hyper 1.x.
+///
+/// Rather than a thread per device, every device is served from a single HTTP listener (with
+/// each device's URLs under `/device/{uuid}`), and SSDP runs on a tokio UDP socket.  All of it
+/// runs as tasks on the caller's tokio runtime.
+///
+/// `VirtualDevice` implementations are still blocking, so every request that might call into
+/// one is answered on tokio's blocking pool.  At most `max_blocking_calls` of those run at once
+/// and the rest wait their turn, so a burst of Alexa group commands can't spawn an unbounded
+/// number of threads.
+///
+/// The server stops when `::shutdown()` is called or the last clone of it is dropped.
+///
+#[derive(Clone)]
+pub struct AsyncRustmoServer {
+    devices: VirtualDevicesList,
+    sensors: VirtualSensorsList,
+    ip_address: IpAddr,
+    port: u16,
+    routes: SharedDeviceRoutes,
+    registry: Arc<Mutex<DeviceRegistry>>,
+    hue_whitelist: Arc<Mutex<HueWhitelist>>,
+    hue_bridge: Option<RustmoDeviceInfo>,
+    hue_bridge_mdns: Option<Arc<HueBridgeAdvertisement>>,
+    announcements: mpsc::UnboundedSender<Announcement>,
+    tasks: Arc<ServerTasks>,
+    clip_servers: Arc<Mutex<Vec<HttpServer>>>,
+}
+
+///
+/// The tasks backing an `AsyncRustmoServer`, which are aborted when the last clone of the server
+/// goes away
+///
+#[derive(Default)]
+struct ServerTasks(Mutex<Vec<JoinHandle<()>>>);
+
+impl ServerTasks {
+    fn abort(&self) {
+        for task in self.0.lock().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for ServerTasks {
+    fn drop(&mut self) {
+        self.abort()
+    }
+}
+
+///
+/// Runs blocking work on tokio's blocking pool, with no more than a fixed number of calls in
+/// flight at once
+///
+#[derive(Clone)]
+struct BlockingPool {
+    permits: Arc<Semaphore>,
+}
+
+impl BlockingPool {
+    fn new(max_blocking_calls: usize) -> Self {
+        BlockingPool {
+            permits: Arc::new(Semaphore::new(max_blocking_calls.max(1))),
+        }
+    }
+
+    ///
+    /// Run `f` once there's room for it, returning `None` if it couldn't run to completion
+    ///
+    async fn run<T, F>(&self, f: F) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let permit = self.permits.clone().acquire_owned().await.ok()?;
+        match tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f()
+        })
+        .await
+        {
+            Ok(result) => Some(result),
+            Err(e) => {
+                tracing::error!("blocking device call failed: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl AsyncRustmoServer {
+    ///
+    /// Create a new `AsyncRustmoServer` that listens for SSDP requests on the specified network
+    /// interface and serves every device from `port`
+    ///
+    pub async fn new(interface: IpAddr, port: u16) -> Result<Self, RustmoError> {
+        Self::with_hue_bridge_port(interface, port, 0).await
+    }
+
+    ///
+    /// Like `::new()`, but also emulate a Hue bridge listening on `hue_bridge_port` (which is
+    /// advertised as port 80) for dimmable devices
+    ///
+    pub async fn with_hue_bridge_port(
+        interface: IpAddr,
+        port: u16,
+        hue_bridge_port: u16,
+    ) -> Result<Self, RustmoError> {
+        Self::with_max_blocking_calls(interface, port, hue_bridge_port, DEFAULT_MAX_BLOCKING_CALLS)
+            .await
+    }
+
+    ///
+    /// Like `::with_hue_bridge_port()`, but allow up to `max_blocking_calls` `VirtualDevice`
+    /// calls to be in flight at once
+    ///
+    pub async fn with_max_blocking_calls(
+        interface: IpAddr,
+        port: u16,
+        hue_bridge_port: u16,
+        max_blocking_calls: usize,
+    ) -> Result<Self, RustmoError> {
+        let hue_bridge = (hue_bridge_port != 0).then(|| HueBridgeConfig::new(hue_bridge_port));
+        Self::start(interface, port, hue_bridge, max_blocking_calls).await
+    }
+
+    ///
+    /// Like `::new()`, but also emulate a Hue bridge described by `hue_bridge`, such as to give
+    /// it a name, or run more than one on the same network
+    ///
+    pub async fn with_hue_bridge(
+        interface: IpAddr,
+        port: u16,
+        hue_bridge: HueBridgeConfig,
+    ) -> Result<Self, RustmoError> {
+        Self::start(
+            interface,
+            port,
+            Some(hue_bridge),
+            DEFAULT_MAX_BLOCKING_CALLS,
+        )
+        .await
+    }
+
+    async fn start(
+        interface: IpAddr,
+        port: u16,
+        hue_bridge: Option<HueBridgeConfig>,
+        max_blocking_calls: usize,
+    ) -> Result<Self, RustmoError> {
+        let devices: VirtualDevicesList = Arc::new(RwLock::new(Vec::new()));
+        let sensors: VirtualSensorsList = Default::default();
+        let registry: Arc<Mutex<DeviceRegistry>> = Default::default();
+        let routes = SharedDeviceRoutes::default();
+        let hue_whitelist = Arc::new(Mutex::new(HueWhitelist::default()));
+        let blocking = BlockingPool::new(max_blocking_calls);
+        let tasks = Arc::new(ServerTasks::default());
+
+        let address = bind_address(interface, port);
+        let listener = TcpListener::bind(address).await.map_err(|e| {
+            tracing::error!("unable to start device listener on {}: {}", address, e);
+            RustmoError::bind_failed(port, e)
+        })?;
+        tracing::info!("serving devices from {}", address);
+        tasks.0.lock().push(tokio::spawn(serve(
+            listener,
+            routes.clone(),
+            blocking.clone(),
+        )));
+
+        let hue_bridge = if let Some(config) = hue_bridge {
+            let bridge = config.device_info(interface)?;
+            let hue_bridge_port = config.bind_port;
+            let address = match interface {
+                IpAddr::V4(_) => {
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), hue_bridge_port)
+                }
+                IpAddr::V6(_) => bind_address(interface, hue_bridge_port),
+            };
+            let listener = TcpListener::bind(address).await.map_err(|e| {
+                tracing::error!("unable to start Hue bridge server on {}: {}", address, e);
+                RustmoError::bind_failed(hue_bridge_port, e)
+            })?;
+            tracing::info!(
+                "starting Hue bridge HTTP server on {} advertised as http://{}:{}/description.xml",
+                address,
+                bridge.ip_address,
+                bridge.port
+            );
+            let handler = HueBridgeHttpServerHandler::new(
+                bridge.clone(),
+                devices.clone(),
+                sensors.clone(),
+                DeviceRenamer::new(&devices, &registry),
+                hue_whitelist.clone(),
+            );
+            tasks
+                .0
+                .lock()
+                .push(tokio::spawn(serve(listener, handler, blocking.clone())));
+            Some(bridge)
+        } else {
+            tracing::info!("Hue bridge disabled");
+            None
+        };
+
+        let network_interface = NetworkInterface::lookup(interface);
+        let socket = bind_multicast_listener(&network_interface)
+            .and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                UdpSocket::from_std(socket)
+            })
+            .map_err(|e| {
+                tracing::error!("unable to listen for SSDP on {}: {}", interface, e);
+                RustmoError::NetworkError(e)
+            })?;
+        tasks.0.lock().push(tokio::spawn(listen_for_discovery(
+            socket,
+            network_interface,
+            devices.clone(),
+            hue_bridge.clone(),
+            blocking.clone(),
+        )));
+
+        let hue_bridge_mdns = hue_bridge
+            .as_ref()
+            .and_then(|bridge| HueBridgeAdvertisement::publish(bridge, &[interface]))
+            .map(Arc::new);
+
+        let (announcements, pending_announcements) = mpsc::unbounded_channel();
+        tasks.0.lock().push(tokio::spawn(announce_periodically(
+            interface,
+            devices.clone(),
+            hue_bridge.clone(),
+            pending_announcements,
+            blocking,
+        )));
+
+        Ok(AsyncRustmoServer {
+            devices,
+            sensors,
+            ip_address: interface,
+            port,
+            routes,
+            registry,
+            hue_whitelist,
+            hue_bridge,
+            hue_bridge_mdns,
+            announcements,
+            tasks,
+            clip_servers: Default::default(),
+        })
+    }
+
+    ///
+    /// Remember device identities in the JSON file at `path`, creating it if necessary.
+    ///
+    /// See `RustmoServer::use_device_registry()`
+    ///
+    pub fn use_device_registry<P: AsRef<Path>>(&mut self, path: P) -> Result<(), RustmoError> {
+        self.registry.lock().load(path.as_ref()).map_err(|e| {
+            RustmoError::DeviceRegistryError(format!("{}: {}", path.as_ref().display(), e))
+        })
+    }
+
+    ///
+    /// Remember the usernames of Hue apps that have paired with the Hue bridge in the JSON file
+    /// at `path`, creating it if necessary.
+    ///
+    /// See `RustmoServer::use_hue_whitelist()`
+    ///
+    pub fn use_hue_whitelist<P: AsRef<Path>>(&mut self, path: P) -> Result<(), RustmoError> {
+        self.hue_whitelist.lock().load(path.as_ref()).map_err(|e| {
+            RustmoError::HueWhitelistError(format!("{}: {}", path.as_ref().display(), e))
+        })
+    }
+
+    ///
+    /// Press the Hue bridge's link button, allowing Hue apps to pair with it for the next 30
+    /// seconds.
+    ///
+    /// See `RustmoServer::press_link_button()`
+    ///
+    pub fn press_link_button(&self) {
+        self.hue_whitelist.lock().press_link_button();
+    }
+
+    ///
+    /// Serve the Hue bridge's CLIP v2 API over HTTPS on `bind_port`, alongside its legacy API.
+    ///
+    /// Unlike the rest of this server, the CLIP v2 API is served from threads of its own rather
+    /// than tokio tasks, since each of its event streams holds a connection open indefinitely.
+    ///
+    /// See `RustmoServer::use_clip_v2()`
+    ///
+    pub fn use_clip_v2(&mut self, bind_port: u16) -> Result<(), RustmoError> {
+        let Some(bridge) = self.hue_bridge.as_ref() else {
+            return Err(RustmoError::HueBridgeError(
+                "the Hue bridge is disabled".to_string(),
+            ));
+        };
+
+        let tls = self_signed_tls_config(&hue_serial(bridge)).map_err(|e| {
+            RustmoError::HueBridgeError(format!("unable to create a TLS certificate: {e}"))
+        })?;
+        let servers = start_clip_v2_https_server(
+            tls,
+            &[self.ip_address],
+            bind_port,
+            self.devices.clone(),
+            self.hue_whitelist.clone(),
+        );
+        if servers.is_empty() {
+            return Err(RustmoError::PortUnavailable(bind_port));
+        }
+
+        self.clip_servers.lock().extend(servers);
+        Ok(())
+    }
+
+    ///
+    /// Stop this `AsyncRustmoServer`.
+    ///
+    /// The SSDP and HTTP listeners stop and release their ports, the Hue bridge's mDNS
+    /// advertisement is withdrawn, and all devices are removed from the server.  Requests that
+    /// are already being answered are allowed to finish.
+    ///
+    pub fn shutdown(&mut self) {
+        tracing::info!("shutting down async Rustmo server on {}", self.ip_address);
+        self.tasks.abort();
+        self.clip_servers.lock().clear();
+        if let Some(hue_bridge_mdns) = self.hue_bridge_mdns.as_ref() {
+            hue_bridge_mdns.withdraw();
+        }
+        self.routes.clear();
+        self.sensors.write().clear();
+        self.devices.write().clear();
+    }
+
+    ///
+    /// Add a `VirtualDevice` to make it discoverable and controllable.
+    ///
+    /// See `RustmoServer::add_device()`
+    ///
+    pub fn add_device<T: VirtualDevice, S: Into<String>>(
+        &mut self,
+        name: S,
+        virtual_device: T,
+    ) -> Result<SynchronizedDevice<T>, RustmoError> {
+        add_hosted_device(self, name.into(), virtual_device, None)
+    }
+
+    ///
+    /// Add a `VirtualDevice` that polls for up to 4 seconds whenever it's turned on or off.
+    ///
+    /// See `RustmoServer::add_polling_device()`
+    ///
+    pub fn add_polling_device<T: VirtualDevice, S: Into<String>>(
+        &mut self,
+        name: S,
+        virtual_device: T,
+    ) -> Result<SynchronizedDevice<PollingDevice<T>>, RustmoError> {
+        let virtual_device = PollingDevice {
+            device: virtual_device,
+        };
+        add_hosted_device(self, name.into(), virtual_device, None)
+    }
+
+    ///
+    /// Add a `VirtualDevice` that pretends turning it on or off happens immediately.
+    ///
+    /// See `RustmoServer::add_instant_on_device()`
+    ///
+    pub fn add_instant_on_device<T: VirtualDevice, S: Into<String>>(
+        &mut self,
+        name: S,
+        virtual_device: T,
+    ) -> Result<SynchronizedDevice<InstantOnDevice<T>>, RustmoError> {
+        let virtual_device = InstantOnDevice {
+            device: virtual_device,
+            believed_on: Default::default(),
+        };
+        add_hosted_device(self, name.into(), virtual_device, None)
+    }
+
+    ///
+    /// Add a `VirtualDevice` that's given a deadline to answer by.
+    ///
+    /// See `RustmoServer::add_deadline_device()`
+    ///
+    pub fn add_deadline_device<T: VirtualDevice, S: Into<String>>(
+        &mut self,
+        name: S,
+        virtual_device: T,
+    ) -> Result<Arc<DeadlineDevice<T>>, RustmoError> {
+        let device = Arc::new(DeadlineDevice::new(virtual_device));
+        add_shared_device::<_, DeadlineDevice<T>>(self, name.into(), device.clone(), None)?;
+        Ok(device)
+    }
+
+    ///
+    /// Add an anonymous device to make it discoverable and controllable.
+    ///
+    /// See `RustmoServer::add_functional_device()`
+    ///
+    pub fn add_functional_device<TurnOn, TurnOff, CheckIsOn>(
+        &mut self,
+        name: &str,
+        turn_on: TurnOn,
+        turn_off: TurnOff,
+        check_is_on: CheckIsOn,
+    ) -> Result<SynchronizedDevice<FunctionalDevice<TurnOn, TurnOff, CheckIsOn>>, RustmoError>
+    where
+        TurnOn: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
+        TurnOff: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
+        CheckIsOn: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
+    {
+        let virtual_device = FunctionalDevice {
+            turn_on,
+            turn_off,
+            check_is_on,
+        };
+        add_hosted_device(self, name.into(), virtual_device, None)
+    }
+
+    ///
+    /// Add a momentary device, which fires a one-shot `action` when Alexa turns it on.
+    ///
+    /// See `RustmoServer::add_momentary_device()`
+    ///
+    pub fn add_momentary_device<F>(
+        &mut self,
+        name: &str,
+        action: F,
+    ) -> Result<SynchronizedDevice<MomentaryDevice>, RustmoError>
+    where
+        F: Fn() -> Result<(), VirtualDeviceError> + Send + Sync + 'static,
+    {
+        add_hosted_device(
+            self,
+            name.into(),
+            MomentaryDevice::new(Box::new(action)),
+            None,
+        )
+    }
+
+    ///
+    /// Add a momentary device for each of a list of named actions.
+    ///
+    /// See `RustmoServer::add_momentary_devices()`
+    ///
+    pub fn add_momentary_devices(
+        &mut self,
+        actions: Vec<(&str, DeviceAction)>,
+    ) -> Result<Vec<SynchronizedDevice<MomentaryDevice>>, RustmoError> {
+        actions
+            .into_iter()
+            .map(|(name, action)| {
+                add_hosted_device(self, name.into(), MomentaryDevice::new(action), None)
+            })
+            .collect()
+    }
+
+    ///
+    /// Add a device that is a composite of multiple other devices.
+    ///
+    /// See `RustmoServer::add_device_group()`
+    ///
+    pub fn add_device_group(
+        &mut self,
+        name: &str,
+        members: &[&str],
+    ) -> Result<SynchronizedDevice<CompositeDevice>, RustmoError> {
+        add_hosted_group(self, name, members, HueGroupKind::LightGroup)
+    }
+
+    ///
+    /// Add a device group that the Hue bridge exposes as a "Room".
+    ///
+    /// See `RustmoServer::add_device_room()`
+    ///
+    pub fn add_device_room(
+        &mut self,
+        name: &str,
+        members: &[&str],
+    ) -> Result<SynchronizedDevice<CompositeDevice>, RustmoError> {
+        add_hosted_group(self, name, members, HueGroupKind::Room)
+    }
+
+    ///
+    /// Add a device that runs a sequence of steps in order.
+    ///
+    /// See `RustmoServer::add_device_sequence()`
+    ///
+    pub fn add_device_sequence(
+        &mut self,
+        name: &str,
+        steps: Vec<SequenceStep>,
+    ) -> Result<SynchronizedDevice<SequenceDevice>, RustmoError> {
+        add_hosted_device(self, name.into(), SequenceDevice::new(steps), None)
+    }
+
+    ///
+    /// Add a `VirtualSensor`, which the Hue bridge exposes under `/sensors`.
+    ///
+    /// See `RustmoServer::add_sensor()`
+    ///
+    pub fn add_sensor<T: VirtualSensor, S: Into<String>>(
+        &mut self,
+        name: S,
+        sensor: T,
+    ) -> Result<(), RustmoError> {
+        add_sensor(self, name.into(), sensor)
+    }
+
+    ///
+    /// Remove a previously added sensor.
+    ///
+    /// See `RustmoServer::remove_sensor()`
+    ///
+    pub fn remove_sensor(&mut self, name: &str) -> Result<(), RustmoError> {
+        remove_sensor(self, name)
+    }
+
+    ///
+    /// Remove a previously added device so that it is no longer discoverable or controllable.
+    ///
+    /// See `RustmoServer::remove_device()`
+    ///
+    pub fn remove_device(&mut self, name: &str) -> Result<(), RustmoError> {
+        remove_hosted_device(self, name)
+    }
+
+    ///
+    /// Rename a previously added device, keeping its identity.
+    ///
+    /// See `RustmoServer::rename_device()`
+    ///
+    pub fn rename_device(&mut self, name: &str, new_name: &str) -> Result<(), RustmoError> {
+        rename_device(&self.devices, &self.registry, name, new_name)
+    }
+}
+
+impl DeviceHost for AsyncRustmoServer {
+    fn devices(&self) -> &VirtualDevicesList {
+        &self.devices
+    }
+
+    fn sensors(&self) -> &VirtualSensorsList {
+        &self.sensors
+    }
+
+    fn registry(&self) -> &Arc<Mutex<DeviceRegistry>> {
+        &self.registry
+    }
+
+    fn hue_bridge(&self) -> Option<&RustmoDeviceInfo> {
+        self.hue_bridge.as_ref()
+    }
+
+    fn serve(
+        &mut self,
+        name: String,
+        identity: (Uuid, Option<usize>),
+        device: Arc<dyn VirtualDevice>,
+    ) -> Result<RustmoDevice, RustmoError> {
+        Ok(RustmoDevice::new_shared(
+            name,
+            self.ip_address,
+            self.port,
+            identity,
+            &self.routes,
+            DeviceRenamer::new(&self.devices, &self.registry),
+            device,
+        ))
+    }
+
+    fn announce(&self, device: &RustmoDevice) {
+        if self
+            .announcements
+            .send(Announcement::for_device(device))
+            .is_err()
+        {
+            tracing::warn!(
+                "unable to announce `{}`: SSDP announcer has stopped",
+                device.info.name
+            );
+        }
+    }
+
+    fn withdraw(&self, device: RustmoDevice) {
+        self.routes.unroute(&device.info.uuid);
+        notify_byebye(self.ip_address, &device.info);
+    }
+}
+
+///
+/// Accept connections on `listener` forever, answering each request with `service` on the
+/// blocking pool
+///
+async fn serve<S: HttpService>(listener: TcpListener, service: S, blocking: BlockingPool) {
+    let service = Arc::new(service);
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("failed to accept connection: {e}");
+                continue;
+            }
+        };
+        let local_addr = match stream.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(e) => {
+                tracing::debug!(
+                    "dropping connection from {remote_addr} without a local address: {e}"
+                );
+                continue;
+            }
+        };
+
+        let service = service.clone();
+        let blocking = blocking.clone();
+        tokio::spawn(async move {
+            let handler = service_fn(move |request| {
+                let addresses = (remote_addr, local_addr);
+                dispatch(service.clone(), blocking.clone(), addresses, request)
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), handler)
+                .await
+            {
+                tracing::debug!("error serving {remote_addr}: {e}");
+            }
+        });
+    }
+}
+
+async fn dispatch<S: HttpService>(
+    service: Arc<S>,
+    blocking: BlockingPool,
+    (remote_addr, local_addr): (SocketAddr, SocketAddr),
+    request: hyper1::Request<Incoming>,
+) -> Result<hyper1::Response<SentBody>, Infallible> {
+    let (parts, body) = request.into_parts();
+    let body = match body.collect().await {
+        Ok(body) => body.to_bytes().to_vec(),
+        Err(e) => {
+            tracing::debug!("unable to read request body from {remote_addr}: {e}");
+            return Ok(to_hyper_response(HttpResponse::new(400)));
+        }
+    };
+    let request = HttpRequest {
+        method: parts.method.to_string(),
+        path: parts
+            .uri
+            .path_and_query()
+            .map(|path| path.to_string())
+            .unwrap_or_else(|| parts.uri.to_string()),
+        headers: parts
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).to_string(),
+                )
+            })
+            .collect(),
+        body,
+        remote_addr,
+        local_addr,
+    };
+
+    let response = blocking
+        .run(move || service.respond(request))
+        .await
+        .unwrap_or_else(|| HttpResponse::new(500));
+    Ok(to_hyper_response(response))
+}
+
+fn to_hyper_response(response: HttpResponse) -> hyper1::Response<SentBody> {
+    let mut builder = hyper1::Response::builder().status(response.status);
+    for (name, value) in response.headers {
+        builder = builder.header(name, value);
+    }
+    let body = SentBody {
+        body: Full::new(Bytes::from(response.body)),
+        after_sent: response.after_sent,
+    };
+    builder.body(body).unwrap_or_else(|e| {
+        tracing::error!("unable to build response: {e}");
+        let mut response = hyper1::Response::new(SentBody {
+            body: Full::new(Bytes::new()),
+            after_sent: None,
+        });
+        *response.status_mut() = hyper1::StatusCode::INTERNAL_SERVER_ERROR;
+        response
+    })
+}
+
+///
+/// A response body that runs its `HttpResponse`'s `after_sent` once hyper is done sending it
+///
+struct SentBody {
+    body: Full<Bytes>,
+    after_sent: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Body for SentBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for SentBody {
+    fn drop(&mut self) {
+        if let Some(after_sent) = self.after_sent.take() {
+            after_sent();
+        }
+    }
+}
+
+///
+/// Answer SSDP discovery requests arriving on `socket` forever
+///
+async fn listen_for_discovery(
+    socket: UdpSocket,
+    interface: NetworkInterface,
+    devices: VirtualDevicesList,
+    hue_bridge: Option<RustmoDeviceInfo>,
+    blocking: BlockingPool,
+) {
+    let mut buf = vec![0; 65535];
+    loop {
+        let (len, src) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                tracing::warn!("problem receiving SSDP data: {e}");
+                continue;
+            }
+        };
+        if !interface.is_on_link(&src) {
+            continue;
+        }
+        let dgram = String::from_utf8_lossy(&buf[..len]).to_string();
+        let Some(search_target) = SsdpListener::discovery_search_target(&dgram) else {
+            continue;
+        };
+
+        // building responses asks devices whether they're dimmable, which may block
+        let responses = {
+            let devices = devices.clone();
+            let hue_bridge = hue_bridge.clone();
+            let address = interface.address;
+            blocking
+                .run(move || {
+                    SsdpListener::build_discovery_responses(
+                        &devices.read(),
+                        hue_bridge.as_ref(),
+                        search_target,
+                        address,
+                    )
+                })
+                .await
+                .unwrap_or_default()
+        };
+        tracing::info!(
+            "SSDP discovery from {} target={:?} responses={}",
+            src,
+            search_target,
+            responses.len()
+        );
+        for response in responses {
+            if let Err(e) = socket.send_to(response.as_bytes(), src).await {
+                tracing::warn!("unable to answer SSDP discovery from {}: {}", src, e);
+            }
+        }
+    }
+}
+
+///
+/// Multicast `ssdp:alive` notifications at half of their advertised `max-age`, and anything sent
+/// through `pending_announcements` right away, in a burst
+///
+async fn announce_periodically(
+    interface: IpAddr,
+    devices: VirtualDevicesList,
+    hue_bridge: Option<RustmoDeviceInfo>,
+    mut pending_announcements: mpsc::UnboundedReceiver<Announcement>,
+    blocking: BlockingPool,
+) {
+    let socket =
+        match bind_multicast_sender(&NetworkInterface::lookup(interface)).and_then(|socket| {
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket)
+        }) {
+            Ok(socket) => socket,
+            Err(e) => {
+                tracing::error!("unable to send SSDP announcements on {}: {}", interface, e);
+                return;
+            }
+        };
+
+    let mut belkin_announcements = tokio::time::interval(BELKIN_MAX_AGE / 2);
+    let mut hue_announcements = tokio::time::interval(HUE_MAX_AGE / 2);
+    loop {
+        tokio::select! {
+            announcement = pending_announcements.recv() => {
+                let messages = match announcement {
+                    Some(Announcement::Device(device)) => {
+                        let device = device.on_interface(interface);
+                        vec![SsdpListener::build_belkin_notify(&device, "ssdp:alive")]
+                    }
+                    Some(Announcement::HueBridge) => hue_bridge
+                        .as_ref()
+                        .map(|bridge| SsdpListener::build_hue_alive(&bridge.on_interface(interface)))
+                        .unwrap_or_default(),
+                    None => break,
+                };
+                for i in 0..ANNOUNCEMENT_BURST {
+                    if i > 0 {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                    send_all(&socket, interface, &messages).await;
+                }
+            }
+            _ = belkin_announcements.tick() => {
+                let devices = devices.clone();
+                let messages = blocking
+                    .run(move || SsdpListener::build_belkin_alive(&devices.read(), interface))
+                    .await
+                    .unwrap_or_default();
+                send_all(&socket, interface, &messages).await;
+            }
+            _ = hue_announcements.tick() => {
+                let devices = devices.clone();
+                let hue_bridge = hue_bridge.clone();
+                let messages = blocking
+                    .run(move || {
+                        SsdpListener::build_periodic_hue_alive(
+                            &devices.read(),
+                            hue_bridge.as_ref(),
+                            interface,
+                        )
+                    })
+                    .await
+                    .unwrap_or_default();
+                send_all(&socket, interface, &messages).await;
+            }
+        }
+    }
+}
+
+async fn send_all(socket: &UdpSocket, interface: IpAddr, messages: &[String]) {
+    for message in messages {
+        if let Err(e) = socket
+            .send_to(message.as_bytes(), multicast_address(interface))
+            .await
+        {
+            tracing::warn!("unable to send SSDP alive: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct SlowService {
+        in_flight: AtomicUsize,
+        most_in_flight: AtomicUsize,
+    }
+
+    impl HttpService for SlowService {
+        fn respond(&self, request: HttpRequest) -> HttpResponse {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            HttpResponse::new(200).with_body("text/plain", request.path.into_bytes())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requests_are_answered_within_the_blocking_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = Arc::new(SlowService::default());
+        let server = tokio::spawn(serve(listener, service.clone(), BlockingPool::new(2)));
+
+        let responses = tokio::task::spawn_blocking(move || {
+            let clients = (0..8)
+                .map(|i| {
+                    thread::spawn(move || {
+                        let mut stream = std::net::TcpStream::connect(address).unwrap();
+                        write!(
+                            stream,
+                            "GET /device/{i}/setup.xml HTTP/1.1\r\nConnection: close\r\n\r\n"
+                        )
+                        .unwrap();
+                        let mut response = String::new();
+                        stream.read_to_string(&mut response).unwrap();
+                        response
+                    })
+                })
+                .collect::<Vec<_>>();
+            clients
+                .into_iter()
+                .map(|client| client.join().unwrap())
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap();
+        server.abort();
+
+        for (i, response) in responses.iter().enumerate() {
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+            assert!(response.ends_with(&format!("/device/{i}/setup.xml")));
+        }
+        assert!(service.most_in_flight.load(Ordering::SeqCst) <= 2);
+    }
+}
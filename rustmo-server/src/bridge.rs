@@ -0,0 +1,277 @@
+use std::net::IpAddr;
+
+use uuid::Uuid;
+
+use crate::{DeviceName, RustmoDeviceInfo, RustmoError};
+
+/// the port Hue apps expect to find a bridge on
+const HUE_ADVERTISED_PORT: u16 = 80;
+
+/// what real bridges call themselves until they're renamed
+const HUE_DEFAULT_NAME: &str = "Philips hue";
+
+/// every Hue bridge's UUID starts with this, and ends with its MAC address
+const HUE_UUID_PREFIX: &str = "2f402f80-da50-11e1-9b23-";
+
+///
+/// `HueBridgeConfig` describes the Hue bridge a `RustmoServer` emulates:  which port it listens
+/// on, and the identity (bridge id, MAC address, UUID, name) it's known by.
+///
+/// By default the identity is derived from the MAC address of the interface the bridge is
+/// advertised on, the way a real bridge's is, so several Rustmo bridges on the same network
+/// don't collide.  Any of it can be overridden, such as to keep a bridge's identity when moving
+/// it to a different host.
+///
+#[derive(Clone, Debug)]
+pub struct HueBridgeConfig {
+    pub(crate) bind_port: u16,
+    pub(crate) advertised_port: u16,
+    bridge_id: Option<String>,
+    mac: Option<[u8; 6]>,
+    name: String,
+    uuid: Option<Uuid>,
+    /// why the bridge can't be started, if it was given a bad bridge id or MAC address
+    invalid: Option<String>,
+}
+
+///
+/// The identity a Hue bridge is known by, once `HueBridgeConfig`'s defaults have been resolved
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct HueBridgeIdentity {
+    /// sixteen uppercase hex digits, like `001788FFFE23BFC2`
+    pub(crate) bridge_id: String,
+    /// lowercase and colon separated, like `00:17:88:23:bf:c2`
+    pub(crate) mac: String,
+}
+
+impl HueBridgeConfig {
+    ///
+    /// A Hue bridge listening on `bind_port`, which is advertised as port 80
+    ///
+    pub fn new(bind_port: u16) -> Self {
+        HueBridgeConfig {
+            bind_port,
+            advertised_port: HUE_ADVERTISED_PORT,
+            bridge_id: None,
+            mac: None,
+            name: HUE_DEFAULT_NAME.to_string(),
+            uuid: None,
+            invalid: None,
+        }
+    }
+
+    ///
+    /// The bridge's id, as sixteen hex digits.  Defaults to the MAC address with `FFFE` in the
+    /// middle, as with real bridges.
+    ///
+    /// If `bridge_id` isn't sixteen hex digits, starting a server with this bridge fails with
+    /// `RustmoError::HueBridgeError`
+    ///
+    pub fn bridge_id<S: AsRef<str>>(mut self, bridge_id: S) -> Self {
+        let bridge_id = bridge_id.as_ref();
+        if bridge_id.len() == 16 && bridge_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            self.bridge_id = Some(bridge_id.to_uppercase());
+        } else {
+            self.invalid
+                .get_or_insert(format!("`{bridge_id}` isn't a Hue bridge id"));
+        }
+        self
+    }
+
+    ///
+    /// The bridge's MAC address, as six hex bytes optionally separated by `:` or `-`.  Defaults
+    /// to the MAC address of the interface the bridge is advertised on.
+    ///
+    /// If `mac` isn't a MAC address, starting a server with this bridge fails with
+    /// `RustmoError::HueBridgeError`
+    ///
+    pub fn mac<S: AsRef<str>>(mut self, mac: S) -> Self {
+        let mac = mac.as_ref();
+        match parse_mac(mac) {
+            Some(parsed) => self.mac = Some(parsed),
+            None => {
+                self.invalid
+                    .get_or_insert(format!("`{mac}` isn't a MAC address"));
+            }
+        }
+        self
+    }
+
+    ///
+    /// The port the bridge is advertised on, which defaults to 80.  Something (such as a port
+    /// forwarding rule) needs to send connections to this port on to the bind port
+    ///
+    pub fn advertised_port(mut self, port: u16) -> Self {
+        self.advertised_port = port;
+        self
+    }
+
+    ///
+    /// The bridge's name, as Hue apps show it.  Defaults to `Philips hue`
+    ///
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    ///
+    /// The bridge's UPnP UUID.  Defaults to the UUID real bridges use, ending with the MAC
+    /// address
+    ///
+    pub fn uuid(mut self, uuid: Uuid) -> Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    ///
+    /// The bridge as advertised on `interface`, with any unspecified parts of its identity
+    /// derived from the host
+    ///
+    pub(crate) fn device_info(&self, interface: IpAddr) -> Result<RustmoDeviceInfo, RustmoError> {
+        if let Some(invalid) = &self.invalid {
+            return Err(RustmoError::HueBridgeError(invalid.clone()));
+        }
+
+        let mac = self.mac.unwrap_or_else(|| host_mac(interface));
+        let mac_hex = mac.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        let bridge_id = self
+            .bridge_id
+            .clone()
+            .unwrap_or_else(|| format!("{}FFFE{}", &mac_hex[..6], &mac_hex[6..]).to_uppercase());
+        let uuid = self.uuid.unwrap_or_else(|| {
+            Uuid::parse_str(&format!("{HUE_UUID_PREFIX}{mac_hex}"))
+                .expect("a MAC address should make a valid UUID")
+        });
+
+        Ok(RustmoDeviceInfo {
+            name: DeviceName::new(&self.name),
+            ip_address: interface,
+            port: self.advertised_port,
+            uuid,
+            hue_id: None,
+            base_path: String::new(),
+            hue_bridge: Some(HueBridgeIdentity {
+                bridge_id,
+                mac: mac
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<Vec<_>>()
+                    .join(":"),
+            }),
+            changes: Default::default(),
+            interfaces: Vec::new(),
+        })
+    }
+}
+
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let hex = mac.replace([':', '-'], "");
+    if hex.len() != 12 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut bytes = [0u8; 6];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+///
+/// The MAC address of the network interface that owns `address`.  If it has none (or we can't
+/// find out), make up a locally administered one from the address and the host's name, which
+/// at least stays the same across restarts
+///
+fn host_mac(address: IpAddr) -> [u8; 6] {
+    let interface_name = if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|interface| interface.ip() == address)
+        .map(|interface| interface.name);
+    let mac = interface_name
+        .and_then(|name| std::fs::read_to_string(format!("/sys/class/net/{name}/address")).ok())
+        .and_then(|mac| parse_mac(mac.trim()))
+        .filter(|mac| mac.iter().any(|b| *b != 0));
+
+    mac.unwrap_or_else(|| {
+        tracing::warn!(
+            "unable to find the MAC address for {}, making one up for the Hue bridge",
+            address
+        );
+        let hostname = std::fs::read_to_string("/etc/hostname").unwrap_or_default();
+        let hash = Uuid::new_v5(
+            &Uuid::NAMESPACE_DNS,
+            format!("{}/{}", hostname.trim(), address).as_bytes(),
+        );
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&hash.as_bytes()[..6]);
+        // locally administered, unicast
+        mac[0] = (mac[0] | 0x02) & !0x01;
+        mac
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn identity_is_derived_from_the_mac() {
+        let bridge = HueBridgeConfig::new(8080)
+            .mac("00-17-88-23-BF-C2")
+            .device_info(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .unwrap();
+        let identity = bridge.hue_bridge.unwrap();
+        assert_eq!(identity.bridge_id, "001788FFFE23BFC2");
+        assert_eq!(identity.mac, "00:17:88:23:bf:c2");
+        assert_eq!(bridge.name.get(), "Philips hue");
+        assert_eq!(
+            bridge.uuid.to_string(),
+            "2f402f80-da50-11e1-9b23-00178823bfc2"
+        );
+        assert_eq!(bridge.port, 80);
+    }
+
+    #[test]
+    fn identity_can_be_overridden() {
+        let bridge = HueBridgeConfig::new(8080)
+            .bridge_id("001788fffe000001")
+            .advertised_port(8081)
+            .name("Upstairs")
+            .uuid(Uuid::nil())
+            .device_info(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .unwrap();
+        let identity = bridge.hue_bridge.unwrap();
+        assert_eq!(identity.bridge_id, "001788FFFE000001");
+        assert_eq!(bridge.name.get(), "Upstairs");
+        assert_eq!(bridge.uuid, Uuid::nil());
+        assert_eq!(bridge.port, 8081);
+    }
+
+    #[test]
+    fn bad_identities_fail_instead_of_panicking() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(matches!(
+            HueBridgeConfig::new(8080)
+                .bridge_id("bridge")
+                .device_info(localhost),
+            Err(RustmoError::HueBridgeError(_))
+        ));
+        assert!(matches!(
+            HueBridgeConfig::new(8080)
+                .mac("00:17:88")
+                .device_info(localhost),
+            Err(RustmoError::HueBridgeError(_))
+        ));
+    }
+
+    #[test]
+    fn made_up_macs_are_locally_administered() {
+        let mac = host_mac("192.0.2.1".parse().unwrap());
+        assert_eq!(mac[0] & 0x03, 0x02);
+        assert_eq!(mac, host_mac("192.0.2.1".parse().unwrap()));
+    }
+}
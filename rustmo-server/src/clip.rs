@@ -0,0 +1,705 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use rcgen::{CertificateParams, DnType, KeyPair};
+use rustls::pki_types::PrivatePkcs8KeyDer;
+use rustls::ServerConfig;
+use uuid::Uuid;
+
+use crate::http::{HttpRequest, HttpResponse, HttpServer, HttpService};
+use crate::upnp::{hue_bridge_bind_addresses, percent_devices, HUE_MAX_MIREDS, HUE_MIN_MIREDS};
+use crate::virtual_device::{Color, VirtualDevice, VirtualDeviceError, VirtualDeviceState};
+use crate::whitelist::HueWhitelist;
+use crate::{RustmoDevice, VirtualDevicesList};
+
+/// how often lights are polled to catch changes while anyone is listening to the event stream
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// the properties of a light that can change, as last sent to event stream listeners
+type PublishedLights = HashMap<Uuid, serde_json::Map<String, serde_json::Value>>;
+
+pub(crate) fn start_clip_v2_https_server(
+    tls: Arc<ServerConfig>,
+    interfaces: &[IpAddr],
+    bind_port: u16,
+    devices: VirtualDevicesList,
+    whitelist: Arc<Mutex<HueWhitelist>>,
+) -> Vec<HttpServer> {
+    let handler = Arc::new(ClipV2HttpServerHandler::new(devices, whitelist));
+    hue_bridge_bind_addresses(interfaces, bind_port)
+        .into_iter()
+        .filter_map(|bind_address| {
+            match HttpServer::bind_tls(bind_address, tls.clone(), handler.clone()) {
+                Ok(server) => {
+                    tracing::info!("starting Hue CLIP v2 HTTPS server on {}", bind_address);
+                    Some(server)
+                }
+                Err(error) => {
+                    tracing::warn!(
+                        "unable to start Hue CLIP v2 HTTPS server on {}: {}",
+                        bind_address,
+                        error
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+///
+/// A TLS configuration using a freshly generated, self-signed certificate for the Hue bridge
+/// identified by `bridge_id`, named the way a real bridge names its own
+///
+pub(crate) fn self_signed_tls_config(bridge_id: &str) -> Result<Arc<ServerConfig>, String> {
+    let bridge_id = bridge_id.to_lowercase();
+    let key_pair = KeyPair::generate().map_err(|e| e.to_string())?;
+    let mut params = CertificateParams::new(vec![bridge_id.clone()]).map_err(|e| e.to_string())?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, bridge_id.as_str());
+    params
+        .distinguished_name
+        .push(DnType::OrganizationName, "Philips Hue");
+    params.distinguished_name.push(DnType::CountryName, "NL");
+    let certificate = params.self_signed(&key_pair).map_err(|e| e.to_string())?;
+
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![certificate.der().clone()],
+            PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into(),
+        )
+        .map(Arc::new)
+        .map_err(|e| e.to_string())
+}
+
+///
+/// Serves the Hue bridge's CLIP v2 API:  our dimmable devices as `light` resources under
+/// `/clip/v2/resource/light`, and the `/eventstream/clip/v2` server-sent event stream that
+/// pushes changes to them.
+///
+/// Every request must carry a `hue-application-key` header holding a username that has paired
+/// with the bridge through the legacy `/api`.
+///
+pub(crate) struct ClipV2HttpServerHandler {
+    devices: VirtualDevicesList,
+    whitelist: Arc<Mutex<HueWhitelist>>,
+    events: EventStream,
+}
+
+impl HttpService for ClipV2HttpServerHandler {
+    fn respond(&self, request: HttpRequest) -> HttpResponse {
+        tracing::info!(
+            "HUE CLIP v2 request: {} {} from {}",
+            request.method,
+            request.path,
+            request.remote_addr
+        );
+
+        let authorized = request
+            .header("hue-application-key")
+            .is_some_and(|key| self.whitelist.lock().is_authorized(key));
+        if !authorized {
+            tracing::warn!(
+                "HUE CLIP v2 request from unauthorized client {}",
+                request.remote_addr
+            );
+            return clip_response(403, Vec::new(), &["unauthorized user"]);
+        }
+
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/eventstream/clip/v2") => self.handle_event_stream(),
+            ("GET", "/clip/v2/resource/light") => self.make_lights_response(),
+            (method, path) => match (method, path.strip_prefix("/clip/v2/resource/light/")) {
+                ("GET", Some(id)) => self.make_light_response(id),
+                ("PUT", Some(id)) => self.handle_set_light(id, &request),
+                _ => {
+                    tracing::warn!("Unrecognized Hue CLIP v2 request: {:?}", request.path);
+                    clip_response(404, Vec::new(), &["Not Found"])
+                }
+            },
+        }
+    }
+}
+
+impl ClipV2HttpServerHandler {
+    pub(crate) fn new(devices: VirtualDevicesList, whitelist: Arc<Mutex<HueWhitelist>>) -> Self {
+        Self {
+            events: EventStream::new(devices.clone()),
+            devices,
+            whitelist,
+        }
+    }
+
+    fn handle_event_stream(&self) -> HttpResponse {
+        tracing::info!("HUE CLIP v2 event stream opened");
+        HttpResponse::new(200)
+            .with_header("CACHE-CONTROL", "no-cache")
+            .with_stream(
+                "text/event-stream; charset=utf-8",
+                b": hi\n\n".to_vec(),
+                self.events.listen(),
+            )
+    }
+
+    fn make_lights_response(&self) -> HttpResponse {
+        let devices = self.devices.read();
+        let lights = percent_devices(&devices)
+            .into_iter()
+            .map(|(hue_id, device)| clip_light_json(hue_id, device))
+            .collect::<Vec<_>>();
+        tracing::info!(
+            "HUE CLIP v2 lights requested, exposing {} lights",
+            lights.len()
+        );
+        clip_response(200, lights, &[])
+    }
+
+    fn make_light_response(&self, id: &str) -> HttpResponse {
+        let devices = self.devices.read();
+        match find_light(&devices, id) {
+            Some((hue_id, device)) => {
+                clip_response(200, vec![clip_light_json(hue_id, device)], &[])
+            }
+            None => clip_response(404, Vec::new(), &["Not Found"]),
+        }
+    }
+
+    ///
+    /// Apply a CLIP v2 light update.  Nothing is changed if the light doesn't support every
+    /// property being updated
+    ///
+    fn handle_set_light(&self, id: &str, request: &HttpRequest) -> HttpResponse {
+        let Ok(command) = serde_json::from_slice::<serde_json::Value>(&request.body) else {
+            return clip_response(400, Vec::new(), &["invalid json body"]);
+        };
+
+        // the light is changed after the device list is unlocked, so a slow device doesn't hold
+        // up every other request
+        let (name, device, changes) = {
+            let devices = self.devices.read();
+            let Some((_, device)) = find_light(&devices, id) else {
+                return clip_response(404, Vec::new(), &["Not Found"]);
+            };
+
+            if command.get("color_temperature").is_some() && !device.supports_color_temperature() {
+                return clip_response(
+                    400,
+                    Vec::new(),
+                    &["device (light) does not support color_temperature"],
+                );
+            }
+            if command.get("color").is_some() && !device.supports_color() {
+                return clip_response(400, Vec::new(), &["device (light) does not support color"]);
+            }
+
+            (
+                device.info.name.get(),
+                device.device.clone(),
+                device.info.changes.clone(),
+            )
+        };
+
+        apply_light_command(&name, device.as_ref(), &command);
+        changes.notify();
+
+        self.events.state_changed();
+        clip_response(
+            200,
+            vec![serde_json::json!({ "rid": id, "rtype": "light" })],
+            &[],
+        )
+    }
+}
+
+fn apply_light_command(name: &str, device: &dyn VirtualDevice, command: &serde_json::Value) {
+    let report = |result: Result<VirtualDeviceState, VirtualDeviceError>| {
+        if let Err(error) = result {
+            tracing::error!("Problem with {}: {}", name, error.0);
+        }
+    };
+
+    if let Some(on) = command["on"]["on"].as_bool() {
+        tracing::info!("HUE CLIP v2 set `{}` on={}", name, on);
+        report(if on {
+            device.turn_on()
+        } else {
+            device.turn_off()
+        });
+    }
+
+    if let Some(brightness) = command["dimming"]["brightness"].as_f64() {
+        let percent = brightness.clamp(0.0, 100.0).round() as u8;
+        tracing::info!("HUE CLIP v2 set `{}` percent={}", name, percent);
+        report(device.set_percent(percent));
+    }
+
+    if let Some(mirek) = command["color_temperature"]["mirek"].as_u64() {
+        let mireds = mirek.clamp(HUE_MIN_MIREDS as u64, HUE_MAX_MIREDS as u64) as u16;
+        tracing::info!("HUE CLIP v2 set `{}` ct={}", name, mireds);
+        report(device.set_color_temperature(mireds));
+    }
+
+    let xy = &command["color"]["xy"];
+    if let (Some(x), Some(y)) = (xy["x"].as_f64(), xy["y"].as_f64()) {
+        let color = Color::Xy {
+            x: x.clamp(0.0, 1.0) as f32,
+            y: y.clamp(0.0, 1.0) as f32,
+        };
+        tracing::info!("HUE CLIP v2 set `{}` color={:?}", name, color);
+        report(device.set_color(color));
+    }
+}
+
+///
+/// `EventStream` publishes changes to our lights to every open `/eventstream/clip/v2`
+/// connection.
+///
+/// Changes are noticed right away when they're made through the CLIP v2 API, and otherwise by
+/// polling the lights while anyone is listening.  Each event only describes the properties that
+/// changed.
+///
+struct EventStream {
+    devices: VirtualDevicesList,
+    listeners: Arc<Mutex<Listeners>>,
+    stopped: Arc<AtomicBool>,
+    wake: Mutex<Option<Sender<()>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Default)]
+struct Listeners {
+    listeners: Vec<Sender<Vec<u8>>>,
+    last_published: PublishedLights,
+    seq: u32,
+}
+
+impl EventStream {
+    fn new(devices: VirtualDevicesList) -> Self {
+        EventStream {
+            devices,
+            listeners: Default::default(),
+            stopped: Default::default(),
+            wake: Default::default(),
+            thread: Default::default(),
+        }
+    }
+
+    ///
+    /// Add a listener, returning the events it'll be sent
+    ///
+    fn listen(&self) -> Receiver<Vec<u8>> {
+        let (sender, receiver) = mpsc::channel();
+        {
+            let mut listeners = self.listeners.lock();
+            if listeners.listeners.is_empty() {
+                // the first listener's events describe changes from what the lights look like now
+                listeners.last_published = light_states(&self.devices.read());
+            }
+            listeners.listeners.push(sender);
+        }
+        self.ensure_watching();
+        receiver
+    }
+
+    ///
+    /// Let the event stream know a light was likely just changed, so listeners can be told
+    /// without waiting for the next poll
+    ///
+    fn state_changed(&self) {
+        if let Some(wake) = self.wake.lock().as_ref() {
+            let _ = wake.send(());
+        }
+    }
+
+    fn ensure_watching(&self) {
+        let mut thread = self.thread.lock();
+        if thread.is_some() || self.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let (wake, woken) = mpsc::channel();
+        let devices = self.devices.clone();
+        let listeners = self.listeners.clone();
+        let stopped = self.stopped.clone();
+        *self.wake.lock() = Some(wake);
+        *thread = Some(thread::spawn(move || loop {
+            if let Err(RecvTimeoutError::Disconnected) = woken.recv_timeout(POLL_INTERVAL) {
+                break;
+            }
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+
+            publish(&devices, &listeners);
+        }));
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        // hanging up on the watcher wakes it immediately
+        self.wake.lock().take();
+        if let Some(thread) = self.thread.lock().take() {
+            if thread.join().is_err() {
+                tracing::error!("Hue CLIP v2 event stream thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
+
+///
+/// Send an `update` event describing whatever changed since the last one to every listener
+///
+fn publish(devices: &VirtualDevicesList, listeners: &Mutex<Listeners>) {
+    if listeners.lock().listeners.is_empty() {
+        return;
+    }
+
+    let (current, identities) = {
+        let devices = devices.read();
+        let identities = percent_devices(&devices)
+            .into_iter()
+            .map(|(hue_id, device)| (clip_light_id(device), light_identity(hue_id, device)))
+            .collect::<HashMap<_, _>>();
+        (light_states(&devices), identities)
+    };
+
+    let mut listeners = listeners.lock();
+    let mut updates = Vec::new();
+    for (id, state) in &current {
+        let previous = listeners.last_published.get(id);
+        let changed = state
+            .iter()
+            .filter(|(key, value)| previous.and_then(|previous| previous.get(*key)) != Some(value))
+            .collect::<Vec<_>>();
+        if changed.is_empty() {
+            continue;
+        }
+
+        let mut update = identities[id].clone();
+        for (key, value) in changed {
+            update[key] = value.clone();
+        }
+        updates.push(update);
+    }
+    listeners.last_published = current;
+    if updates.is_empty() {
+        return;
+    }
+
+    let now = SystemTime::now();
+    let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let seq = listeners.seq;
+    listeners.seq = listeners.seq.wrapping_add(1);
+    let event = serde_json::json!([{
+        "creationtime": rfc3339(seconds),
+        "data": updates,
+        "id": Uuid::new_v4().to_string(),
+        "type": "update"
+    }]);
+    tracing::info!(
+        "HUE CLIP v2 event to {} listeners: {}",
+        listeners.listeners.len(),
+        event
+    );
+    let chunk = format!("id: {seconds}:{seq}\ndata: {event}\n\n").into_bytes();
+
+    // listeners whose connection has closed have hung up on us
+    listeners
+        .listeners
+        .retain(|listener| listener.send(chunk.clone()).is_ok());
+}
+
+fn find_light<'a>(devices: &'a [RustmoDevice], id: &str) -> Option<(usize, &'a RustmoDevice)> {
+    percent_devices(devices)
+        .into_iter()
+        .find(|(_, device)| clip_light_id(device).to_string() == id)
+}
+
+///
+/// Each light's id is derived from its device's UUID, so it's as stable as the device itself
+///
+fn clip_light_id(device: &RustmoDevice) -> Uuid {
+    Uuid::new_v5(&device.info.uuid, b"light")
+}
+
+fn light_states(devices: &[RustmoDevice]) -> PublishedLights {
+    percent_devices(devices)
+        .into_iter()
+        .map(|(_, device)| (clip_light_id(device), clip_light_state(device)))
+        .collect()
+}
+
+///
+/// The properties that identify a light resource
+///
+fn light_identity(hue_id: usize, device: &RustmoDevice) -> serde_json::Value {
+    serde_json::json!({
+        "id": clip_light_id(device).to_string(),
+        "id_v1": format!("/lights/{hue_id}"),
+        "owner": {
+            "rid": device.info.uuid.to_string(),
+            "rtype": "device"
+        },
+        "type": "light"
+    })
+}
+
+fn clip_light_json(hue_id: usize, device: &RustmoDevice) -> serde_json::Value {
+    let mut json = light_identity(hue_id, device);
+    json["metadata"] = serde_json::json!({
+        "name": device.info.name,
+        "archetype": "classic_bulb"
+    });
+    json["mode"] = serde_json::json!("normal");
+    for (key, value) in clip_light_state(device) {
+        json[key] = value;
+    }
+    json
+}
+
+///
+/// The properties of a light that can change
+///
+fn clip_light_state(device: &RustmoDevice) -> serde_json::Map<String, serde_json::Value> {
+    let on = device.check_is_on().unwrap_or(VirtualDeviceState::Off) == VirtualDeviceState::On;
+    let percent = device.check_percent().unwrap_or(None).unwrap_or(0);
+    let mut state = serde_json::Map::new();
+    state.insert("on".into(), serde_json::json!({ "on": on }));
+    state.insert(
+        "dimming".into(),
+        serde_json::json!({ "brightness": percent as f64 }),
+    );
+
+    if device.supports_color_temperature() {
+        let mireds = device
+            .check_color_temperature()
+            .unwrap_or(None)
+            .map(|mireds| mireds.clamp(HUE_MIN_MIREDS, HUE_MAX_MIREDS));
+        state.insert(
+            "color_temperature".into(),
+            serde_json::json!({
+                "mirek": mireds,
+                "mirek_valid": mireds.is_some(),
+                "mirek_schema": {
+                    "mirek_minimum": HUE_MIN_MIREDS,
+                    "mirek_maximum": HUE_MAX_MIREDS
+                }
+            }),
+        );
+    }
+    if device.supports_color() {
+        let (x, y) = device
+            .check_color()
+            .unwrap_or(None)
+            .map(|color| color.to_xy())
+            .unwrap_or((0.3127, 0.3290));
+        state.insert(
+            "color".into(),
+            serde_json::json!({ "xy": { "x": x, "y": y } }),
+        );
+    }
+
+    state
+}
+
+///
+/// A CLIP v2 response, which always carries both `data` and `errors`
+///
+fn clip_response(status: u16, data: Vec<serde_json::Value>, errors: &[&str]) -> HttpResponse {
+    let errors = errors
+        .iter()
+        .map(|description| serde_json::json!({ "description": description }))
+        .collect::<Vec<_>>();
+    let body = serde_json::json!({ "data": data, "errors": errors });
+    HttpResponse::new(status).with_body("application/json", serde_json::to_vec(&body).unwrap())
+}
+
+///
+/// `seconds` since the Unix epoch as an RFC 3339 UTC timestamp, like `2021-10-18T17:04:55Z`
+///
+pub(crate) fn rfc3339(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64;
+    let time = seconds % 86400;
+
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::atomic::AtomicU8;
+
+    use parking_lot::RwLock;
+
+    use super::*;
+    use crate::virtual_device::VirtualDevice;
+
+    #[derive(Default)]
+    struct Dimmer(AtomicU8);
+
+    impl VirtualDevice for Dimmer {
+        fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.set_percent(100)
+        }
+
+        fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.set_percent(0)
+        }
+
+        fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            Ok(match self.0.load(Ordering::SeqCst) {
+                0 => VirtualDeviceState::Off,
+                _ => VirtualDeviceState::On,
+            })
+        }
+
+        fn supports_percent(&self) -> bool {
+            true
+        }
+
+        fn set_percent(&self, percent: u8) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.0.store(percent, Ordering::SeqCst);
+            self.check_is_on()
+        }
+
+        fn check_percent(&self) -> Result<Option<u8>, VirtualDeviceError> {
+            Ok(Some(self.0.load(Ordering::SeqCst)))
+        }
+    }
+
+    fn handler() -> (ClipV2HttpServerHandler, String) {
+        let device = RustmoDevice {
+            info: crate::RustmoDeviceInfo {
+                name: crate::DeviceName::new("Lamp"),
+                ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 1100,
+                uuid: Uuid::nil(),
+                hue_id: Some(1),
+                base_path: String::new(),
+                hue_bridge: None,
+                changes: Default::default(),
+                interfaces: Vec::new(),
+            },
+            device: Arc::new(Dimmer::default()),
+            http_servers: Vec::new(),
+            hue_group: None,
+        };
+        let whitelist = Arc::new(Mutex::new(HueWhitelist::default()));
+        whitelist.lock().press_link_button();
+        let key = whitelist.lock().pair("Home Assistant").unwrap();
+        let devices = Arc::new(RwLock::new(vec![device]));
+        (ClipV2HttpServerHandler::new(devices, whitelist), key)
+    }
+
+    fn request(method: &str, path: &str, key: &str, body: &str) -> HttpRequest {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 443);
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: vec![("hue-application-key".to_string(), key.to_string())],
+            body: body.as_bytes().to_vec(),
+            remote_addr: address,
+            local_addr: address,
+        }
+    }
+
+    fn json(response: &HttpResponse) -> serde_json::Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[test]
+    fn lights_are_clip_v2_resources() {
+        let (handler, key) = handler();
+
+        let response = handler.respond(request("GET", "/clip/v2/resource/light", "nope", ""));
+        assert_eq!(response.status, 403);
+
+        let response = handler.respond(request("GET", "/clip/v2/resource/light", &key, ""));
+        assert_eq!(response.status, 200);
+        let light = &json(&response)["data"][0];
+        assert_eq!(light["id_v1"], "/lights/1");
+        assert_eq!(light["metadata"]["name"], "Lamp");
+        assert_eq!(light["on"]["on"], false);
+        assert!(light.get("color").is_none());
+
+        let path = format!("/clip/v2/resource/light/{}", light["id"].as_str().unwrap());
+        let body = r#"{"dimming": {"brightness": 42.4}}"#;
+        let response = handler.respond(request("PUT", &path, &key, body));
+        assert_eq!(json(&response)["data"][0]["rtype"], "light");
+        let light = &json(&handler.respond(request("GET", &path, &key, "")))["data"][0];
+        assert_eq!(light["on"]["on"], true);
+        assert_eq!(light["dimming"]["brightness"], 42.0);
+
+        let body = r#"{"color": {"xy": {"x": 0.5, "y": 0.4}}}"#;
+        let response = handler.respond(request("PUT", &path, &key, body));
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn event_stream_pushes_changes() {
+        let (handler, key) = handler();
+        let response = handler.respond(request("GET", "/eventstream/clip/v2", &key, ""));
+        assert_eq!(response.body, b": hi\n\n");
+        let events = response.stream.unwrap();
+
+        let light = json(&handler.respond(request("GET", "/clip/v2/resource/light", &key, "")));
+        let path = format!(
+            "/clip/v2/resource/light/{}",
+            light["data"][0]["id"].as_str().unwrap()
+        );
+        handler.respond(request("PUT", &path, &key, r#"{"on": {"on": true}}"#));
+
+        let event = String::from_utf8(events.recv_timeout(POLL_INTERVAL).unwrap()).unwrap();
+        let data = event
+            .lines()
+            .nth(1)
+            .unwrap()
+            .strip_prefix("data: ")
+            .unwrap();
+        let update = &serde_json::from_str::<serde_json::Value>(data).unwrap()[0];
+        assert_eq!(update["type"], "update");
+        assert_eq!(update["data"][0]["on"]["on"], true);
+        assert_eq!(update["data"][0]["dimming"]["brightness"], 100.0);
+        assert!(update["data"][0].get("metadata").is_none());
+    }
+
+    #[test]
+    fn timestamps_are_rfc3339() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(1634576695), "2021-10-18T17:04:55Z");
+        assert_eq!(rfc3339(951782400), "2000-02-29T00:00:00Z");
+    }
+}
@@ -0,0 +1,477 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use regex::Regex;
+use uuid::Uuid;
+
+use crate::virtual_device::VirtualDeviceState;
+use crate::RustmoDevice;
+
+/// how long a subscription lasts when the subscriber doesn't ask for something specific
+const DEFAULT_SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(1800);
+
+/// how often subscribed devices are polled to catch changes made outside of Rustmo
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// how long to wait on a subscriber's callback before giving up on an event
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// what a device last looked like to its subscribers:  its on/off state and brightness
+type PublishedState = (VirtualDeviceState, Option<u8>);
+
+struct Subscription {
+    sid: String,
+    callbacks: Vec<String>,
+    expires_at: Instant,
+    seq: u32,
+    /// whether the response to the `SUBSCRIBE` has been sent, which has to happen before any
+    /// events are
+    accepted: bool,
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    subscriptions: Vec<Subscription>,
+    last_published: Option<PublishedState>,
+    /// whether the watcher thread is running
+    watching: bool,
+}
+
+///
+/// How a device's `EventPublisher` hears about changes made to the device from outside of its
+/// own `basicevent1` service, such as through the Hue bridge.  Every copy of a device's
+/// `RustmoDeviceInfo` shares it
+///
+#[derive(Clone, Default)]
+pub(crate) struct StateChanges(Arc<Mutex<Option<mpsc::Sender<()>>>>);
+
+impl StateChanges {
+    ///
+    /// Let the device's subscribers know its state was likely just changed, if it has any
+    ///
+    pub(crate) fn notify(&self) {
+        if let Some(wake) = self.0.lock().as_ref() {
+            let _ = wake.send(());
+        }
+    }
+}
+
+///
+/// `EventPublisher` implements the publisher side of UPnP GENA eventing for a device's
+/// `basicevent1` service.
+///
+/// Subscribers are sent a `BinaryState` (and `brightness`, for dimmable devices) event when they
+/// first subscribe, and again whenever it changes.  Changes are noticed right away when they're
+/// made through Rustmo, and otherwise by polling the device while anyone is subscribed.  The
+/// polling stops once the last subscriber leaves.
+///
+pub(crate) struct EventPublisher {
+    device: Weak<RustmoDevice>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    stopped: Arc<AtomicBool>,
+    wake: StateChanges,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl EventPublisher {
+    pub(crate) fn new(device: Weak<RustmoDevice>, wake: StateChanges) -> Self {
+        EventPublisher {
+            device,
+            subscriptions: Default::default(),
+            stopped: Default::default(),
+            wake,
+            thread: Default::default(),
+        }
+    }
+
+    ///
+    /// Add a subscriber, returning its SID and how long the subscription lasts.  It isn't sent
+    /// anything until `::accepted()` says the response to its `SUBSCRIBE` has gone out
+    ///
+    pub(crate) fn subscribe(
+        &self,
+        callbacks: Vec<String>,
+        timeout: Option<Duration>,
+    ) -> (String, Duration) {
+        let sid = format!("uuid:{}", Uuid::new_v4());
+        let timeout = timeout.unwrap_or(DEFAULT_SUBSCRIPTION_TIMEOUT);
+        self.subscriptions.lock().subscriptions.push(Subscription {
+            sid: sid.clone(),
+            callbacks,
+            expires_at: Instant::now() + timeout,
+            seq: 0,
+            accepted: false,
+        });
+
+        self.ensure_watching();
+        (sid, timeout)
+    }
+
+    ///
+    /// What to do once the response to the `SUBSCRIBE` that created `sid` has been sent:  send
+    /// the subscriber its initial event, which GENA requires to come after the response
+    ///
+    pub(crate) fn accepted(&self, sid: String) -> impl FnOnce() + Send + 'static {
+        let subscriptions = self.subscriptions.clone();
+        let wake = self.wake.clone();
+        move || {
+            if let Some(subscription) = subscriptions
+                .lock()
+                .subscriptions
+                .iter_mut()
+                .find(|subscription| subscription.sid == sid)
+            {
+                subscription.accepted = true;
+            }
+
+            // the watcher sends the initial event for new subscriptions
+            wake.notify();
+        }
+    }
+
+    ///
+    /// Extend the subscription identified by `sid`, returning its new duration, or `None` if
+    /// there's no such subscription
+    ///
+    pub(crate) fn renew(&self, sid: &str, timeout: Option<Duration>) -> Option<Duration> {
+        let timeout = timeout.unwrap_or(DEFAULT_SUBSCRIPTION_TIMEOUT);
+        let mut subscriptions = self.subscriptions.lock();
+        let subscription = subscriptions
+            .subscriptions
+            .iter_mut()
+            .find(|subscription| subscription.sid == sid && !subscription.is_expired())?;
+        subscription.expires_at = Instant::now() + timeout;
+        Some(timeout)
+    }
+
+    ///
+    /// Remove the subscription identified by `sid`, returning `false` if there's no such
+    /// subscription
+    ///
+    pub(crate) fn unsubscribe(&self, sid: &str) -> bool {
+        let mut subscriptions = self.subscriptions.lock();
+        let before = subscriptions.subscriptions.len();
+        subscriptions
+            .subscriptions
+            .retain(|subscription| subscription.sid != sid);
+        subscriptions.subscriptions.len() != before
+    }
+
+    ///
+    /// Let the publisher know the device's state was likely just changed, so subscribers can be
+    /// notified without waiting for the next poll
+    ///
+    pub(crate) fn state_changed(&self) {
+        self.wake.notify();
+    }
+
+    fn ensure_watching(&self) {
+        let mut thread = self.thread.lock();
+        if self.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        {
+            let mut subscriptions = self.subscriptions.lock();
+            if subscriptions.watching {
+                return;
+            }
+            subscriptions.watching = true;
+        }
+
+        // the previous watcher, if there was one, already gave up when its last subscriber left
+        if let Some(previous) = thread.take() {
+            if previous.join().is_err() {
+                tracing::error!("GENA event publisher thread panicked");
+            }
+        }
+
+        let (wake, woken) = mpsc::channel();
+        let device = self.device.clone();
+        let subscriptions = self.subscriptions.clone();
+        let stopped = self.stopped.clone();
+        *self.wake.0.lock() = Some(wake);
+        *thread = Some(thread::spawn(move || loop {
+            if let Err(RecvTimeoutError::Disconnected) = woken.recv_timeout(POLL_INTERVAL) {
+                break;
+            }
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            let Some(device) = device.upgrade() else {
+                break;
+            };
+
+            if !publish(&device, &subscriptions) {
+                break;
+            }
+        }));
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        // hanging up on the watcher wakes it immediately
+        self.wake.0.lock().take();
+        if let Some(thread) = self.thread.lock().take() {
+            if thread.join().is_err() {
+                tracing::error!("GENA event publisher thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for EventPublisher {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
+
+impl Subscription {
+    fn is_expired(&self) -> bool {
+        self.expires_at <= Instant::now()
+    }
+}
+
+///
+/// Send the device's current state to every subscriber that hasn't seen it yet, returning
+/// `false` (and no longer watching) once there are no subscribers left
+///
+fn publish(device: &RustmoDevice, subscriptions: &Mutex<Subscriptions>) -> bool {
+    {
+        let mut subscriptions = subscriptions.lock();
+        subscriptions
+            .subscriptions
+            .retain(|subscription| !subscription.is_expired());
+        if subscriptions.subscriptions.is_empty() {
+            subscriptions.watching = false;
+            subscriptions.last_published = None;
+            return false;
+        }
+    }
+
+    let state = match device.check_is_on() {
+        Ok(state) => state,
+        Err(e) => {
+            tracing::warn!(
+                "unable to check `{}` for subscribers: {}",
+                device.info.name,
+                e.0
+            );
+            return true;
+        }
+    };
+    let percent = if device.supports_percent() {
+        device.check_percent().unwrap_or(None)
+    } else {
+        None
+    };
+    let current = (state, percent);
+
+    let events = {
+        let mut subscriptions = subscriptions.lock();
+        let changed = subscriptions.last_published != Some(current);
+        subscriptions.last_published = Some(current);
+        subscriptions
+            .subscriptions
+            .iter_mut()
+            .filter(|subscription| subscription.accepted && (changed || subscription.seq == 0))
+            .map(|subscription| {
+                let event = (
+                    subscription.sid.clone(),
+                    subscription.callbacks.clone(),
+                    subscription.seq,
+                );
+                subscription.seq = subscription.seq.wrapping_add(1).max(1);
+                event
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let body = make_propertyset(state, percent);
+    for (sid, callbacks, seq) in events {
+        tracing::info!(
+            "GENA notify `{}` BinaryState={:?} brightness={:?} seq={} sid={}",
+            device.info.name,
+            state,
+            percent,
+            seq,
+            sid
+        );
+        notify(&sid, &callbacks, seq, &body);
+    }
+    true
+}
+
+fn notify(sid: &str, callbacks: &[String], seq: u32, body: &str) {
+    for callback in callbacks {
+        let result = ureq::request("NOTIFY", callback)
+            .timeout(NOTIFY_TIMEOUT)
+            .set("CONTENT-TYPE", "text/xml; charset=\"utf-8\"")
+            .set("NT", "upnp:event")
+            .set("NTS", "upnp:propchange")
+            .set("SID", sid)
+            .set("SEQ", &seq.to_string())
+            .send_string(body);
+
+        match result {
+            Ok(_) => return,
+            Err(e) => tracing::warn!("GENA notify to {} failed: {}", callback, e),
+        }
+    }
+}
+
+pub(crate) fn make_propertyset(state: VirtualDeviceState, percent: Option<u8>) -> String {
+    let brightness = percent
+        .map(|percent| format!("<e:property><brightness>{percent}</brightness></e:property>"))
+        .unwrap_or_default();
+    format!(
+        "<?xml version=\"1.0\"?>\
+        <e:propertyset xmlns:e=\"urn:schemas-upnp-org:event-1-0\">\
+            <e:property><BinaryState>{state}</BinaryState></e:property>\
+            {brightness}\
+        </e:propertyset>",
+        state = match state {
+            VirtualDeviceState::On => 1,
+            VirtualDeviceState::Off => 0,
+        },
+        brightness = brightness
+    )
+}
+
+///
+/// Parse a GENA `CALLBACK` header, which is one or more URLs each wrapped in angle brackets
+///
+pub(crate) fn parse_callbacks(header: &str) -> Vec<String> {
+    let re = Regex::new("<([^>]+)>").unwrap();
+    re.captures_iter(header)
+        .map(|capture| capture[1].trim().to_string())
+        .filter(|callback| callback.starts_with("http://"))
+        .collect()
+}
+
+///
+/// Parse a GENA `TIMEOUT` header of the form `Second-<n>`.  `Second-infinite`, like anything we
+/// don't understand, gets the default timeout
+///
+pub(crate) fn parse_timeout(header: &str) -> Option<Duration> {
+    header
+        .trim()
+        .strip_prefix("Second-")?
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::virtual_device::{VirtualDevice, VirtualDeviceError};
+    use crate::{DeviceName, RustmoDeviceInfo};
+
+    struct Switch;
+
+    impl VirtualDevice for Switch {
+        fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            Ok(VirtualDeviceState::On)
+        }
+
+        fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            Ok(VirtualDeviceState::Off)
+        }
+
+        fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            Ok(VirtualDeviceState::On)
+        }
+    }
+
+    #[test]
+    fn callbacks_are_parsed_from_angle_brackets() {
+        let callbacks =
+            parse_callbacks("<http://192.168.0.10:3400/notify><http://192.168.0.10:3401/notify>");
+
+        assert_eq!(
+            callbacks,
+            vec![
+                "http://192.168.0.10:3400/notify".to_string(),
+                "http://192.168.0.10:3401/notify".to_string()
+            ]
+        );
+        assert!(parse_callbacks("http://192.168.0.10/no-brackets").is_empty());
+    }
+
+    #[test]
+    fn timeouts_are_parsed_in_seconds() {
+        assert_eq!(parse_timeout("Second-300"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_timeout("Second-infinite"), None);
+        assert_eq!(parse_timeout("garbage"), None);
+    }
+
+    #[test]
+    fn propertyset_reports_binary_state_and_brightness() {
+        let event = make_propertyset(VirtualDeviceState::On, Some(40));
+
+        assert!(event.contains("<e:property><BinaryState>1</BinaryState></e:property>"));
+        assert!(event.contains("<e:property><brightness>40</brightness></e:property>"));
+
+        let event = make_propertyset(VirtualDeviceState::Off, None);
+        assert!(event.contains("<BinaryState>0</BinaryState>"));
+        assert!(!event.contains("brightness"));
+    }
+
+    #[test]
+    fn subscriptions_can_be_renewed_and_cancelled() {
+        let publisher = EventPublisher::new(Weak::new(), StateChanges::default());
+        let (sid, timeout) = publisher.subscribe(
+            vec!["http://127.0.0.1:1/notify".to_string()],
+            Some(Duration::from_secs(60)),
+        );
+
+        assert!(sid.starts_with("uuid:"));
+        assert_eq!(timeout, Duration::from_secs(60));
+        assert_eq!(
+            publisher.renew(&sid, None),
+            Some(DEFAULT_SUBSCRIPTION_TIMEOUT)
+        );
+        assert!(publisher.unsubscribe(&sid));
+        assert!(!publisher.unsubscribe(&sid));
+        assert_eq!(publisher.renew(&sid, None), None);
+    }
+
+    #[test]
+    fn watching_stops_once_the_last_subscriber_leaves() {
+        let device = Arc::new(RustmoDevice {
+            info: RustmoDeviceInfo {
+                name: DeviceName::new("Switch"),
+                ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 1100,
+                uuid: Uuid::nil(),
+                hue_id: None,
+                base_path: String::new(),
+                hue_bridge: None,
+                changes: Default::default(),
+                interfaces: Vec::new(),
+            },
+            device: Arc::new(Switch),
+            http_servers: Vec::new(),
+            hue_group: None,
+        });
+        let publisher = EventPublisher::new(Arc::downgrade(&device), device.info.changes.clone());
+        let (sid, _) = publisher.subscribe(vec!["http://127.0.0.1:1/notify".to_string()], None);
+        assert!(publisher.subscriptions.lock().watching);
+
+        publisher.unsubscribe(&sid);
+        device.info.changes.notify();
+        let started = Instant::now();
+        while publisher.subscriptions.lock().watching && started.elapsed() < POLL_INTERVAL {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(!publisher.subscriptions.lock().watching);
+    }
+}
@@ -0,0 +1,469 @@
+use std::io::{BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use hyper::buffer::BufReader;
+use hyper::header::{Connection, Headers};
+use hyper::net::{HttpStream, NetworkStream};
+use hyper::server::{Request, Response};
+use hyper::status::StatusCode;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how often a streaming response checks whether its server has been stopped
+const STREAM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+///
+/// An HTTP request, fully read, independent of the HTTP stack that received it
+///
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+    pub(crate) remote_addr: SocketAddr,
+    /// the local address the request arrived on
+    pub(crate) local_addr: SocketAddr,
+}
+
+impl HttpRequest {
+    ///
+    /// The (trimmed) value of the first header named `name`, ignoring case
+    ///
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    }
+
+    pub(crate) fn body_string(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+}
+
+///
+/// An HTTP response to be sent by whichever HTTP stack received the request
+///
+pub(crate) struct HttpResponse {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(&'static str, String)>,
+    pub(crate) body: Vec<u8>,
+    /// chunks to send after `body`, for as long as the sender stays connected
+    pub(crate) stream: Option<Receiver<Vec<u8>>>,
+    /// what to do once the response has been sent
+    pub(crate) after_sent: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl HttpResponse {
+    pub(crate) fn new(status: u16) -> Self {
+        HttpResponse {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            stream: None,
+            after_sent: None,
+        }
+    }
+
+    pub(crate) fn not_found() -> Self {
+        HttpResponse::new(404)
+    }
+
+    pub(crate) fn with_header<S: Into<String>>(mut self, name: &'static str, value: S) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub(crate) fn with_body(self, content_type: &str, body: Vec<u8>) -> Self {
+        let mut response = self.with_header("CONTENT-TYPE", content_type);
+        response.body = body;
+        response
+    }
+
+    ///
+    /// Keep the connection open after sending `body`, sending each chunk received from `stream`
+    /// as it arrives, until the sender hangs up or the server is stopped
+    ///
+    pub(crate) fn with_stream(
+        self,
+        content_type: &str,
+        body: Vec<u8>,
+        stream: Receiver<Vec<u8>>,
+    ) -> Self {
+        let mut response = self.with_body(content_type, body);
+        response.stream = Some(stream);
+        response
+    }
+
+    ///
+    /// Run `after_sent` once the response has been sent, whether or not that succeeded
+    ///
+    pub(crate) fn after_sent<F: FnOnce() + Send + 'static>(mut self, after_sent: F) -> Self {
+        self.after_sent = Some(Box::new(after_sent));
+        self
+    }
+}
+
+///
+/// Something that answers HTTP requests.  Implementations may block (they talk to
+/// `VirtualDevice`s), so they're always called from a thread that's allowed to
+///
+pub(crate) trait HttpService: Send + Sync + 'static {
+    fn respond(&self, request: HttpRequest) -> HttpResponse;
+}
+
+impl<S: HttpService> HttpService for Arc<S> {
+    fn respond(&self, request: HttpRequest) -> HttpResponse {
+        (**self).respond(request)
+    }
+}
+
+///
+/// A small HTTP server that dispatches requests to an `HttpService`.
+///
+/// `hyper::Server` (as of 0.10) has no way to stop listening once started, so Rustmo runs its
+/// own accept loop instead.  Each connection is handled on its own thread and serves exactly
+/// one request.  Connections may optionally be secured with TLS.
+///
+/// Stopping the server (or dropping it) closes the listening socket, which frees its port, and
+/// joins the accept thread along with any connection threads still in flight.
+///
+pub(crate) struct HttpServer {
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HttpServer {
+    ///
+    /// Bind to `address` and begin serving requests with `handler` on a background thread
+    ///
+    pub(crate) fn bind<S: HttpService>(address: SocketAddr, service: S) -> std::io::Result<Self> {
+        Self::bind_with_tls(address, None, service)
+    }
+
+    ///
+    /// Bind to `address` and begin serving requests with `handler` over HTTPS, using `tls`
+    ///
+    pub(crate) fn bind_tls<S: HttpService>(
+        address: SocketAddr,
+        tls: Arc<ServerConfig>,
+        service: S,
+    ) -> std::io::Result<Self> {
+        Self::bind_with_tls(address, Some(tls), service)
+    }
+
+    fn bind_with_tls<S: HttpService>(
+        address: SocketAddr,
+        tls: Option<Arc<ServerConfig>>,
+        service: S,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let local_addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let service = Arc::new(service);
+
+        let thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                let mut connections: Vec<JoinHandle<()>> = Vec::new();
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    connections.retain(|connection| !connection.is_finished());
+                    match stream {
+                        Ok(stream) => {
+                            let service = service.clone();
+                            let tls = tls.clone();
+                            let stopped = stopped.clone();
+                            connections.push(thread::spawn(move || {
+                                handle_connection(&*service, stream, tls, &stopped)
+                            }));
+                        }
+                        Err(e) => {
+                            tracing::warn!("failed to accept connection on {local_addr}: {e}")
+                        }
+                    }
+                }
+
+                for connection in connections {
+                    let _ = connection.join();
+                }
+                tracing::info!("HTTP server on {local_addr} stopped");
+            })
+        };
+
+        Ok(HttpServer {
+            local_addr,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    ///
+    /// Stop accepting connections, release the listening socket, and wait for any in-flight
+    /// requests to finish
+    ///
+    pub(crate) fn stop(&mut self) {
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+
+        self.stopped.store(true, Ordering::SeqCst);
+
+        // the accept loop is blocked in `accept()`, so poke it with a connection of our own
+        let wake_address = match self.local_addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => {
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), self.local_addr.port())
+            }
+            IpAddr::V6(ip) if ip.is_unspecified() => {
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), self.local_addr.port())
+            }
+            _ => self.local_addr,
+        };
+        if let Err(e) = TcpStream::connect_timeout(&wake_address, Duration::from_secs(1)) {
+            tracing::warn!("unable to wake HTTP server on {}: {}", self.local_addr, e);
+        }
+
+        if thread.join().is_err() {
+            tracing::error!("HTTP server thread on {} panicked", self.local_addr);
+        }
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
+
+fn handle_connection<S: HttpService>(
+    service: &S,
+    stream: TcpStream,
+    tls: Option<Arc<ServerConfig>>,
+    stopped: &AtomicBool,
+) {
+    let remote_addr = match stream.peer_addr() {
+        Ok(remote_addr) => remote_addr,
+        Err(e) => {
+            tracing::debug!("dropping connection without a peer address: {e}");
+            return;
+        }
+    };
+    let local_addr = match stream.local_addr() {
+        Ok(local_addr) => local_addr,
+        Err(e) => {
+            tracing::debug!("dropping connection from {remote_addr} without a local address: {e}");
+            return;
+        }
+    };
+    if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+        tracing::warn!("unable to set read timeout for {remote_addr}: {e}");
+    }
+
+    match tls {
+        Some(tls) => match ServerConnection::new(tls) {
+            Ok(connection) => serve(
+                service,
+                TlsStream(StreamOwned::new(connection, stream)),
+                remote_addr,
+                local_addr,
+                stopped,
+            ),
+            Err(e) => tracing::warn!("unable to start TLS session with {remote_addr}: {e}"),
+        },
+        None => serve(
+            service,
+            HttpStream(stream),
+            remote_addr,
+            local_addr,
+            stopped,
+        ),
+    }
+}
+
+///
+/// Read one request from `stream` and send `service`'s response to it
+///
+fn serve<S: HttpService, T: NetworkStream>(
+    service: &S,
+    mut stream: T,
+    remote_addr: SocketAddr,
+    local_addr: SocketAddr,
+    stopped: &AtomicBool,
+) {
+    let request = {
+        let reader_stream: &mut dyn NetworkStream = &mut stream;
+        let mut reader = BufReader::new(reader_stream);
+        match Request::new(&mut reader, remote_addr) {
+            Ok(mut request) => {
+                let mut body = Vec::new();
+                if let Err(e) = request.read_to_end(&mut body) {
+                    tracing::debug!("unable to read request body from {remote_addr}: {e}");
+                }
+                let http_request = HttpRequest {
+                    method: request.method.to_string(),
+                    path: request.uri.to_string(),
+                    headers: request
+                        .headers
+                        .iter()
+                        .map(|header| (header.name().to_string(), header.value_string()))
+                        .collect(),
+                    body,
+                    remote_addr,
+                    local_addr,
+                };
+                Some((request.version, http_request))
+            }
+            Err(e) => {
+                tracing::debug!("bad request from {remote_addr}: {e}");
+                None
+            }
+        }
+    };
+
+    let mut after_sent = None;
+    if let Some((version, request)) = request {
+        let mut response = service.respond(request);
+        after_sent = response.after_sent.take();
+        let mut writer = BufWriter::new(&mut stream);
+        let mut headers = Headers::new();
+        headers.set(Connection::close());
+        for (name, value) in response.headers {
+            headers.append_raw(name, value.into_bytes());
+        }
+        let mut hyper_response = Response::new(&mut writer, &mut headers);
+        hyper_response.version = version;
+        *hyper_response.status_mut() = StatusCode::from_u16(response.status);
+        let sent = match response.stream {
+            Some(chunks) => send_streaming(hyper_response, response.body, chunks, stopped),
+            None => hyper_response.send(&response.body),
+        };
+        if let Err(e) = sent {
+            tracing::warn!("unable to send response to {remote_addr}: {e}");
+        }
+        if let Err(e) = writer.flush() {
+            tracing::debug!("unable to flush response to {remote_addr}: {e}");
+        }
+    }
+
+    let _ = stream.close(Shutdown::Both);
+    if let Some(after_sent) = after_sent {
+        after_sent();
+    }
+}
+
+///
+/// Send `body` and then each chunk from `chunks`, flushing as we go so the client sees every
+/// chunk right away
+///
+fn send_streaming(
+    response: Response,
+    body: Vec<u8>,
+    chunks: Receiver<Vec<u8>>,
+    stopped: &AtomicBool,
+) -> std::io::Result<()> {
+    let mut response = response.start()?;
+    response.write_all(&body)?;
+    response.flush()?;
+    loop {
+        match chunks.recv_timeout(STREAM_POLL_INTERVAL) {
+            Ok(chunk) => {
+                if response
+                    .write_all(&chunk)
+                    .and_then(|_| response.flush())
+                    .is_err()
+                {
+                    // the client hung up
+                    return Ok(());
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if !stopped.load(Ordering::SeqCst) => continue,
+            Err(_) => break,
+        }
+    }
+    response.end()
+}
+
+///
+/// A TLS session over a `TcpStream`, as a stream hyper can read requests from
+///
+struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> std::io::Result<SocketAddr> {
+        self.0.sock.peer_addr()
+    }
+
+    fn set_read_timeout(&self, duration: Option<Duration>) -> std::io::Result<()> {
+        self.0.sock.set_read_timeout(duration)
+    }
+
+    fn set_write_timeout(&self, duration: Option<Duration>) -> std::io::Result<()> {
+        self.0.sock.set_write_timeout(duration)
+    }
+
+    fn close(&mut self, how: Shutdown) -> std::io::Result<()> {
+        self.0.conn.send_close_notify();
+        let _ = self.0.flush();
+        self.0.sock.shutdown(how)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct HelloService;
+
+    impl HttpService for HelloService {
+        fn respond(&self, request: HttpRequest) -> HttpResponse {
+            let greeting = format!("hello {}", request.body_string());
+            HttpResponse::new(200).with_body("text/plain", greeting.into_bytes())
+        }
+    }
+
+    #[test]
+    fn stopped_server_releases_its_port() {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let mut server = HttpServer::bind(address, HelloService).unwrap();
+        let address = server.local_addr;
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nworld")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("CONTENT-TYPE: text/plain"));
+        assert!(response.ends_with("hello world"));
+
+        server.stop();
+        assert!(TcpListener::bind(address).is_ok());
+    }
+}
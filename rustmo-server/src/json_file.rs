@@ -0,0 +1,27 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+///
+/// Read the JSON list of entries stored at `path`, which is empty if the file doesn't exist yet
+///
+pub(crate) fn load_entries<T: DeserializeOwned>(path: &Path) -> std::io::Result<Vec<T>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+///
+/// Write `entries` to `path` as JSON.  They're written to a temporary file first, so a crash
+/// can't leave a truncated file behind
+///
+pub(crate) fn save_entries<T: Serialize>(path: &Path, entries: &[T]) -> std::io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, serde_json::to_vec_pretty(entries)?)?;
+    fs::rename(&temp_path, path)
+}
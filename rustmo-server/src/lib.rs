@@ -2,81 +2,260 @@
 extern crate serde_derive;
 
 use std::fmt::{Debug, Display, Formatter};
-use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
-use std::thread;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Weak};
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use tracing::warn;
-use uuid::{uuid, Uuid};
+use uuid::Uuid;
 
-use crate::ssdp::SsdpListener;
+use crate::bridge::HueBridgeIdentity;
+use crate::clip::{self_signed_tls_config, start_clip_v2_https_server};
+use crate::gena::StateChanges;
+use crate::http::HttpServer;
+use crate::mdns::HueBridgeAdvertisement;
+use crate::registry::DeviceRegistry;
+use crate::sensors::{RustmoSensor, VirtualSensorsList};
+use crate::ssdp::{bind_address, SsdpListener};
 use crate::upnp::*;
 use crate::virtual_device::wrappers::*;
 use crate::virtual_device::*;
+use crate::whitelist::HueWhitelist;
 
+#[cfg(feature = "async")]
+mod async_server;
+mod bridge;
+mod clip;
+mod gena;
+mod http;
+mod json_file;
+mod mdns;
+mod registry;
+mod sensors;
 mod ssdp;
 mod upnp;
 pub mod virtual_device;
+mod whitelist;
+
+#[cfg(feature = "async")]
+pub use async_server::{AsyncRustmoServer, DEFAULT_MAX_BLOCKING_CALLS};
+pub use bridge::HueBridgeConfig;
+
+///
+/// A device's name, which every copy of its `RustmoDeviceInfo` shares so that renaming the
+/// device renames it everywhere it's served from
+///
+#[derive(Clone, Default)]
+pub(crate) struct DeviceName(Arc<RwLock<String>>);
+
+impl DeviceName {
+    pub(crate) fn new<S: Into<String>>(name: S) -> Self {
+        DeviceName(Arc::new(RwLock::new(name.into())))
+    }
+
+    pub(crate) fn get(&self) -> String {
+        self.0.read().clone()
+    }
+
+    ///
+    /// Is this device named `name`?  Names are case-insensitive
+    ///
+    pub(crate) fn is(&self, name: &str) -> bool {
+        self.0.read().to_lowercase() == name.to_lowercase()
+    }
+
+    pub(crate) fn set(&self, name: String) {
+        *self.0.write() = name;
+    }
+}
+
+impl Display for DeviceName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0.read())
+    }
+}
+
+impl Debug for DeviceName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&*self.0.read(), f)
+    }
+}
+
+impl serde::Serialize for DeviceName {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.read())
+    }
+}
 
 #[derive(Clone)]
 pub struct RustmoDeviceInfo {
-    pub(crate) name: String,
+    pub(crate) name: DeviceName,
     pub(crate) ip_address: IpAddr,
     pub(crate) port: u16,
     pub(crate) uuid: Uuid,
+    pub(crate) hue_id: Option<usize>,
+    /// prefix for the device's UPnP URLs, which is empty unless it's behind a shared listener
+    pub(crate) base_path: String,
+    /// who the device is, if it's the emulated Hue bridge
+    pub(crate) hue_bridge: Option<HueBridgeIdentity>,
+    /// how the device's GENA subscribers hear about changes made through the Hue bridge
+    pub(crate) changes: StateChanges,
+    /// the interfaces the device can be discovered on, or all of the server's if it's empty
+    pub(crate) interfaces: Vec<IpAddr>,
+}
+
+impl RustmoDeviceInfo {
+    ///
+    /// This device as seen through `interface`, which is the address its URLs need to use to be
+    /// reachable by whoever asked about it
+    ///
+    pub(crate) fn on_interface(&self, interface: IpAddr) -> RustmoDeviceInfo {
+        RustmoDeviceInfo {
+            ip_address: interface,
+            ..self.clone()
+        }
+    }
+
+    ///
+    /// Can this device be discovered through `interface`?
+    ///
+    pub(crate) fn is_discoverable_on(&self, interface: IpAddr) -> bool {
+        self.interfaces.is_empty() || self.interfaces.contains(&interface)
+    }
 }
 
 pub struct RustmoDevice {
     pub(crate) info: RustmoDeviceInfo,
-    pub(crate) device: Box<dyn VirtualDevice>,
+    pub(crate) device: Arc<dyn VirtualDevice>,
+    /// the device's own HTTP servers, one per interface, unless it's behind a shared listener
+    pub(crate) http_servers: Vec<HttpServer>,
+    /// how the Hue bridge exposes this device, if it's a device group
+    pub(crate) hue_group: Option<HueGroup>,
 }
 
 // unsafe impl Send for RustmoDevice {}
 // unsafe impl Sync for RustmoDevice {}
 
 impl RustmoDevice {
+    ///
+    /// Create a device served by its own HTTP server on `port` of each of `interfaces`.  The
+    /// first interface is the one the device is known by.
+    ///
+    /// Fails with `RustmoError::PortUnavailable` if `port` is already in use on any interface,
+    /// or `RustmoError::NetworkError` if it can't be bound for some other reason
+    ///
     pub fn new<T: VirtualDevice, S: Into<String>>(
         name: S,
-        ip_address: IpAddr,
+        interfaces: &[IpAddr],
         port: u16,
+        uuid: Uuid,
+        hue_id: Option<usize>,
         virtual_device: &SynchronizedDevice<T>,
-    ) -> Self {
-        let name = name.into();
-        let mut bytes = Vec::from(name.as_bytes());
-        while bytes.len() < 16 {
-            bytes.push(bytes.len() as u8);
-        }
-        while bytes.len() > 16 {
-            bytes.pop();
-        }
+    ) -> Result<Self, RustmoError> {
+        let device = Arc::new(virtual_device.clone());
+        Self::with_renamer(name, interfaces, port, uuid, hue_id, None, device)
+    }
+
+    ///
+    /// Like `::new()`, but renaming the device (such as with Belkin's `ChangeFriendlyName`) goes
+    /// through `renamer`, so it can't take another device's name
+    ///
+    pub(crate) fn with_renamer<S: Into<String>>(
+        name: S,
+        interfaces: &[IpAddr],
+        port: u16,
+        uuid: Uuid,
+        hue_id: Option<usize>,
+        renamer: Option<DeviceRenamer>,
+        device: Arc<dyn VirtualDevice>,
+    ) -> Result<Self, RustmoError> {
+        let device_info = RustmoDeviceInfo {
+            name: DeviceName::new(name),
+            ip_address: interfaces[0],
+            port,
+            uuid,
+            hue_id,
+            base_path: String::new(),
+            hue_bridge: None,
+            changes: Default::default(),
+            interfaces: Vec::new(),
+        };
+
+        let handler = Arc::new(
+            DeviceHttpServerHandler::new(RustmoDevice {
+                info: device_info.clone(),
+                device: device.clone(),
+                http_servers: Vec::new(),
+                hue_group: None,
+            })
+            .with_renamer(renamer),
+        );
+        // any servers already started are stopped (by being dropped) if a later one fails
+        let http_servers = interfaces
+            .iter()
+            .map(|ip_address| {
+                let address = bind_address(*ip_address, port);
+                HttpServer::bind(address, handler.clone()).map_err(|e| {
+                    tracing::warn!(
+                        "unable to start device server for `{}` on {}: {}",
+                        device_info.name,
+                        address,
+                        e
+                    );
+                    RustmoError::bind_failed(port, e)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
 
+        Ok(RustmoDevice {
+            info: device_info,
+            device,
+            http_servers,
+            hue_group: None,
+        })
+    }
+
+    ///
+    /// Create a device served from a shared listener on `port`, identified by its UUID and Hue
+    /// light id
+    ///
+    pub(crate) fn new_shared<S: Into<String>>(
+        name: S,
+        ip_address: IpAddr,
+        port: u16,
+        (uuid, hue_id): (Uuid, Option<usize>),
+        routes: &SharedDeviceRoutes,
+        renamer: DeviceRenamer,
+        device: Arc<dyn VirtualDevice>,
+    ) -> Self {
         let device_info = RustmoDeviceInfo {
-            name: name.to_string(),
+            name: DeviceName::new(name),
             ip_address,
             port,
-            uuid: Uuid::from_slice(bytes.as_slice()).expect("failed to generate UUID"),
+            uuid,
+            hue_id,
+            base_path: shared_device_base_path(&uuid),
+            hue_bridge: None,
+            changes: Default::default(),
+            interfaces: Vec::new(),
         };
 
-        let device: Box<dyn VirtualDevice> = Box::new(virtual_device.clone());
-        let info = device_info.clone();
-        thread::spawn(move || {
-            let device = RustmoDevice { info, device };
-
-            let server = match hyper::Server::http(SocketAddr::new(ip_address, port)) {
-                Ok(server) => server,
-                Err(e) => panic!(
-                    "ERROR STARTING DEVICE SERVER:  ip={}, port={}, e={}",
-                    ip_address, port, e
-                ),
-            };
-            server.handle(DeviceHttpServerHandler::new(device)).unwrap();
-        });
+        routes.route(
+            DeviceHttpServerHandler::new(RustmoDevice {
+                info: device_info.clone(),
+                device: device.clone(),
+                http_servers: Vec::new(),
+                hue_group: None,
+            })
+            .with_renamer(Some(renamer)),
+        );
 
-        let device: Box<dyn VirtualDevice> = Box::new(virtual_device.clone());
         RustmoDevice {
             info: device_info,
             device,
+            http_servers: Vec::new(),
+            hue_group: None,
         }
     }
 
@@ -103,6 +282,37 @@ impl RustmoDevice {
     fn check_percent(&self) -> Result<Option<u8>, VirtualDeviceError> {
         self.device.check_percent()
     }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.device.supports_color_temperature()
+    }
+
+    fn check_color_temperature(&self) -> Result<Option<u16>, VirtualDeviceError> {
+        self.device.check_color_temperature()
+    }
+
+    fn supports_color(&self) -> bool {
+        self.device.supports_color()
+    }
+
+    fn check_color(&self) -> Result<Option<Color>, VirtualDeviceError> {
+        self.device.check_color()
+    }
+
+    ///
+    /// Is this device exposed as a light behind the Hue bridge, rather than as a WeMo device?
+    ///
+    pub(crate) fn is_hue_light(&self) -> bool {
+        is_hue_light(self.device.as_ref())
+    }
+
+    pub(crate) fn is_wemo_dimmer(&self) -> bool {
+        self.supports_percent() && !self.is_hue_light()
+    }
+}
+
+pub(crate) fn is_hue_light(device: &dyn VirtualDevice) -> bool {
+    device.supports_percent() && device.dimmer_exposure() == DimmerExposure::HueLight
 }
 
 ///
@@ -120,16 +330,38 @@ impl RustmoDevice {
 #[derive(Clone)]
 pub struct RustmoServer {
     devices: VirtualDevicesList,
+    sensors: VirtualSensorsList,
     next_port: u16,
     ip_address: IpAddr,
-    ssdp_listener: SsdpListener,
+    interfaces: Vec<IpAddr>,
+    /// the interfaces new devices are added on, or all of them if it's empty
+    device_interfaces: Vec<IpAddr>,
+    ssdp_listeners: Vec<SsdpListener>,
+    hue_bridge: Option<RustmoDeviceInfo>,
+    hue_bridge_mdns: Option<Arc<HueBridgeAdvertisement>>,
+    hue_bridge_servers: Arc<Mutex<Vec<HttpServer>>>,
+    shared_listener: Arc<Mutex<Option<SharedDeviceListener>>>,
+    registry: Arc<Mutex<DeviceRegistry>>,
+    hue_whitelist: Arc<Mutex<HueWhitelist>>,
 }
 
+/// how many ports (starting with the next one in line) we try before giving up on binding a
+/// device's HTTP server
+const PORT_ATTEMPTS: u16 = 100;
+
 pub(crate) type VirtualDevicesList = Arc<RwLock<Vec<RustmoDevice>>>;
 
 #[derive(Debug)]
 pub enum RustmoError {
     DeviceAlreadyExistsByName(String),
+    DeviceNotFoundByName(String),
+    InvalidDeviceName(String),
+    DeviceRegistryError(String),
+    HueWhitelistError(String),
+    HueBridgeError(String),
+    PortUnavailable(u16),
+    NetworkError(std::io::Error),
+    InvalidInterfaces(String),
 }
 
 impl Display for RustmoError {
@@ -140,11 +372,24 @@ impl Display for RustmoError {
 
 impl std::error::Error for RustmoError {}
 
+impl RustmoError {
+    ///
+    /// The error for failing to bind `port`.  Only a port that's already in use is
+    /// `PortUnavailable` (and worth trying the next port for); anything else is a `NetworkError`
+    ///
+    pub(crate) fn bind_failed(port: u16, e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::AddrInUse => RustmoError::PortUnavailable(port),
+            _ => RustmoError::NetworkError(e),
+        }
+    }
+}
+
 impl RustmoServer {
     ///
     /// Create a new `RustmoServer` and listen for SSDP requests on the specified network interface
     ///
-    pub fn new(interface: IpAddr, starting_port: u16) -> Self {
+    pub fn new(interface: IpAddr, starting_port: u16) -> Result<Self, RustmoError> {
         Self::with_hue_bridge_port(interface, starting_port, 0)
     }
 
@@ -152,34 +397,285 @@ impl RustmoServer {
         interface: IpAddr,
         starting_port: u16,
         hue_bridge_port: u16,
-    ) -> Self {
+    ) -> Result<Self, RustmoError> {
+        Self::with_interfaces(&[interface], starting_port, hue_bridge_port)
+    }
+
+    ///
+    /// Create a new `RustmoServer` that listens for SSDP requests on each of the specified
+    /// network interfaces, which may be any mix of IPv4 and IPv6 addresses.
+    ///
+    /// Every device's HTTP server is bound on all of them (unless `::add_devices_on()` says
+    /// otherwise), and discovery responses point at the address of the interface each request
+    /// arrived on.  The first interface is the primary one that devices are otherwise known by.
+    ///
+    /// Fails with `RustmoError::InvalidInterfaces` if `interfaces` is empty
+    ///
+    pub fn with_interfaces(
+        interfaces: &[IpAddr],
+        starting_port: u16,
+        hue_bridge_port: u16,
+    ) -> Result<Self, RustmoError> {
+        let hue_bridge = (hue_bridge_port != 0).then(|| HueBridgeConfig::new(hue_bridge_port));
+        Self::start(interfaces, starting_port, hue_bridge)
+    }
+
+    ///
+    /// Like `::with_interfaces()`, but emulate a Hue bridge described by `hue_bridge`, such as to
+    /// give it a name, or run more than one on the same network.
+    ///
+    /// Fails with `RustmoError::HueBridgeError` if `hue_bridge` was given a bad bridge id or MAC
+    /// address
+    ///
+    pub fn with_hue_bridge(
+        interfaces: &[IpAddr],
+        starting_port: u16,
+        hue_bridge: HueBridgeConfig,
+    ) -> Result<Self, RustmoError> {
+        Self::start(interfaces, starting_port, Some(hue_bridge))
+    }
+
+    fn start(
+        interfaces: &[IpAddr],
+        starting_port: u16,
+        hue_bridge: Option<HueBridgeConfig>,
+    ) -> Result<Self, RustmoError> {
+        let Some(&interface) = interfaces.first() else {
+            return Err(RustmoError::InvalidInterfaces(
+                "RustmoServer needs at least one interface".to_string(),
+            ));
+        };
         let devices: VirtualDevicesList = Arc::new(RwLock::new(Vec::new()));
-        let hue_bridge = if hue_bridge_port == 0 {
-            tracing::info!("Hue bridge disabled");
-            None
-        } else {
-            let bridge = RustmoDeviceInfo {
-                name: "Rustmo Hue Bridge".to_string(),
-                ip_address: interface,
-                port: 80,
-                uuid: uuid!("2f402f80-da50-11e1-9b23-001788255acc"),
-            };
+        let sensors: VirtualSensorsList = Default::default();
+        let registry: Arc<Mutex<DeviceRegistry>> = Default::default();
+        let hue_whitelist = Arc::new(Mutex::new(HueWhitelist::default()));
+        let (hue_bridge, hue_bridge_servers) = if let Some(config) = hue_bridge {
+            let bridge = config.device_info(interface)?;
             tracing::info!(
-                "configuring Hue bridge bind_port={} advertised_port=80 ip={}",
-                hue_bridge_port,
-                interface
+                "configuring Hue bridge bind_port={} advertised_port={} ip={} bridgeid={}",
+                config.bind_port,
+                config.advertised_port,
+                interface,
+                hue_serial(&bridge)
+            );
+            let servers = start_hue_bridge_http_server(
+                bridge.clone(),
+                interfaces,
+                config.bind_port,
+                devices.clone(),
+                sensors.clone(),
+                DeviceRenamer::new(&devices, &registry),
+                hue_whitelist.clone(),
             );
-            if start_hue_bridge_http_server(bridge.clone(), hue_bridge_port, devices.clone()) {
-                Some(bridge)
+            if servers.is_empty() {
+                (None, servers)
             } else {
-                None
+                (Some(bridge), servers)
             }
+        } else {
+            tracing::info!("Hue bridge disabled");
+            (None, Vec::new())
         };
-        RustmoServer {
+        Ok(RustmoServer {
             devices: devices.clone(),
+            sensors,
             ip_address: interface,
+            interfaces: interfaces.to_vec(),
+            device_interfaces: Vec::new(),
             next_port: starting_port,
-            ssdp_listener: SsdpListener::listen(interface, devices, hue_bridge),
+            ssdp_listeners: interfaces
+                .iter()
+                .map(|interface| {
+                    SsdpListener::listen(*interface, devices.clone(), hue_bridge.clone())
+                })
+                .collect::<Result<_, _>>()?,
+            hue_bridge_mdns: hue_bridge
+                .as_ref()
+                .and_then(|bridge| HueBridgeAdvertisement::publish(bridge, interfaces))
+                .map(Arc::new),
+            hue_bridge,
+            hue_bridge_servers: Arc::new(Mutex::new(hue_bridge_servers)),
+            shared_listener: Default::default(),
+            registry,
+            hue_whitelist,
+        })
+    }
+
+    ///
+    /// Serve and advertise devices added from now on only on `interfaces`, rather than on every
+    /// interface the server listens on, such as to keep some devices on an IoT VLAN.  Each of
+    /// them must be one of the server's interfaces, and passing all of them goes back to the
+    /// default.
+    ///
+    /// Behind a shared listener, devices are still reachable on every interface, but can only be
+    /// discovered on these.  Dimmable devices exposed as Hue lights are reached through the Hue
+    /// bridge, which is on every interface regardless.
+    ///
+    pub fn add_devices_on(&mut self, interfaces: &[IpAddr]) -> Result<(), RustmoError> {
+        if interfaces.is_empty() {
+            return Err(RustmoError::InvalidInterfaces(
+                "devices need at least one interface".to_string(),
+            ));
+        }
+        if let Some(unknown) = interfaces
+            .iter()
+            .find(|interface| !self.interfaces.contains(interface))
+        {
+            return Err(RustmoError::InvalidInterfaces(format!(
+                "{unknown} isn't one of the server's interfaces"
+            )));
+        }
+
+        self.device_interfaces = if self
+            .interfaces
+            .iter()
+            .all(|interface| interfaces.contains(interface))
+        {
+            Vec::new()
+        } else {
+            interfaces.to_vec()
+        };
+        Ok(())
+    }
+
+    ///
+    /// Serve every device added from now on from a single HTTP listener, rather than giving each
+    /// device its own port and thread.
+    ///
+    /// The shared listener takes the next available port, and each device's UPnP URLs are
+    /// prefixed with `/device/{uuid}` so requests can be routed to the right device.
+    ///
+    /// This should be called before any devices are added.
+    ///
+    pub fn use_shared_listener(&mut self) -> Result<(), RustmoError> {
+        let mut shared_listener = self.shared_listener.lock();
+        if shared_listener.is_some() {
+            return Ok(());
+        }
+
+        let interfaces = &self.interfaces;
+        let listener = bind_next_free_port(&mut self.next_port, |port| {
+            SharedDeviceListener::bind(interfaces, port).map_err(|e| {
+                tracing::warn!(
+                    "unable to start shared device listener on port {}: {}",
+                    port,
+                    e
+                );
+                RustmoError::bind_failed(port, e)
+            })
+        })?;
+        tracing::info!(
+            "serving devices from shared listener on port {}",
+            listener.port()
+        );
+        *shared_listener = Some(listener);
+        Ok(())
+    }
+
+    ///
+    /// Remember device identities in the JSON file at `path`, creating it if necessary.
+    ///
+    /// Every device is identified by a UUID derived from its name, which is unique per name and
+    /// stable across restarts.  The registry file records those UUIDs so that a device can be
+    /// renamed (by editing its `name` in the file) without Alexa considering it a new device.
+    ///
+    /// It also records the Hue light id assigned to each dimmable device, so that ids stay the
+    /// same across restarts regardless of the order devices are added in.
+    ///
+    /// This should be called before any devices are added.
+    ///
+    pub fn use_device_registry<P: AsRef<Path>>(&mut self, path: P) -> Result<(), RustmoError> {
+        self.registry.lock().load(path.as_ref()).map_err(|e| {
+            RustmoError::DeviceRegistryError(format!("{}: {}", path.as_ref().display(), e))
+        })
+    }
+
+    ///
+    /// Remember the usernames of Hue apps that have paired with the Hue bridge in the JSON file
+    /// at `path`, creating it if necessary, so they don't need to pair again after a restart.
+    ///
+    pub fn use_hue_whitelist<P: AsRef<Path>>(&mut self, path: P) -> Result<(), RustmoError> {
+        self.hue_whitelist.lock().load(path.as_ref()).map_err(|e| {
+            RustmoError::HueWhitelistError(format!("{}: {}", path.as_ref().display(), e))
+        })
+    }
+
+    ///
+    /// Press the Hue bridge's link button, allowing Hue apps to pair with it for the next 30
+    /// seconds.
+    ///
+    /// Alexa needs to pair with the bridge (by discovering devices) while the button is pressed
+    /// before it can control any of the bridge's lights.
+    ///
+    pub fn press_link_button(&self) {
+        self.hue_whitelist.lock().press_link_button();
+    }
+
+    ///
+    /// Serve the Hue bridge's CLIP v2 API over HTTPS on `bind_port`, alongside its legacy API.
+    ///
+    /// Newer Hue apps (and Home Assistant) talk to a bridge's dimmable lights through
+    /// `/clip/v2/resource/light`, and follow changes to them with the `/eventstream/clip/v2`
+    /// event stream.  They expect to find it on port 443, with the bridge's certificate, which is
+    /// self-signed and generated anew each time this is called.
+    ///
+    /// Apps authenticate with a username they were given by pairing through the legacy API, so
+    /// the Hue bridge must be enabled.
+    ///
+    pub fn use_clip_v2(&mut self, bind_port: u16) -> Result<(), RustmoError> {
+        let Some(bridge) = self.hue_bridge.as_ref() else {
+            return Err(RustmoError::HueBridgeError(
+                "the Hue bridge is disabled".to_string(),
+            ));
+        };
+
+        let tls = self_signed_tls_config(&hue_serial(bridge)).map_err(|e| {
+            RustmoError::HueBridgeError(format!("unable to create a TLS certificate: {e}"))
+        })?;
+        let servers = start_clip_v2_https_server(
+            tls,
+            &self.interfaces,
+            bind_port,
+            self.devices.clone(),
+            self.hue_whitelist.clone(),
+        );
+        if servers.is_empty() {
+            return Err(RustmoError::PortUnavailable(bind_port));
+        }
+
+        self.hue_bridge_servers.lock().extend(servers);
+        Ok(())
+    }
+
+    ///
+    /// Stop this `RustmoServer`.
+    ///
+    /// The SSDP listener and the Hue bridge (if enabled) stop listening, the bridge's mDNS
+    /// advertisement is withdrawn, every device's backing HTTP server is stopped, and all of
+    /// their threads are joined before returning.  All devices are removed from the server, so
+    /// their ports are free to be used again.
+    ///
+    /// This also happens automatically when the last clone of a `RustmoServer` is dropped.
+    ///
+    pub fn shutdown(&mut self) {
+        tracing::info!("shutting down Rustmo server on {}", self.ip_address);
+        self.ssdp_listeners.iter().for_each(SsdpListener::stop);
+        if let Some(hue_bridge_mdns) = self.hue_bridge_mdns.as_ref() {
+            hue_bridge_mdns.withdraw();
+        }
+
+        for mut hue_bridge_server in std::mem::take(&mut *self.hue_bridge_servers.lock()) {
+            hue_bridge_server.stop();
+        }
+
+        if let Some(mut shared_listener) = self.shared_listener.lock().take() {
+            shared_listener.stop();
+        }
+
+        self.sensors.write().clear();
+        let devices = std::mem::take(&mut *self.devices.write());
+        for mut device in devices {
+            device.http_servers.iter_mut().for_each(HttpServer::stop);
         }
     }
 
@@ -195,7 +691,7 @@ impl RustmoServer {
         name: S,
         virtual_device: T,
     ) -> Result<SynchronizedDevice<T>, RustmoError> {
-        self.internal_add_device(name, self.ip_address, virtual_device)
+        add_hosted_device(self, name.into(), virtual_device, None)
     }
 
     ///
@@ -219,7 +715,7 @@ impl RustmoServer {
         let virtual_device = PollingDevice {
             device: virtual_device,
         };
-        self.internal_add_device(name, self.ip_address, virtual_device)
+        add_hosted_device(self, name.into(), virtual_device, None)
     }
 
     ///
@@ -248,7 +744,39 @@ impl RustmoServer {
             device: virtual_device,
             believed_on: Default::default(),
         };
-        self.internal_add_device(name, self.ip_address, virtual_device)
+        add_hosted_device(self, name.into(), virtual_device, None)
+    }
+
+    ///
+    /// Add a `VirtualDevice` to make it discoverable and controllable.
+    ///
+    /// This version calls the provided `VirtualDevice` on a worker thread of its own, one call at
+    /// a time, and stops waiting for it after 4 seconds (or as set with
+    /// `DeadlineDevice::set_deadline()` on the returned device) so that Alexa always gets an
+    /// answer before it gives up on the device.  The `DeadlineDevice` isn't wrapped in a
+    /// `SynchronizedDevice`, so calls aren't queued behind each other before their deadline
+    /// starts.
+    ///
+    /// A call to `::turn_on()` or `::turn_off()` that misses the deadline is answered with the
+    /// state Alexa asked for, and a call to `::check_is_on()` with the last state the device was
+    /// known to be in.  The late call carries on in the background, and is logged when it
+    /// finishes.  Until it does, `::check_is_on()` isn't queued behind it, and is answered with
+    /// the last known state right away.
+    ///
+    /// This form is useful when controlling a physical device that usually answers quickly, but
+    /// can occasionally take much longer, such as while reconnecting.
+    ///
+    /// `@name`:  The word or phrase you'll use when talking to Alexa to control this device
+    /// `@virtual_device`:  A `VirtualDevice` implementation
+    ///
+    pub fn add_deadline_device<T: VirtualDevice, S: Into<String>>(
+        &mut self,
+        name: S,
+        virtual_device: T,
+    ) -> Result<Arc<DeadlineDevice<T>>, RustmoError> {
+        let device = Arc::new(DeadlineDevice::new(virtual_device));
+        add_shared_device::<_, DeadlineDevice<T>>(self, name.into(), device.clone(), None)?;
+        Ok(device)
     }
 
     ///
@@ -279,7 +807,54 @@ impl RustmoServer {
             turn_off,
             check_is_on,
         };
-        self.internal_add_device(name, self.ip_address, virtual_device)
+        add_hosted_device(self, name.into(), virtual_device, None)
+    }
+
+    ///
+    /// Add a momentary device, which fires a one-shot `action` (like pressing a remote's "menu"
+    /// button) when Alexa turns it on.
+    ///
+    /// The device reports that it's on for a few seconds after it fires, so Alexa sees the change
+    /// it asked for, and then goes back to off by itself.  Turning it off doesn't fire anything,
+    /// but ends that window early.
+    ///
+    /// `@name`:  The word or phrase you'll use when talking to Alexa to control this device
+    /// `@action`:  A closure that performs the action
+    ///
+    pub fn add_momentary_device<F>(
+        &mut self,
+        name: &str,
+        action: F,
+    ) -> Result<SynchronizedDevice<MomentaryDevice>, RustmoError>
+    where
+        F: Fn() -> Result<(), VirtualDeviceError> + Send + Sync + 'static,
+    {
+        add_hosted_device(
+            self,
+            name.into(),
+            MomentaryDevice::new(Box::new(action)),
+            None,
+        )
+    }
+
+    ///
+    /// Add a momentary device, like `::add_momentary_device()`, for each of a list of named
+    /// actions.
+    ///
+    /// The devices are added in order, and if one can't be added the ones before it remain.
+    ///
+    /// `@actions`:  The name of each device, and the action it fires
+    ///
+    pub fn add_momentary_devices(
+        &mut self,
+        actions: Vec<(&str, DeviceAction)>,
+    ) -> Result<Vec<SynchronizedDevice<MomentaryDevice>>, RustmoError> {
+        actions
+            .into_iter()
+            .map(|(name, action)| {
+                add_hosted_device(self, name.into(), MomentaryDevice::new(action), None)
+            })
+            .collect()
     }
 
     ///
@@ -294,58 +869,581 @@ impl RustmoServer {
     ///
     /// An example of this might be turning a receiver on (one device) and changing its input to
     /// "DVD".  The receiver would need to be guaranteed "on" before its input source can be changed
-    /// and this function does not guarantee that.
+    /// and this function does not guarantee that.  Use `::add_device_sequence()` for that instead.
+    ///
+    /// If the Hue bridge is enabled, the group is also exposed as a Hue "LightGroup" whose lights
+    /// are its dimmable members.
     ///
     /// `@name`:  The word or phrase you'll use when talking to Alexa to control this device
     /// `@port`:  The port on which the backing HTTP server will listen for UPNP requests
-    /// `@devices`:  A vector of `SynchronizedDevice` instances that have previously been added
-    /// to this `RustmoServer`
+    /// `@members`:  The names of devices that have previously been added to this `RustmoServer`
     ///
     pub fn add_device_group(
         &mut self,
         name: &str,
-        devices: Vec<Box<dyn VirtualDevice>>,
+        members: &[&str],
+    ) -> Result<SynchronizedDevice<CompositeDevice>, RustmoError> {
+        add_hosted_group(self, name, members, HueGroupKind::LightGroup)
+    }
+
+    ///
+    /// Add a device group, like `::add_device_group()`, that the Hue bridge exposes as a "Room"
+    /// rather than a "LightGroup".
+    ///
+    /// Hue only allows a light to be in one room, so a device shouldn't be a member of more than
+    /// one of these.
+    ///
+    pub fn add_device_room(
+        &mut self,
+        name: &str,
+        members: &[&str],
     ) -> Result<SynchronizedDevice<CompositeDevice>, RustmoError> {
-        let virtual_device = CompositeDevice { devices };
-        self.internal_add_device(name, self.ip_address, virtual_device)
+        add_hosted_group(self, name, members, HueGroupKind::Room)
+    }
+
+    ///
+    /// Add a device that runs a sequence of steps, one after the other, when it's turned on, and
+    /// runs them in reverse when it's turned off.
+    ///
+    /// This is useful for a "Movie Night" device ("Alexa, turn on Movie Night") that has to wait
+    /// for a receiver to be on before changing its input.  A step that fails stops the sequence.
+    ///
+    /// Alexa gives up on a device that takes more than 5 seconds to answer, so a long sequence
+    /// may be reported as not responding even though it carries on to the end.
+    ///
+    /// `@name`:  The word or phrase you'll use when talking to Alexa to control this device
+    /// `@steps`:  The `SequenceStep`s to run, in order
+    ///
+    pub fn add_device_sequence(
+        &mut self,
+        name: &str,
+        steps: Vec<SequenceStep>,
+    ) -> Result<SynchronizedDevice<SequenceDevice>, RustmoError> {
+        add_hosted_device(self, name.into(), SequenceDevice::new(steps), None)
+    }
+
+    ///
+    /// Remove a previously added device so that it is no longer discoverable or controllable.
+    ///
+    /// The device's backing HTTP server is stopped, which releases its port, and an SSDP
+    /// `ssdp:byebye` notification is multicast so that Alexa forgets about it.
+    ///
+    /// `@name`:  The name the device was added with (case-insensitive)
+    ///
+    pub fn remove_device(&mut self, name: &str) -> Result<(), RustmoError> {
+        remove_hosted_device(self, name)
+    }
+
+    ///
+    /// Rename a previously added device, which Alexa (and Hue apps) see the next time they look
+    /// at it.  The device keeps its UUID and Hue light id, so Alexa doesn't see it as a new device.
+    ///
+    /// `@name`:  The device's current name (case-insensitive)
+    /// `@new_name`:  The name to give it, which no other device may have
+    ///
+    pub fn rename_device(&mut self, name: &str, new_name: &str) -> Result<(), RustmoError> {
+        rename_device(&self.devices, &self.registry, name, new_name)
     }
 
-    fn internal_add_device<T: VirtualDevice, S: Into<String>>(
+    ///
+    /// Add a `VirtualSensor`, which the Hue bridge exposes under `/sensors` so that Alexa
+    /// routines can be triggered by its readings.
+    ///
+    /// `@name`:  The name the sensor is known by, which must be unique among sensors
+    ///
+    pub fn add_sensor<T: VirtualSensor, S: Into<String>>(
         &mut self,
         name: S,
-        ip_address: IpAddr,
-        virtual_device: T,
-    ) -> Result<SynchronizedDevice<T>, RustmoError> {
-        let name = name.into();
-        warn!(
-            "registered Rustmo Device `{}` of type `{}`",
-            name,
-            std::any::type_name::<T>()
-        );
-        let mut device_list = self.devices.write();
-        for existing_device in device_list.iter() {
-            if existing_device
-                .info
-                .name
-                .to_lowercase()
-                .eq(&name.to_lowercase())
+        sensor: T,
+    ) -> Result<(), RustmoError> {
+        add_sensor(self, name.into(), sensor)
+    }
+
+    ///
+    /// Remove a previously added sensor
+    ///
+    /// `@name`:  The name the sensor was added with (case-insensitive)
+    ///
+    pub fn remove_sensor(&mut self, name: &str) -> Result<(), RustmoError> {
+        remove_sensor(self, name)
+    }
+}
+
+impl DeviceHost for RustmoServer {
+    fn devices(&self) -> &VirtualDevicesList {
+        &self.devices
+    }
+
+    fn sensors(&self) -> &VirtualSensorsList {
+        &self.sensors
+    }
+
+    fn registry(&self) -> &Arc<Mutex<DeviceRegistry>> {
+        &self.registry
+    }
+
+    fn hue_bridge(&self) -> Option<&RustmoDeviceInfo> {
+        self.hue_bridge.as_ref()
+    }
+
+    fn serve(
+        &mut self,
+        name: String,
+        (uuid, hue_id): (Uuid, Option<usize>),
+        device: Arc<dyn VirtualDevice>,
+    ) -> Result<RustmoDevice, RustmoError> {
+        let renamer = DeviceRenamer::new(&self.devices, &self.registry);
+        let interfaces = if self.device_interfaces.is_empty() {
+            &self.interfaces
+        } else {
+            &self.device_interfaces
+        };
+        let mut device = match self.shared_listener.lock().as_ref() {
+            Some(shared_listener) => RustmoDevice::new_shared(
+                name,
+                interfaces[0],
+                shared_listener.port(),
+                (uuid, hue_id),
+                shared_listener.routes(),
+                renamer,
+                device,
+            ),
+            None => bind_next_free_port(&mut self.next_port, |port| {
+                RustmoDevice::with_renamer(
+                    name.clone(),
+                    interfaces,
+                    port,
+                    uuid,
+                    hue_id,
+                    Some(renamer.clone()),
+                    device.clone(),
+                )
+            })?,
+        };
+        device.info.interfaces = self.device_interfaces.clone();
+        Ok(device)
+    }
+
+    fn announce(&self, device: &RustmoDevice) {
+        for ssdp_listener in &self.ssdp_listeners {
+            ssdp_listener.announce(device);
+        }
+    }
+
+    fn withdraw(&self, mut device: RustmoDevice) {
+        for ssdp_listener in &self.ssdp_listeners {
+            ssdp_listener.notify_byebye(&device.info);
+        }
+
+        device.http_servers.iter_mut().for_each(HttpServer::stop);
+        if let Some(shared_listener) = self.shared_listener.lock().as_ref() {
+            shared_listener.routes().unroute(&device.info.uuid);
+        }
+    }
+}
+
+///
+/// How a server serves and announces its devices, which is all that differs between
+/// `RustmoServer` and `AsyncRustmoServer` when devices and sensors come and go
+///
+pub(crate) trait DeviceHost {
+    fn devices(&self) -> &VirtualDevicesList;
+
+    fn sensors(&self) -> &VirtualSensorsList;
+
+    fn registry(&self) -> &Arc<Mutex<DeviceRegistry>>;
+
+    fn hue_bridge(&self) -> Option<&RustmoDeviceInfo>;
+
+    ///
+    /// Start serving `device` as the device named `name`, known by its UUID and Hue light id
+    ///
+    fn serve(
+        &mut self,
+        name: String,
+        identity: (Uuid, Option<usize>),
+        device: Arc<dyn VirtualDevice>,
+    ) -> Result<RustmoDevice, RustmoError>;
+
+    ///
+    /// Let Alexa know about a device that was just added
+    ///
+    fn announce(&self, device: &RustmoDevice);
+
+    ///
+    /// Stop serving a device that was just removed, and let Alexa know it's gone
+    ///
+    fn withdraw(&self, device: RustmoDevice);
+}
+
+///
+/// Add `virtual_device` to `host` as the device named `name`, which the Hue bridge also exposes
+/// as a group if it's a device group
+///
+pub(crate) fn add_hosted_device<H: DeviceHost, T: VirtualDevice>(
+    host: &mut H,
+    name: String,
+    virtual_device: T,
+    group: Option<(HueGroupKind, Vec<Uuid>)>,
+) -> Result<SynchronizedDevice<T>, RustmoError> {
+    let synced = SynchronizedDevice::new(virtual_device);
+    add_shared_device::<H, T>(host, name, Arc::new(synced.clone()), group)?;
+    Ok(synced)
+}
+
+///
+/// Add `device`, a `T` that's already safe to call from several threads at once, to `host`
+/// without wrapping it in a `SynchronizedDevice` of its own
+///
+pub(crate) fn add_shared_device<H: DeviceHost, T: VirtualDevice>(
+    host: &mut H,
+    name: String,
+    device: Arc<dyn VirtualDevice>,
+    group: Option<(HueGroupKind, Vec<Uuid>)>,
+) -> Result<(), RustmoError> {
+    warn!(
+        "registered Rustmo Device `{}` of type `{}`",
+        name,
+        std::any::type_name::<T>()
+    );
+    let devices = host.devices().clone();
+    let mut device_list = devices.write();
+    let identity = device_identity(
+        &device_list,
+        host.registry(),
+        &name,
+        is_hue_light(device.as_ref()),
+    )?;
+    let hue_group = group
+        .map(|(kind, members)| hue_group(host.registry(), &name, kind, members))
+        .transpose()?;
+
+    let mut device = host.serve(name, identity, device)?;
+    device.hue_group = hue_group;
+
+    host.announce(&device);
+    device_list.push(device);
+
+    Ok(())
+}
+
+///
+/// Add a group of the devices named `members` to `host`, which the Hue bridge exposes as a
+/// `kind` group
+///
+pub(crate) fn add_hosted_group<H: DeviceHost>(
+    host: &mut H,
+    name: &str,
+    members: &[&str],
+    kind: HueGroupKind,
+) -> Result<SynchronizedDevice<CompositeDevice>, RustmoError> {
+    let (uuids, devices): (Vec<_>, Vec<_>) = {
+        let device_list = host.devices().read();
+        members
+            .iter()
+            .map(|member| {
+                device_list
+                    .iter()
+                    .find(|device| device.info.name.is(member))
+                    .map(|device| (device.info.uuid, device.device.clone()))
+                    .ok_or_else(|| RustmoError::DeviceNotFoundByName(member.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .unzip()
+    };
+    let virtual_device = CompositeDevice::from_members(devices);
+    add_hosted_device(host, name.to_string(), virtual_device, Some((kind, uuids)))
+}
+
+///
+/// Remove the device named `name` (case-insensitive) from `host`
+///
+pub(crate) fn remove_hosted_device<H: DeviceHost>(host: &H, name: &str) -> Result<(), RustmoError> {
+    let device = take_device(host.devices(), name)?;
+    warn!(
+        "removed Rustmo Device `{}` on port {}",
+        device.info.name, device.info.port
+    );
+    host.withdraw(device);
+    Ok(())
+}
+
+///
+/// Call `bind` with `*next_port`, and each port after it in turn while it fails with
+/// `RustmoError::PortUnavailable`, until it succeeds or we've tried `PORT_ATTEMPTS` ports.
+/// The port after the one that worked is then next in line
+///
+fn bind_next_free_port<T, F>(next_port: &mut u16, mut bind: F) -> Result<T, RustmoError>
+where
+    F: FnMut(u16) -> Result<T, RustmoError>,
+{
+    let first_port = *next_port;
+    let mut port = first_port;
+    loop {
+        match bind(port) {
+            Ok(bound) => {
+                *next_port = port.saturating_add(1);
+                return Ok(bound);
+            }
+            Err(RustmoError::PortUnavailable(_))
+                if port - first_port + 1 < PORT_ATTEMPTS && port < u16::MAX =>
             {
-                return Err(RustmoError::DeviceAlreadyExistsByName(name.to_string()));
+                port += 1;
+            }
+            Err(RustmoError::PortUnavailable(_)) => {
+                tracing::error!("no free port between {} and {}", first_port, port);
+                return Err(RustmoError::PortUnavailable(first_port));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+///
+/// Make sure no device is already named `name`, and look up (or assign) the UUID and, for
+/// Hue lights, the Hue light id that identify it
+///
+pub(crate) fn device_identity(
+    devices: &[RustmoDevice],
+    registry: &Mutex<DeviceRegistry>,
+    name: &str,
+    is_hue_light: bool,
+) -> Result<(Uuid, Option<usize>), RustmoError> {
+    if devices.iter().any(|device| device.info.name.is(name)) {
+        return Err(RustmoError::DeviceAlreadyExistsByName(name.to_string()));
+    }
+
+    let mut registry = registry.lock();
+    let uuid = registry
+        .uuid_for(name)
+        .map_err(|e| RustmoError::DeviceRegistryError(e.to_string()))?;
+    let hue_id = if is_hue_light {
+        Some(
+            registry
+                .hue_id_for(name)
+                .map_err(|e| RustmoError::DeviceRegistryError(e.to_string()))?,
+        )
+    } else {
+        None
+    };
+    Ok((uuid, hue_id))
+}
+
+///
+/// Rename the device named `name` (case-insensitive) to `new_name`, as long as no other device
+/// has (or had) that name
+///
+pub(crate) fn rename_device(
+    devices: &VirtualDevicesList,
+    registry: &Mutex<DeviceRegistry>,
+    name: &str,
+    new_name: &str,
+) -> Result<(), RustmoError> {
+    let new_name = new_name.trim();
+    if new_name.is_empty() {
+        return Err(RustmoError::InvalidDeviceName(new_name.to_string()));
+    }
+
+    let device_name = {
+        let device_list = devices.read();
+        let device = device_list
+            .iter()
+            .find(|device| device.info.name.is(name))
+            .ok_or_else(|| RustmoError::DeviceNotFoundByName(name.to_string()))?;
+        if device_list
+            .iter()
+            .any(|other| !std::ptr::eq(other, device) && other.info.name.is(new_name))
+        {
+            return Err(RustmoError::DeviceAlreadyExistsByName(new_name.to_string()));
+        }
+        device.info.name.clone()
+    };
+
+    // the device list isn't locked while the registry file is written.  Every device has a
+    // registry entry, so the registry still refuses to give two devices the same name, and
+    // holding its lock until the device is renamed keeps concurrent renames in order
+    let mut registry = registry.lock();
+    if !device_name.is(name) {
+        return Err(RustmoError::DeviceNotFoundByName(name.to_string()));
+    }
+    registry
+        .rename(&device_name.get(), new_name)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => {
+                RustmoError::DeviceAlreadyExistsByName(new_name.to_string())
             }
+            _ => RustmoError::DeviceRegistryError(e.to_string()),
+        })?;
+    warn!("renamed Rustmo Device `{}` to `{}`", device_name, new_name);
+    device_name.set(new_name.to_string());
+    Ok(())
+}
+
+///
+/// `DeviceRenamer` lets a device's own handlers rename it, through `rename_device()`
+///
+#[derive(Clone)]
+pub(crate) struct DeviceRenamer {
+    devices: Weak<RwLock<Vec<RustmoDevice>>>,
+    registry: Arc<Mutex<DeviceRegistry>>,
+}
+
+impl DeviceRenamer {
+    pub(crate) fn new(devices: &VirtualDevicesList, registry: &Arc<Mutex<DeviceRegistry>>) -> Self {
+        DeviceRenamer {
+            // devices own their handlers, which own this
+            devices: Arc::downgrade(devices),
+            registry: registry.clone(),
         }
+    }
 
-        let synced = SynchronizedDevice::new(virtual_device);
-        let device = RustmoDevice::new(name, ip_address, self.next_port, &synced);
-        self.next_port += 1;
+    pub(crate) fn rename(&self, name: &str, new_name: &str) -> Result<(), RustmoError> {
+        let devices = self
+            .devices
+            .upgrade()
+            .ok_or_else(|| RustmoError::DeviceNotFoundByName(name.to_string()))?;
+        rename_device(&devices, &self.registry, name, new_name)
+    }
+}
 
-        device_list.push(device);
+///
+/// Look up (or assign) the Hue group id of the device group named `name`
+///
+pub(crate) fn hue_group(
+    registry: &Mutex<DeviceRegistry>,
+    name: &str,
+    kind: HueGroupKind,
+    members: Vec<Uuid>,
+) -> Result<HueGroup, RustmoError> {
+    let id = registry
+        .lock()
+        .hue_group_id_for(name)
+        .map_err(|e| RustmoError::DeviceRegistryError(e.to_string()))?;
+    Ok(HueGroup { id, kind, members })
+}
+
+///
+/// Add the sensor named `name` to `host`, with the Hue sensor id it's known by, as long as no
+/// other sensor has that name
+///
+pub(crate) fn add_sensor<H: DeviceHost, T: VirtualSensor>(
+    host: &H,
+    name: String,
+    sensor: T,
+) -> Result<(), RustmoError> {
+    if host.hue_bridge().is_none() {
+        return Err(RustmoError::HueBridgeError(
+            "sensors need the Hue bridge, which is disabled".to_string(),
+        ));
+    }
 
-        Ok(synced)
+    let mut sensor_list = host.sensors().write();
+    if sensor_list
+        .iter()
+        .any(|sensor| sensor.name.to_lowercase().eq(&name.to_lowercase()))
+    {
+        return Err(RustmoError::DeviceAlreadyExistsByName(name));
     }
+
+    let (uuid, hue_id) = {
+        let mut registry = host.registry().lock();
+        registry
+            .sensor_uuid_for(&name)
+            .and_then(|uuid| Ok((uuid, registry.hue_sensor_id_for(&name)?)))
+            .map_err(|e| RustmoError::DeviceRegistryError(e.to_string()))?
+    };
+    warn!(
+        "registered Rustmo Sensor `{}` of type `{}` as Hue sensor {}",
+        name,
+        std::any::type_name::<T>(),
+        hue_id
+    );
+    sensor_list.push(RustmoSensor::new(name, uuid, hue_id, sensor));
+    Ok(())
+}
+
+///
+/// Remove the sensor named `name` (case-insensitive) from `host`
+///
+pub(crate) fn remove_sensor<H: DeviceHost>(host: &H, name: &str) -> Result<(), RustmoError> {
+    let mut sensor_list = host.sensors().write();
+    let index = sensor_list
+        .iter()
+        .position(|sensor| sensor.name.to_lowercase().eq(&name.to_lowercase()))
+        .ok_or_else(|| RustmoError::DeviceNotFoundByName(name.to_string()))?;
+    let sensor = sensor_list.remove(index);
+    warn!("removed Rustmo Sensor `{}`", sensor.name);
+    Ok(())
+}
+
+///
+/// Remove the device named `name` (case-insensitive) from `devices`
+///
+pub(crate) fn take_device(
+    devices: &VirtualDevicesList,
+    name: &str,
+) -> Result<RustmoDevice, RustmoError> {
+    let mut device_list = devices.write();
+    let index = device_list
+        .iter()
+        .position(|device| device.info.name.is(name))
+        .ok_or_else(|| RustmoError::DeviceNotFoundByName(name.to_string()))?;
+    Ok(device_list.remove(index))
 }
 
-impl Drop for RustmoServer {
-    fn drop(&mut self) {
-        self.ssdp_listener.stop()
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use super::*;
+
+    #[test]
+    fn taken_ports_are_skipped() {
+        let taken = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut next_port = taken.local_addr().unwrap().port();
+        let first_port = next_port;
+        let bound = bind_next_free_port(&mut next_port, |port| {
+            TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+                .map_err(|e| RustmoError::bind_failed(port, e))
+        })
+        .unwrap();
+
+        let bound_port = bound.local_addr().unwrap().port();
+        assert!(bound_port > first_port);
+        assert_eq!(next_port, bound_port + 1);
+    }
+
+    #[test]
+    fn only_ports_in_use_are_unavailable() {
+        let in_use = std::io::Error::from(std::io::ErrorKind::AddrInUse);
+        assert!(matches!(
+            RustmoError::bind_failed(1100, in_use),
+            RustmoError::PortUnavailable(1100)
+        ));
+
+        let mut next_port = 1100;
+        let mut attempts = 0;
+        let result: Result<(), _> = bind_next_free_port(&mut next_port, |port| {
+            attempts += 1;
+            let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+            Err(RustmoError::bind_failed(port, denied))
+        });
+        assert!(matches!(result, Err(RustmoError::NetworkError(_))));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn gives_up_after_port_attempts() {
+        let mut next_port = 1000;
+        let mut attempts = 0;
+        let result: Result<(), _> = bind_next_free_port(&mut next_port, |port| {
+            attempts += 1;
+            Err(RustmoError::PortUnavailable(port))
+        });
+
+        assert!(matches!(result, Err(RustmoError::PortUnavailable(1000))));
+        assert_eq!(attempts, PORT_ATTEMPTS);
+        assert_eq!(next_port, 1000);
     }
 }
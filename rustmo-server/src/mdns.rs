@@ -0,0 +1,122 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
+use parking_lot::Mutex;
+
+use crate::ssdp::hue_bridge_id;
+use crate::RustmoDeviceInfo;
+
+/// the DNS-SD service type Hue bridges are published as
+const HUE_SERVICE_TYPE: &str = "_hue._tcp.local.";
+
+/// the model a (second generation) Hue bridge reports in its TXT record
+const HUE_MODEL_ID: &str = "BSB002";
+
+/// how long to wait for the goodbye announcement to go out when withdrawing
+const WITHDRAW_TIMEOUT: Duration = Duration::from_secs(1);
+
+///
+/// `HueBridgeAdvertisement` publishes the Hue bridge as an mDNS `_hue._tcp` service on each of
+/// the server's interfaces, with the same bridge id it's known by over SSDP, for the Hue apps and
+/// hubs that only discover bridges with mDNS.
+///
+/// Withdrawing the advertisement (or dropping it) announces that the service is going away.
+///
+pub(crate) struct HueBridgeAdvertisement {
+    daemon: Mutex<Option<ServiceDaemon>>,
+    fullname: String,
+}
+
+impl HueBridgeAdvertisement {
+    ///
+    /// Begin advertising `bridge` on `interfaces`, or log why we couldn't and return `None`.
+    /// The bridge can still be discovered with SSDP either way
+    ///
+    pub(crate) fn publish(bridge: &RustmoDeviceInfo, interfaces: &[IpAddr]) -> Option<Self> {
+        let bridge_id = hue_bridge_id(bridge).to_lowercase();
+        let service = ServiceInfo::new(
+            HUE_SERVICE_TYPE,
+            &hue_instance_name(&bridge_id),
+            &format!("{bridge_id}.local."),
+            interfaces,
+            bridge.port,
+            &[("bridgeid", bridge_id.as_str()), ("modelid", HUE_MODEL_ID)][..],
+        );
+
+        let advertised = service.and_then(|service| {
+            let fullname = service.get_fullname().to_string();
+            let daemon = ServiceDaemon::new()?;
+            daemon.disable_interface(IfKind::All)?;
+            daemon.enable_interface(
+                interfaces
+                    .iter()
+                    .map(|interface| IfKind::Addr(*interface))
+                    .collect::<Vec<_>>(),
+            )?;
+            daemon.register(service)?;
+            Ok((daemon, fullname))
+        });
+
+        match advertised {
+            Ok((daemon, fullname)) => {
+                tracing::info!("advertising Hue bridge over mDNS as `{}`", fullname);
+                Some(HueBridgeAdvertisement {
+                    daemon: Mutex::new(Some(daemon)),
+                    fullname,
+                })
+            }
+            Err(e) => {
+                tracing::warn!("unable to advertise Hue bridge over mDNS: {}", e);
+                None
+            }
+        }
+    }
+
+    ///
+    /// Stop advertising the bridge, letting anyone who found it know it's gone
+    ///
+    pub(crate) fn withdraw(&self) {
+        let Some(daemon) = self.daemon.lock().take() else {
+            return;
+        };
+
+        match daemon.unregister(&self.fullname) {
+            Ok(status) => {
+                let _ = status.recv_timeout(WITHDRAW_TIMEOUT);
+            }
+            Err(e) => tracing::warn!("unable to withdraw `{}` from mDNS: {}", self.fullname, e),
+        }
+        if let Ok(status) = daemon.shutdown() {
+            let _ = status.recv_timeout(WITHDRAW_TIMEOUT);
+        }
+        tracing::info!("stopped advertising Hue bridge over mDNS");
+    }
+}
+
+impl Drop for HueBridgeAdvertisement {
+    fn drop(&mut self) {
+        self.withdraw()
+    }
+}
+
+///
+/// Real bridges are named for the last six digits of their id, like `Philips Hue - 23BFC2`
+///
+fn hue_instance_name(bridge_id: &str) -> String {
+    let suffix = &bridge_id[bridge_id.len().saturating_sub(6)..];
+    format!("Philips Hue - {}", suffix.to_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instances_are_named_like_real_bridges() {
+        assert_eq!(
+            hue_instance_name("001788fffe23bfc2"),
+            "Philips Hue - 23BFC2"
+        );
+    }
+}
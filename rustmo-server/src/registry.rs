@@ -0,0 +1,325 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use uuid::{uuid, Uuid};
+
+use crate::json_file::{load_entries, save_entries};
+
+/// namespace for the name-based (v5) UUIDs we hand out to devices
+const RUSTMO_NAMESPACE: Uuid = uuid!("6f1c7d2e-52a4-4b8e-9e0b-7a3d2c5f9e41");
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RegistryEntry {
+    name: String,
+    uuid: Uuid,
+    /// sensors are named apart from devices, so a sensor and a device can share a name
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    sensor: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hue_id: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hue_group_id: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hue_sensor_id: Option<usize>,
+}
+
+///
+/// `DeviceRegistry` remembers the UUID that identifies each device name, along with the Hue
+/// light id of each dimmable device, the Hue group id of each device group, and the Hue sensor id
+/// of each sensor.
+///
+/// A device's UUID is derived from its (case-insensitive) name the first time it's seen, so it's
+/// unique per name and stable across restarts even without a registry file.  Sensors are kept
+/// apart from devices, and get UUIDs of their own even when they share a device's name.
+///
+/// When backed by a file, the registry is persisted as JSON every time it changes.  Entries are
+/// never removed, so a device that is removed and later re-added keeps its identity.  Renaming a
+/// device (or editing its `name` in the file, leaving its `uuid` alone) renames it without Alexa
+/// seeing it as a new device.
+///
+/// Hue light, group and sensor ids are handed out in increasing order and never reused, so
+/// adding or removing a device doesn't change which light, group or sensor any other device is.
+///
+#[derive(Default)]
+pub(crate) struct DeviceRegistry {
+    path: Option<PathBuf>,
+    entries: Vec<RegistryEntry>,
+}
+
+impl DeviceRegistry {
+    ///
+    /// Load the registry from `path`, which doesn't need to exist yet.  Entries already known to
+    /// this registry take precedence over the file's, and are written back to it
+    ///
+    pub(crate) fn load<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = load_entries::<RegistryEntry>(&path)?;
+
+        for known in &self.entries {
+            match entries
+                .iter_mut()
+                .find(|entry| entry.sensor == known.sensor && same_name(&entry.name, &known.name))
+            {
+                Some(entry) => {
+                    entry.uuid = known.uuid;
+                    entry.hue_id = known.hue_id.or(entry.hue_id);
+                    entry.hue_group_id = known.hue_group_id.or(entry.hue_group_id);
+                    entry.hue_sensor_id = known.hue_sensor_id.or(entry.hue_sensor_id);
+                }
+                None => entries.push(known.clone()),
+            }
+        }
+
+        tracing::info!(
+            "loaded {} device identities from {}",
+            entries.len(),
+            path.display()
+        );
+        self.path = Some(path);
+        self.entries = entries;
+        self.save()
+    }
+
+    ///
+    /// Return the UUID for the device named `name`, assigning (and persisting) a new one if this
+    /// is the first time we've seen it
+    ///
+    pub(crate) fn uuid_for(&mut self, name: &str) -> std::io::Result<Uuid> {
+        let index = self.register(name, false)?;
+        Ok(self.entries[index].uuid)
+    }
+
+    ///
+    /// Return the UUID for the sensor named `name`, which is distinct from that of any device with
+    /// the same name
+    ///
+    pub(crate) fn sensor_uuid_for(&mut self, name: &str) -> std::io::Result<Uuid> {
+        let index = self.register(name, true)?;
+        Ok(self.entries[index].uuid)
+    }
+
+    ///
+    /// Return the Hue light id for the device named `name`, assigning (and persisting) the next
+    /// unused one if it doesn't have one yet
+    ///
+    pub(crate) fn hue_id_for(&mut self, name: &str) -> std::io::Result<usize> {
+        self.id_for(name, false, |entry| &mut entry.hue_id)
+    }
+
+    ///
+    /// Return the Hue group id for the device group named `name`, assigning (and persisting) the
+    /// next unused one if it doesn't have one yet
+    ///
+    pub(crate) fn hue_group_id_for(&mut self, name: &str) -> std::io::Result<usize> {
+        self.id_for(name, false, |entry| &mut entry.hue_group_id)
+    }
+
+    ///
+    /// Return the Hue sensor id for the sensor named `name`, assigning (and persisting) the next
+    /// unused one if it doesn't have one yet
+    ///
+    pub(crate) fn hue_sensor_id_for(&mut self, name: &str) -> std::io::Result<usize> {
+        self.id_for(name, true, |entry| &mut entry.hue_sensor_id)
+    }
+
+    ///
+    /// Rename the device named `name` to `new_name`, keeping its UUID and Hue ids.  Fails with
+    /// `ErrorKind::AlreadyExists` if `new_name` already identifies a different device, even one
+    /// that has since been removed
+    ///
+    pub(crate) fn rename(&mut self, name: &str, new_name: &str) -> std::io::Result<()> {
+        if !same_name(name, new_name) && self.position(new_name, false).is_some() {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("`{new_name}` already identifies another device"),
+            ));
+        }
+
+        let index = self.register(name, false)?;
+        self.entries[index].name = new_name.to_string();
+        self.save()
+    }
+
+    fn position(&self, name: &str, sensor: bool) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.sensor == sensor && same_name(&entry.name, name))
+    }
+
+    ///
+    /// The index of the entry for the device (or sensor) named `name`, which is added (and
+    /// persisted) if this is the first time we've seen it
+    ///
+    fn register(&mut self, name: &str, sensor: bool) -> std::io::Result<usize> {
+        if let Some(index) = self.position(name, sensor) {
+            return Ok(index);
+        }
+
+        let key = if sensor {
+            format!("sensor:{}", name.to_lowercase())
+        } else {
+            name.to_lowercase()
+        };
+        let mut uuid = Uuid::new_v5(&RUSTMO_NAMESPACE, key.as_bytes());
+        if self.entries.iter().any(|entry| entry.uuid == uuid) {
+            // another device was renamed away from `name` and kept its identity
+            uuid = Uuid::new_v4();
+        }
+
+        self.entries.push(RegistryEntry {
+            name: name.to_string(),
+            uuid,
+            sensor,
+            hue_id: None,
+            hue_group_id: None,
+            hue_sensor_id: None,
+        });
+        self.save()?;
+        Ok(self.entries.len() - 1)
+    }
+
+    fn id_for(
+        &mut self,
+        name: &str,
+        sensor: bool,
+        id: fn(&mut RegistryEntry) -> &mut Option<usize>,
+    ) -> std::io::Result<usize> {
+        let index = self.register(name, sensor)?;
+        if let Some(existing) = *id(&mut self.entries[index]) {
+            return Ok(existing);
+        }
+
+        let next_id = self
+            .entries
+            .iter_mut()
+            .filter_map(|entry| *id(entry))
+            .max()
+            .unwrap_or(0)
+            + 1;
+        *id(&mut self.entries[index]) = Some(next_id);
+        self.save()?;
+        Ok(next_id)
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        match self.path.as_ref() {
+            Some(path) => save_entries(path, &self.entries),
+            None => Ok(()),
+        }
+    }
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.to_lowercase().eq(&b.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn temp_registry_path(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rustmo-registry-{}-{}.json",
+            test,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn similar_names_get_distinct_uuids() {
+        let mut registry = DeviceRegistry::default();
+        let first = registry.uuid_for("Living Room Lamp 1").unwrap();
+        let second = registry.uuid_for("Living Room Lamp 2").unwrap();
+
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn uuids_are_stable_and_case_insensitive() {
+        let first = DeviceRegistry::default().uuid_for("Sconces").unwrap();
+        let second = DeviceRegistry::default().uuid_for("sconces").unwrap();
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn hue_ids_are_never_reused() {
+        let path = temp_registry_path("hue-ids");
+        let mut registry = DeviceRegistry::default();
+        registry.load(&path).unwrap();
+        assert_eq!(registry.hue_id_for("Sconces").unwrap(), 1);
+        assert_eq!(registry.hue_id_for("Cove Lights").unwrap(), 2);
+        assert_eq!(registry.hue_id_for("sconces").unwrap(), 1);
+
+        let mut registry = DeviceRegistry::default();
+        registry.load(&path).unwrap();
+        assert_eq!(registry.hue_id_for("Step Lights").unwrap(), 3);
+        assert_eq!(registry.hue_id_for("Cove Lights").unwrap(), 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hue_group_ids_are_numbered_apart_from_light_ids() {
+        let mut registry = DeviceRegistry::default();
+        assert_eq!(registry.hue_id_for("Sconces").unwrap(), 1);
+        assert_eq!(registry.hue_id_for("Cove Lights").unwrap(), 2);
+        assert_eq!(registry.hue_group_id_for("Theater").unwrap(), 1);
+        assert_eq!(registry.hue_id_for("Theater").unwrap(), 3);
+        assert_eq!(registry.hue_group_id_for("theater").unwrap(), 1);
+    }
+
+    #[test]
+    fn sensors_are_named_apart_from_devices() {
+        let mut registry = DeviceRegistry::default();
+        let device = registry.uuid_for("Porch").unwrap();
+        assert_eq!(registry.hue_id_for("Porch").unwrap(), 1);
+
+        let sensor = registry.sensor_uuid_for("porch").unwrap();
+        assert_ne!(sensor, device);
+        assert_eq!(registry.hue_sensor_id_for("Porch").unwrap(), 1);
+        assert_eq!(registry.uuid_for("porch").unwrap(), device);
+
+        registry.rename("Porch", "Porch Light").unwrap();
+        assert_eq!(registry.sensor_uuid_for("Porch").unwrap(), sensor);
+    }
+
+    #[test]
+    fn renamed_devices_keep_their_uuid() {
+        let path = temp_registry_path("rename");
+        let mut registry = DeviceRegistry::default();
+        registry.load(&path).unwrap();
+        let uuid = registry.uuid_for("Bias Light").unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(&path, contents.replace("Bias Light", "TV Backlight")).unwrap();
+
+        let mut registry = DeviceRegistry::default();
+        registry.load(&path).unwrap();
+        assert_eq!(registry.uuid_for("TV Backlight").unwrap(), uuid);
+        assert_ne!(registry.uuid_for("Bias Light").unwrap(), uuid);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn renaming_keeps_identity_but_not_someone_elses_name() {
+        let mut registry = DeviceRegistry::default();
+        let uuid = registry.uuid_for("Bias Light").unwrap();
+        let hue_id = registry.hue_id_for("Bias Light").unwrap();
+        registry.uuid_for("Sconces").unwrap();
+
+        registry.rename("bias light", "TV Backlight").unwrap();
+        assert_eq!(registry.uuid_for("TV Backlight").unwrap(), uuid);
+        assert_eq!(registry.hue_id_for("tv backlight").unwrap(), hue_id);
+
+        let e = registry.rename("TV Backlight", "sconces").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+        registry.rename("TV Backlight", "tv backlight").unwrap();
+        assert_eq!(registry.uuid_for("TV BACKLIGHT").unwrap(), uuid);
+    }
+}
@@ -0,0 +1,213 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::{Mutex, RwLock};
+use uuid::Uuid;
+
+use crate::clip::rfc3339;
+use crate::virtual_device::{SensorKind, SensorReading, VirtualSensor};
+
+pub(crate) type VirtualSensorsList = Arc<RwLock<Vec<RustmoSensor>>>;
+
+/// the light level below which a `CLIPLightLevel` sensor reports that it's dark
+const HUE_DARK_THRESHOLD: u32 = 16000;
+
+/// how far above `HUE_DARK_THRESHOLD` the light level has to be for it to be daylight
+const HUE_DAYLIGHT_OFFSET: u32 = 7000;
+
+///
+/// A `VirtualSensor` registered with a `RustmoServer`, along with the identity the Hue bridge
+/// knows it by
+///
+pub(crate) struct RustmoSensor {
+    pub(crate) name: String,
+    pub(crate) uuid: Uuid,
+    pub(crate) hue_id: usize,
+    sensor: Box<dyn VirtualSensor>,
+    /// the last reading, and when (in seconds since the Unix epoch) it was first seen
+    last_reading: Mutex<Option<(SensorReading, u64)>>,
+}
+
+impl RustmoSensor {
+    pub(crate) fn new<T: VirtualSensor>(
+        name: String,
+        uuid: Uuid,
+        hue_id: usize,
+        sensor: T,
+    ) -> Self {
+        RustmoSensor {
+            name,
+            uuid,
+            hue_id,
+            sensor: Box::new(sensor),
+            last_reading: Default::default(),
+        }
+    }
+
+    ///
+    /// Take a new reading, returning it along with when it last changed.  If the sensor can't be
+    /// read, its last reading is returned instead, and `reachable` is false
+    ///
+    fn read(&self) -> (Option<(SensorReading, u64)>, bool) {
+        let kind = self.sensor.kind();
+        let reading = match self.sensor.read() {
+            Ok(reading) if reading.kind() == kind => Ok(reading),
+            Ok(reading) => Err(format!("expected a {kind:?} reading, not {reading:?}")),
+            Err(e) => Err(e.0),
+        };
+
+        let mut last_reading = self.last_reading.lock();
+        match reading {
+            Ok(reading) => {
+                if last_reading.is_none_or(|(last, _)| last != reading) {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    *last_reading = Some((reading, now));
+                }
+                (*last_reading, true)
+            }
+            Err(e) => {
+                tracing::warn!("unable to read sensor `{}`: {}", self.name, e);
+                (*last_reading, false)
+            }
+        }
+    }
+
+    ///
+    /// This sensor as a Hue API sensor
+    ///
+    pub(crate) fn hue_json(&self) -> serde_json::Value {
+        let kind = self.sensor.kind();
+        let (reading, reachable) = self.read();
+        let mut json = serde_json::json!({
+            "state": {
+                "lastupdated": reading.map_or("none".to_string(), |(_, seconds)| {
+                    // Hue timestamps are UTC without saying so
+                    rfc3339(seconds).trim_end_matches('Z').to_string()
+                })
+            },
+            "config": {
+                "on": true,
+                "reachable": reachable
+            },
+            "name": self.name,
+            "type": hue_sensor_type(kind),
+            "modelid": "RustmoSensor",
+            "manufacturername": "Rustmo",
+            "swversion": "1.0",
+            "uniqueid": Uuid::new_v5(&self.uuid, b"sensor").simple().to_string()
+        });
+
+        let state = &mut json["state"];
+        match reading.map(|(reading, _)| reading) {
+            Some(SensorReading::Presence(presence)) => {
+                state["presence"] = serde_json::json!(presence)
+            }
+            Some(SensorReading::Temperature(celsius)) => {
+                state["temperature"] = serde_json::json!((celsius * 100.0).round() as i32)
+            }
+            Some(SensorReading::LightLevel(lux)) => {
+                let light_level = hue_light_level(lux);
+                state["lightlevel"] = serde_json::json!(light_level);
+                state["dark"] = serde_json::json!(light_level < HUE_DARK_THRESHOLD);
+                state["daylight"] =
+                    serde_json::json!(light_level >= HUE_DARK_THRESHOLD + HUE_DAYLIGHT_OFFSET);
+            }
+            Some(SensorReading::GenericStatus(status)) => {
+                state["status"] = serde_json::json!(status)
+            }
+            None => {}
+        }
+        if kind == SensorKind::LightLevel {
+            json["config"]["tholddark"] = serde_json::json!(HUE_DARK_THRESHOLD);
+            json["config"]["tholdoffset"] = serde_json::json!(HUE_DAYLIGHT_OFFSET);
+        }
+
+        json
+    }
+}
+
+fn hue_sensor_type(kind: SensorKind) -> &'static str {
+    match kind {
+        SensorKind::Presence => "CLIPPresence",
+        SensorKind::Temperature => "CLIPTemperature",
+        SensorKind::LightLevel => "CLIPLightLevel",
+        SensorKind::GenericStatus => "CLIPGenericStatus",
+    }
+}
+
+///
+/// Hue light levels are `10000 * log10(lux) + 1`
+///
+fn hue_light_level(lux: f32) -> u32 {
+    if lux <= 0.0 {
+        0
+    } else {
+        (10000.0 * lux.log10() + 1.0).round().max(0.0) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::virtual_device::VirtualDeviceError;
+
+    struct Occupancy(Arc<AtomicBool>);
+
+    impl VirtualSensor for Occupancy {
+        fn kind(&self) -> SensorKind {
+            SensorKind::Presence
+        }
+
+        fn read(&self) -> Result<SensorReading, VirtualDeviceError> {
+            Ok(SensorReading::Presence(self.0.load(Ordering::SeqCst)))
+        }
+    }
+
+    struct Broken;
+
+    impl VirtualSensor for Broken {
+        fn kind(&self) -> SensorKind {
+            SensorKind::Temperature
+        }
+
+        fn read(&self) -> Result<SensorReading, VirtualDeviceError> {
+            Ok(SensorReading::GenericStatus(1))
+        }
+    }
+
+    #[test]
+    fn sensors_report_their_readings() {
+        let occupied = Arc::new(AtomicBool::new(false));
+        let sensor = RustmoSensor::new(
+            "Theater".into(),
+            Uuid::nil(),
+            1,
+            Occupancy(occupied.clone()),
+        );
+        let json = sensor.hue_json();
+        assert_eq!(json["type"], "CLIPPresence");
+        assert_eq!(json["state"]["presence"], false);
+        assert_eq!(json["config"]["reachable"], true);
+        assert_ne!(json["state"]["lastupdated"], "none");
+
+        occupied.store(true, Ordering::SeqCst);
+        assert_eq!(sensor.hue_json()["state"]["presence"], true);
+
+        let broken = RustmoSensor::new("Attic".into(), Uuid::nil(), 2, Broken).hue_json();
+        assert_eq!(broken["type"], "CLIPTemperature");
+        assert_eq!(broken["config"]["reachable"], false);
+        assert_eq!(broken["state"]["lastupdated"], "none");
+    }
+
+    #[test]
+    fn light_levels_are_logarithmic() {
+        assert_eq!(hue_light_level(0.0), 0);
+        assert_eq!(hue_light_level(1.0), 1);
+        assert_eq!(hue_light_level(1000.0), 30001);
+    }
+}
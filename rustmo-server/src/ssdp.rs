@@ -1,17 +1,202 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
 use std::str::FromStr;
-use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use net2::unix::UnixUdpBuilderExt;
+use net2::UdpSocketExt;
+use parking_lot::Mutex;
 
-use crate::{RustmoDevice, RustmoDeviceInfo, VirtualDevicesList};
+use crate::{RustmoDevice, RustmoDeviceInfo, RustmoError, VirtualDevicesList};
+
+const SSDP_MULTICAST_ADDRESS_V4: &str = "239.255.255.250:1900";
+
+/// the link-local IPv6 SSDP multicast group
+const SSDP_MULTICAST_ADDRESS_V6: &str = "[ff02::c]:1900";
+
+pub(crate) const SSDP_PORT: u16 = 1900;
+
+/// how often the receive and announce loops wake up to see if they've been asked to stop
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// the `CACHE-CONTROL: max-age` we advertise for Belkin devices
+pub(crate) const BELKIN_MAX_AGE: Duration = Duration::from_secs(86400);
+
+/// the `CACHE-CONTROL: max-age` we advertise for the Hue bridge
+pub(crate) const HUE_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// how many copies of each `ssdp:alive` message to send when a device first appears, since
+/// multicast UDP is unreliable
+pub(crate) const ANNOUNCEMENT_BURST: usize = 3;
 
 #[derive(Clone)]
-pub(crate) struct SsdpListener;
+pub(crate) struct SsdpListener {
+    interface: IpAddr,
+    receiver: Arc<SsdpThread>,
+    announcer: Arc<SsdpThread>,
+    announcements: mpsc::Sender<Announcement>,
+}
+
+///
+/// Owns one of the threads backing a `SsdpListener`.  It's shared between clones of
+/// `SsdpListener` and stops its thread when the last of them goes away
+///
+struct SsdpThread {
+    name: &'static str,
+    stopped: Arc<AtomicBool>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl SsdpThread {
+    fn new(name: &'static str, stopped: Arc<AtomicBool>, thread: JoinHandle<()>) -> Self {
+        SsdpThread {
+            name,
+            stopped,
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    fn stop(&self) {
+        let Some(thread) = self.thread.lock().take() else {
+            return;
+        };
+
+        self.stopped.store(true, Ordering::SeqCst);
+        if thread.join().is_err() {
+            tracing::error!("SSDP {} thread panicked", self.name);
+        }
+    }
+}
+
+impl Drop for SsdpThread {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
+
+///
+/// Something that should be announced right away, rather than waiting for its next periodic
+/// `ssdp:alive`
+///
+pub(crate) enum Announcement {
+    Device(RustmoDeviceInfo),
+    HueBridge,
+}
+
+impl Announcement {
+    ///
+    /// Dimmable devices are reached through the Hue bridge, so it's the bridge that gets
+    /// announced for them
+    ///
+    pub(crate) fn for_device(device: &RustmoDevice) -> Self {
+        if device.is_hue_light() {
+            Announcement::HueBridge
+        } else {
+            Announcement::Device(device.info.clone())
+        }
+    }
+}
+
+///
+/// What we know about the network interface that owns a particular address:  enough to join a
+/// multicast group on it and to tell whether a datagram arrived through it
+///
+#[derive(Clone, Debug)]
+pub(crate) struct NetworkInterface {
+    pub(crate) address: IpAddr,
+    netmask: Option<IpAddr>,
+    index: Option<u32>,
+}
+
+impl NetworkInterface {
+    ///
+    /// Look up the interface that owns `address`.  If it can't be found we still listen on it,
+    /// but can't filter out searches that arrived through other interfaces
+    ///
+    pub(crate) fn lookup(address: IpAddr) -> Self {
+        let found = if_addrs::get_if_addrs()
+            .unwrap_or_else(|e| {
+                tracing::warn!("unable to list network interfaces: {}", e);
+                Vec::new()
+            })
+            .into_iter()
+            .find(|interface| interface.ip() == address);
+        if found.is_none() {
+            tracing::warn!("no network interface has the address {}", address);
+        }
+
+        NetworkInterface {
+            address,
+            netmask: found.as_ref().map(|interface| match &interface.addr {
+                if_addrs::IfAddr::V4(addr) => IpAddr::V4(addr.netmask),
+                if_addrs::IfAddr::V6(addr) => IpAddr::V6(addr.netmask),
+            }),
+            index: found.and_then(|interface| interface.index),
+        }
+    }
+
+    ///
+    /// Did a datagram from `src` (most likely) arrive through this interface?  Sockets bound to
+    /// the wildcard address see multicast traffic from every interface, so each listener only
+    /// answers searches from its own link
+    ///
+    pub(crate) fn is_on_link(&self, src: &SocketAddr) -> bool {
+        match (src, self.address, self.netmask) {
+            // the kernel tells us which interface link-local traffic came in on
+            (SocketAddr::V6(src), IpAddr::V6(_), _) if src.scope_id() != 0 => {
+                self.index.is_none_or(|index| index == src.scope_id())
+            }
+            (SocketAddr::V4(src), IpAddr::V4(address), Some(IpAddr::V4(netmask))) => {
+                let netmask = u32::from(netmask);
+                u32::from(*src.ip()) & netmask == u32::from(address) & netmask
+            }
+            (SocketAddr::V6(src), IpAddr::V6(address), Some(IpAddr::V6(netmask))) => {
+                let netmask = u128::from(netmask);
+                u128::from(*src.ip()) & netmask == u128::from(address) & netmask
+            }
+            (src, address, _) => src.is_ipv4() == address.is_ipv4(),
+        }
+    }
+
+    ///
+    /// The address to bind `port` to on this interface.  Link-local IPv6 addresses are only
+    /// unique within their link, so they need the interface's index as their scope id too
+    ///
+    pub(crate) fn socket_address(&self, port: u16) -> SocketAddr {
+        match self.address {
+            IpAddr::V6(address) if is_link_local(address) => {
+                SocketAddr::V6(SocketAddrV6::new(address, port, 0, self.index.unwrap_or(0)))
+            }
+            address => SocketAddr::new(address, port),
+        }
+    }
+}
 
 ///
-/// `SsdpListener` joins a IPV4 multicast on `239.255.255.250` (as perscribed by the SSDP protocol spec)
-/// and listens to the specified interface on port `1900`
+/// The address to bind `port` to on `interface`, which is only looked up if it's a link-local
+/// IPv6 address that needs a scope id
+///
+pub(crate) fn bind_address(interface: IpAddr, port: u16) -> SocketAddr {
+    match interface {
+        IpAddr::V6(address) if is_link_local(address) => {
+            NetworkInterface::lookup(interface).socket_address(port)
+        }
+        _ => SocketAddr::new(interface, port),
+    }
+}
+
+fn is_link_local(address: Ipv6Addr) -> bool {
+    address.segments()[0] & 0xffc0 == 0xfe80
+}
+
+///
+/// `SsdpListener` joins the SSDP multicast group (`239.255.255.250` for IPv4, `ff02::c` for
+/// IPv6, as prescribed by the SSDP protocol spec) on the specified interface and listens on port
+/// `1900`.  A server listening on several interfaces has one `SsdpListener` for each
 ///
 impl SsdpListener {
     ///
@@ -21,68 +206,237 @@ impl SsdpListener {
     /// `devices` is guarded by a Mutex so that users of this listener can add/remove devices
     /// while we're listening
     ///
+    /// Returns `RustmoError::NetworkError` if the multicast group can't be joined on `interface`
+    ///
     pub(crate) fn listen(
         interface: IpAddr,
         devices: VirtualDevicesList,
         hue_bridge: Option<RustmoDeviceInfo>,
-    ) -> Self {
-        thread::spawn(move || {
-            let mut buf = [0; 65535];
-            let ip = if let IpAddr::V4(ip) = interface {
-                ip
-            } else {
-                panic!("IPv4 is required")
-            };
-            let socket = net2::UdpBuilder::new_v4()
-                .unwrap()
-                .reuse_address(true)
-                .unwrap()
-                .reuse_port(true)
-                .unwrap()
-                .bind("0.0.0.0:1900")
-                .unwrap();
-            socket
-                .join_multicast_v4(&Ipv4Addr::from_str("239.255.255.250").unwrap(), &ip)
-                .unwrap();
-
-            loop {
-                let (len, src) = socket
-                    .recv_from(&mut buf)
-                    .expect("problem receiving data while listening");
-                let dgram = String::from_utf8_lossy(&buf[..len]).to_string();
-
-                // tracing::info!("SSDP discovery from {}:{}", src.ip(), src.port());
-                if let Some(search_target) = SsdpListener::discovery_search_target(&dgram) {
-                    // someone wants to know what devices we have
-                    let devices = devices.read();
-                    let responses = SsdpListener::build_discovery_responses(
-                        &devices,
-                        hue_bridge.as_ref(),
-                        search_target,
-                    );
-                    let hue_bridge_available = hue_bridge.is_some();
-                    tracing::info!(
-                        "SSDP discovery from {} target={:?} devices={} hue_bridge={} responses={}",
-                        src,
-                        search_target,
-                        devices.len(),
-                        hue_bridge_available,
-                        responses.len()
-                    );
-                    for response in responses {
-                        socket.send_to(response.as_bytes(), src).unwrap();
+    ) -> Result<Self, RustmoError> {
+        let network_interface = NetworkInterface::lookup(interface);
+        let socket = bind_multicast_listener(&network_interface)
+            .and_then(|socket| {
+                socket.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+                Ok(socket)
+            })
+            .map_err(|e| {
+                tracing::error!("unable to listen for SSDP on {}: {}", interface, e);
+                RustmoError::NetworkError(e)
+            })?;
+
+        let (announcements, pending_announcements) = mpsc::channel();
+        let announcer = SsdpListener::announce_periodically(
+            interface,
+            devices.clone(),
+            hue_bridge.clone(),
+            pending_announcements,
+        );
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                let mut buf = [0; 65535];
+
+                while !stopped.load(Ordering::SeqCst) {
+                    let (len, src) = match socket.recv_from(&mut buf) {
+                        Ok(received) => received,
+                        Err(e)
+                            if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                        {
+                            continue
+                        }
+                        Err(e) => {
+                            tracing::warn!("problem receiving SSDP data on {}: {}", interface, e);
+                            continue;
+                        }
+                    };
+                    let dgram = String::from_utf8_lossy(&buf[..len]).to_string();
+
+                    // tracing::info!("SSDP discovery from {}:{}", src.ip(), src.port());
+                    if !network_interface.is_on_link(&src) {
+                        continue;
+                    }
+                    if let Some(search_target) = SsdpListener::discovery_search_target(&dgram) {
+                        // someone wants to know what devices we have
+                        let devices = devices.read();
+                        let responses = SsdpListener::build_discovery_responses(
+                            &devices,
+                            hue_bridge.as_ref(),
+                            search_target,
+                            interface,
+                        );
+                        let hue_bridge_available = hue_bridge.is_some();
+                        tracing::info!(
+                            "SSDP discovery from {} target={:?} devices={} hue_bridge={} responses={}",
+                            src,
+                            search_target,
+                            devices.len(),
+                            hue_bridge_available,
+                            responses.len()
+                        );
+                        for response in responses {
+                            if let Err(e) = socket.send_to(response.as_bytes(), src) {
+                                tracing::warn!(
+                                    "unable to answer SSDP discovery from {}: {}",
+                                    src,
+                                    e
+                                );
+                            }
+                        }
                     }
                 }
-            }
-        });
 
-        SsdpListener
+                tracing::info!("SSDP listener on {} stopped", interface);
+            })
+        };
+
+        Ok(SsdpListener {
+            interface,
+            receiver: Arc::new(SsdpThread::new("listener", stopped, thread)),
+            announcer: Arc::new(announcer),
+            announcements,
+        })
+    }
+
+    ///
+    /// Multicast `ssdp:alive` notifications for every Belkin device, and for the Hue bridge when
+    /// there are dimmable devices, at half of their advertised `max-age` so they never expire.
+    ///
+    /// Anything sent through `pending_announcements` is announced immediately, in a burst.
+    ///
+    fn announce_periodically(
+        interface: IpAddr,
+        devices: VirtualDevicesList,
+        hue_bridge: Option<RustmoDeviceInfo>,
+        pending_announcements: mpsc::Receiver<Announcement>,
+    ) -> SsdpThread {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                let mut next_belkin_announcement = Instant::now();
+                let mut next_hue_announcement = Instant::now();
+
+                while !stopped.load(Ordering::SeqCst) {
+                    match pending_announcements.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+                        Ok(Announcement::Device(device)) => {
+                            let message = SsdpListener::build_belkin_notify(
+                                &device.on_interface(interface),
+                                "ssdp:alive",
+                            );
+                            send_burst(interface, &[message]);
+                        }
+                        Ok(Announcement::HueBridge) => {
+                            if let Some(hue_bridge) = hue_bridge.as_ref() {
+                                let hue_bridge = hue_bridge.on_interface(interface);
+                                send_burst(interface, &SsdpListener::build_hue_alive(&hue_bridge));
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+
+                    let now = Instant::now();
+                    if now >= next_belkin_announcement {
+                        send_all(
+                            interface,
+                            &SsdpListener::build_belkin_alive(&devices.read(), interface),
+                        );
+                        next_belkin_announcement = now + BELKIN_MAX_AGE / 2;
+                    }
+
+                    if now >= next_hue_announcement {
+                        let messages = SsdpListener::build_periodic_hue_alive(
+                            &devices.read(),
+                            hue_bridge.as_ref(),
+                            interface,
+                        );
+                        send_all(interface, &messages);
+                        next_hue_announcement = now + HUE_MAX_AGE / 2;
+                    }
+                }
+
+                tracing::info!("SSDP announcer on {} stopped", interface);
+            })
+        };
+
+        SsdpThread::new("announcer", stopped, thread)
     }
 
-    fn build_discovery_responses(
+    ///
+    /// Announce a newly added device right away so that Alexa can find it without being asked
+    /// to discover devices
+    ///
+    pub(crate) fn announce(&self, device: &RustmoDevice) {
+        if !device.info.is_discoverable_on(self.interface) {
+            return;
+        }
+        if self
+            .announcements
+            .send(Announcement::for_device(device))
+            .is_err()
+        {
+            tracing::warn!(
+                "unable to announce `{}`: SSDP announcer has stopped",
+                device.info.name
+            );
+        }
+    }
+
+    ///
+    /// Multicast an `ssdp:byebye` notification for the specified device so that anyone who
+    /// previously discovered it knows it's gone
+    ///
+    pub(crate) fn notify_byebye(&self, device: &RustmoDeviceInfo) {
+        if device.is_discoverable_on(self.interface) {
+            notify_byebye(self.interface, device)
+        }
+    }
+
+    ///
+    /// The periodic `ssdp:alive` notifications for every Belkin device, as reached through
+    /// `interface`
+    ///
+    pub(crate) fn build_belkin_alive(devices: &[RustmoDevice], interface: IpAddr) -> Vec<String> {
+        devices
+            .iter()
+            .filter(|device| !device.is_hue_light() && device.info.is_discoverable_on(interface))
+            .map(|device| {
+                SsdpListener::build_belkin_notify(
+                    &device.info.on_interface(interface),
+                    "ssdp:alive",
+                )
+            })
+            .collect()
+    }
+
+    ///
+    /// The periodic `ssdp:alive` notifications for the Hue bridge, which is only announced while
+    /// there are dimmable devices behind it
+    ///
+    pub(crate) fn build_periodic_hue_alive(
+        devices: &[RustmoDevice],
+        hue_bridge: Option<&RustmoDeviceInfo>,
+        interface: IpAddr,
+    ) -> Vec<String> {
+        match hue_bridge {
+            Some(hue_bridge) if devices.iter().any(|device| device.is_hue_light()) => {
+                SsdpListener::build_hue_alive(&hue_bridge.on_interface(interface))
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    ///
+    /// The responses to a discovery request that arrived through `interface`, which is where
+    /// each response's `LOCATION` points
+    ///
+    pub(crate) fn build_discovery_responses(
         devices: &[RustmoDevice],
         hue_bridge: Option<&RustmoDeviceInfo>,
         search_target: DiscoverySearchTarget,
+        interface: IpAddr,
     ) -> Vec<String> {
         let mut responses = Vec::new();
 
@@ -91,11 +445,11 @@ impl SsdpListener {
             DiscoverySearchTarget::HueBasic
                 | DiscoverySearchTarget::HueRootDevice
                 | DiscoverySearchTarget::All
-        ) && devices.iter().any(|device| device.supports_percent())
+        ) && devices.iter().any(|device| device.is_hue_light())
         {
             if let Some(hue_bridge) = hue_bridge {
                 responses.extend(SsdpListener::build_hue_discovery_responses(
-                    hue_bridge,
+                    &hue_bridge.on_interface(interface),
                     search_target,
                 ));
             }
@@ -108,8 +462,14 @@ impl SsdpListener {
             responses.extend(
                 devices
                     .iter()
-                    .filter(|device| !device.supports_percent())
-                    .map(|device| SsdpListener::build_belkin_discovery_response(&device.info)),
+                    .filter(|device| {
+                        !device.is_hue_light() && device.info.is_discoverable_on(interface)
+                    })
+                    .map(|device| {
+                        SsdpListener::build_belkin_discovery_response(
+                            &device.info.on_interface(interface),
+                        )
+                    }),
             );
         }
 
@@ -119,13 +479,15 @@ impl SsdpListener {
     fn build_belkin_discovery_response(device: &RustmoDeviceInfo) -> String {
         let mut response = String::new();
         response.push_str("HTTP/1.1 200 OK\r\n");
-        response.push_str("CACHE-CONTROL: max-age=86400\r\n");
+        response
+            .push_str(format!("CACHE-CONTROL: max-age={}\r\n", BELKIN_MAX_AGE.as_secs()).as_str());
         response.push_str("DATE: Sat, 26 Nov 2016 04:56:29 GMT\r\n");
         response.push_str("EXT:\r\n");
         response.push_str(
             format!(
-                "LOCATION: http://{}:{}/setup.xml\r\n",
-                device.ip_address, device.port
+                "LOCATION: http://{}{}/setup.xml\r\n",
+                SocketAddr::new(device.ip_address, device.port),
+                device.base_path
             )
             .as_str(),
         );
@@ -138,6 +500,28 @@ impl SsdpListener {
         response
     }
 
+    pub(crate) fn build_belkin_notify(device: &RustmoDeviceInfo, nts: &str) -> String {
+        let mut message = String::new();
+        message.push_str("NOTIFY * HTTP/1.1\r\n");
+        message.push_str(format!("HOST: {}\r\n", multicast_address(device.ip_address)).as_str());
+        message
+            .push_str(format!("CACHE-CONTROL: max-age={}\r\n", BELKIN_MAX_AGE.as_secs()).as_str());
+        message.push_str(
+            format!(
+                "LOCATION: http://{}{}/setup.xml\r\n",
+                SocketAddr::new(device.ip_address, device.port),
+                device.base_path
+            )
+            .as_str(),
+        );
+        message.push_str("SERVER: Theater, UPnP/1.0, Unspecified\r\n");
+        message.push_str("NT: urn:Belkin:device:**\r\n");
+        message.push_str(format!("NTS: {nts}\r\n").as_str());
+        message.push_str(format!("USN: uuid:{}::urn:Belkin:device:**\r\n", device.uuid).as_str());
+        message.push_str("\r\n");
+        message
+    }
+
     fn build_hue_discovery_responses(
         device: &RustmoDeviceInfo,
         search_target: DiscoverySearchTarget,
@@ -164,12 +548,12 @@ impl SsdpListener {
     fn build_hue_discovery_response(device: &RustmoDeviceInfo, st: &str, usn: String) -> String {
         let mut response = String::new();
         response.push_str("HTTP/1.1 200 OK\r\n");
-        response.push_str("CACHE-CONTROL: max-age=60\r\n");
+        response.push_str(format!("CACHE-CONTROL: max-age={}\r\n", HUE_MAX_AGE.as_secs()).as_str());
         response.push_str("EXT:\r\n");
         response.push_str(
             format!(
-                "LOCATION: http://{}:{}/description.xml\r\n",
-                device.ip_address, device.port
+                "LOCATION: http://{}/description.xml\r\n",
+                SocketAddr::new(device.ip_address, device.port)
             )
             .as_str(),
         );
@@ -181,7 +565,48 @@ impl SsdpListener {
         response
     }
 
-    fn discovery_search_target(dgram: &str) -> Option<DiscoverySearchTarget> {
+    pub(crate) fn build_hue_alive(device: &RustmoDeviceInfo) -> Vec<String> {
+        vec![
+            SsdpListener::build_hue_notify(
+                device,
+                "upnp:rootdevice",
+                format!("uuid:{}::upnp:rootdevice", device.uuid),
+            ),
+            SsdpListener::build_hue_notify(
+                device,
+                format!("uuid:{}", device.uuid).as_str(),
+                format!("uuid:{}", device.uuid),
+            ),
+            SsdpListener::build_hue_notify(
+                device,
+                "urn:schemas-upnp-org:device:basic:1",
+                format!("uuid:{}", device.uuid),
+            ),
+        ]
+    }
+
+    fn build_hue_notify(device: &RustmoDeviceInfo, nt: &str, usn: String) -> String {
+        let mut message = String::new();
+        message.push_str("NOTIFY * HTTP/1.1\r\n");
+        message.push_str(format!("HOST: {}\r\n", multicast_address(device.ip_address)).as_str());
+        message.push_str(format!("CACHE-CONTROL: max-age={}\r\n", HUE_MAX_AGE.as_secs()).as_str());
+        message.push_str(
+            format!(
+                "LOCATION: http://{}/description.xml\r\n",
+                SocketAddr::new(device.ip_address, device.port)
+            )
+            .as_str(),
+        );
+        message.push_str("SERVER: FreeRTOS/6.0.5, UPnP/1.0, IpBridge/1.16.0\r\n");
+        message.push_str(format!("hue-bridgeid: {}\r\n", hue_bridge_id(device)).as_str());
+        message.push_str(format!("NT: {nt}\r\n").as_str());
+        message.push_str("NTS: ssdp:alive\r\n");
+        message.push_str(format!("USN: {usn}\r\n").as_str());
+        message.push_str("\r\n");
+        message
+    }
+
+    pub(crate) fn discovery_search_target(dgram: &str) -> Option<DiscoverySearchTarget> {
         let dgram = dgram.to_lowercase();
         // NOTE:  make sure these patterns are all lowercase
         if !dgram.contains("man: \"ssdp:discover\"") {
@@ -201,27 +626,127 @@ impl SsdpListener {
         }
     }
 
+    ///
+    /// Stop listening for SSDP requests and sending announcements, and wait for both loops to
+    /// finish
+    ///
     pub(crate) fn stop(&self) {
-        // TODO:  how to stop the socket?
+        self.receiver.stop();
+        self.announcer.stop();
+    }
+}
+
+///
+/// The SSDP multicast group (and port) for `interface`'s address family
+///
+pub(crate) fn multicast_address(interface: IpAddr) -> &'static str {
+    match interface {
+        IpAddr::V4(_) => SSDP_MULTICAST_ADDRESS_V4,
+        IpAddr::V6(_) => SSDP_MULTICAST_ADDRESS_V6,
+    }
+}
+
+///
+/// Bind the socket SSDP discovery requests arrive on:  port `1900`, joined to the SSDP multicast
+/// group on the specified interface
+///
+pub(crate) fn bind_multicast_listener(interface: &NetworkInterface) -> std::io::Result<UdpSocket> {
+    match interface.address {
+        IpAddr::V4(ip) => {
+            let socket = net2::UdpBuilder::new_v4()?
+                .reuse_address(true)?
+                .reuse_port(true)?
+                .bind(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    SSDP_PORT,
+                ))?;
+            socket.join_multicast_v4(&Ipv4Addr::from_str("239.255.255.250").unwrap(), &ip)?;
+            Ok(socket)
+        }
+        IpAddr::V6(_) => {
+            let builder = net2::UdpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            let socket = builder
+                .reuse_address(true)?
+                .reuse_port(true)?
+                .bind(SocketAddr::new(
+                    IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                    SSDP_PORT,
+                ))?;
+            socket.join_multicast_v6(
+                &Ipv6Addr::from_str("ff02::c").unwrap(),
+                interface.index.unwrap_or(0),
+            )?;
+            Ok(socket)
+        }
+    }
+}
+
+///
+/// Bind a socket for multicasting SSDP notifications out of the specified interface
+///
+pub(crate) fn bind_multicast_sender(interface: &NetworkInterface) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(interface.socket_address(0))?;
+    match interface.address {
+        IpAddr::V4(ip) => socket.set_multicast_if_v4(&ip)?,
+        IpAddr::V6(_) => socket.set_multicast_if_v6(interface.index.unwrap_or(0))?,
+    }
+    Ok(socket)
+}
+
+///
+/// Multicast an `ssdp:byebye` notification for the specified device so that anyone who
+/// previously discovered it knows it's gone
+///
+pub(crate) fn notify_byebye(interface: IpAddr, device: &RustmoDeviceInfo) {
+    let message = SsdpListener::build_belkin_notify(&device.on_interface(interface), "ssdp:byebye");
+    if let Err(e) = send_multicast(interface, &[message]) {
+        tracing::warn!(
+            "unable to send SSDP byebye for `{}` on {}: {}",
+            device.name,
+            interface,
+            e
+        );
+    }
+}
+
+fn send_multicast(interface: IpAddr, messages: &[String]) -> std::io::Result<()> {
+    let socket = bind_multicast_sender(&NetworkInterface::lookup(interface))?;
+    for message in messages {
+        socket.send_to(message.as_bytes(), multicast_address(interface))?;
+    }
+    Ok(())
+}
+
+fn send_all(interface: IpAddr, messages: &[String]) {
+    if messages.is_empty() {
+        return;
+    }
+
+    if let Err(e) = send_multicast(interface, messages) {
+        tracing::warn!("unable to send SSDP alive on {}: {}", interface, e);
+    }
+}
+
+fn send_burst(interface: IpAddr, messages: &[String]) {
+    for i in 0..ANNOUNCEMENT_BURST {
+        if i > 0 {
+            thread::sleep(Duration::from_millis(100));
+        }
+        send_all(interface, messages);
     }
 }
 
 #[derive(Clone, Copy, Debug)]
-enum DiscoverySearchTarget {
+pub(crate) enum DiscoverySearchTarget {
     All,
     Belkin,
     HueBasic,
     HueRootDevice,
 }
 
-fn hue_bridge_id(_device: &RustmoDeviceInfo) -> String {
-    "001788FFFE23BFC2".to_string()
-}
-
-impl Drop for SsdpListener {
-    fn drop(&mut self) {
-        self.stop()
-    }
+pub(crate) fn hue_bridge_id(device: &RustmoDeviceInfo) -> String {
+    crate::upnp::hue_serial(device)
 }
 
 #[cfg(test)]
@@ -231,7 +756,12 @@ mod tests {
     use uuid::Uuid;
 
     use super::*;
-    use crate::virtual_device::{VirtualDevice, VirtualDeviceError, VirtualDeviceState};
+    use crate::HueBridgeConfig;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    use crate::virtual_device::{
+        DimmerExposure, VirtualDevice, VirtualDeviceError, VirtualDeviceState,
+    };
 
     struct BinaryDevice;
 
@@ -269,25 +799,57 @@ mod tests {
         }
     }
 
-    fn rustmo_device(device: Box<dyn VirtualDevice>) -> RustmoDevice {
+    struct WemoDimmer;
+
+    impl VirtualDevice for WemoDimmer {
+        fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            Ok(VirtualDeviceState::On)
+        }
+
+        fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            Ok(VirtualDeviceState::Off)
+        }
+
+        fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            Ok(VirtualDeviceState::Off)
+        }
+
+        fn supports_percent(&self) -> bool {
+            true
+        }
+
+        fn dimmer_exposure(&self) -> DimmerExposure {
+            DimmerExposure::WemoDimmer
+        }
+    }
+
+    fn rustmo_device(device: Arc<dyn VirtualDevice>) -> RustmoDevice {
         RustmoDevice {
             info: RustmoDeviceInfo {
-                name: "Light".to_string(),
+                name: crate::DeviceName::new("Light"),
                 ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
                 port: 1100,
                 uuid: Uuid::nil(),
+                hue_id: None,
+                base_path: String::new(),
+                hue_bridge: None,
+                changes: Default::default(),
+                interfaces: Vec::new(),
             },
             device,
+            http_servers: Vec::new(),
+            hue_group: None,
         }
     }
 
     #[test]
     fn percent_devices_do_not_answer_belkin_searches() {
-        let device = rustmo_device(Box::new(PercentDevice));
+        let device = rustmo_device(Arc::new(PercentDevice));
         let responses = SsdpListener::build_discovery_responses(
             &[device],
             Some(&hue_bridge()),
             DiscoverySearchTarget::Belkin,
+            LOCALHOST,
         );
 
         assert!(responses.is_empty());
@@ -295,12 +857,13 @@ mod tests {
 
     #[test]
     fn percent_devices_answer_hue_searches() {
-        let device = rustmo_device(Box::new(PercentDevice));
+        let device = rustmo_device(Arc::new(PercentDevice));
         let bridge = hue_bridge();
         let responses = SsdpListener::build_discovery_responses(
             &[device],
             Some(&bridge),
             DiscoverySearchTarget::HueBasic,
+            LOCALHOST,
         );
 
         assert_eq!(responses.len(), 1);
@@ -312,12 +875,13 @@ mod tests {
 
     #[test]
     fn percent_devices_answer_rootdevice_searches() {
-        let device = rustmo_device(Box::new(PercentDevice));
+        let device = rustmo_device(Arc::new(PercentDevice));
         let bridge = hue_bridge();
         let responses = SsdpListener::build_discovery_responses(
             &[device],
             Some(&bridge),
             DiscoverySearchTarget::HueRootDevice,
+            LOCALHOST,
         );
 
         assert_eq!(responses.len(), 1);
@@ -328,9 +892,29 @@ mod tests {
 
     #[test]
     fn binary_devices_still_answer_belkin_searches() {
-        let device = rustmo_device(Box::new(BinaryDevice));
-        let responses =
-            SsdpListener::build_discovery_responses(&[device], None, DiscoverySearchTarget::Belkin);
+        let device = rustmo_device(Arc::new(BinaryDevice));
+        let responses = SsdpListener::build_discovery_responses(
+            &[device],
+            None,
+            DiscoverySearchTarget::Belkin,
+            LOCALHOST,
+        );
+
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].contains("ST: urn:Belkin:device:**"));
+        assert!(responses[0].contains("LOCATION: http://127.0.0.1:1100/setup.xml"));
+    }
+
+    #[test]
+    fn wemo_dimmers_answer_belkin_searches_instead_of_hue_searches() {
+        let device = rustmo_device(Arc::new(WemoDimmer));
+        let bridge = hue_bridge();
+        let responses = SsdpListener::build_discovery_responses(
+            &[device],
+            Some(&bridge),
+            DiscoverySearchTarget::All,
+            LOCALHOST,
+        );
 
         assert_eq!(responses.len(), 1);
         assert!(responses[0].contains("ST: urn:Belkin:device:**"));
@@ -339,13 +923,14 @@ mod tests {
 
     #[test]
     fn all_searches_return_one_hue_bridge_device_response_for_many_percent_devices() {
-        let first = rustmo_device(Box::new(PercentDevice));
-        let second = rustmo_device(Box::new(PercentDevice));
+        let first = rustmo_device(Arc::new(PercentDevice));
+        let second = rustmo_device(Arc::new(PercentDevice));
         let bridge = hue_bridge();
         let responses = SsdpListener::build_discovery_responses(
             &[first, second],
             Some(&bridge),
             DiscoverySearchTarget::All,
+            LOCALHOST,
         );
 
         assert_eq!(responses.len(), 1);
@@ -353,12 +938,170 @@ mod tests {
         assert!(responses[0].contains("ST: urn:schemas-upnp-org:device:basic:1"));
     }
 
+    #[test]
+    fn byebye_notify_matches_belkin_discovery_identity() {
+        let device = rustmo_device(Arc::new(BinaryDevice));
+        let message = SsdpListener::build_belkin_notify(&device.info, "ssdp:byebye");
+
+        assert!(message.starts_with("NOTIFY * HTTP/1.1\r\n"));
+        assert!(message.contains("HOST: 239.255.255.250:1900\r\n"));
+        assert!(message.contains("NT: urn:Belkin:device:**\r\n"));
+        assert!(message.contains("NTS: ssdp:byebye\r\n"));
+        assert!(message
+            .contains("USN: uuid:00000000-0000-0000-0000-000000000000::urn:Belkin:device:**\r\n"));
+        assert!(message.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn shared_listener_devices_advertise_their_path_prefix() {
+        let mut device = rustmo_device(Arc::new(BinaryDevice));
+        device.info.base_path = "/device/00000000-0000-0000-0000-000000000000".to_string();
+        let responses = SsdpListener::build_discovery_responses(
+            &[device],
+            None,
+            DiscoverySearchTarget::Belkin,
+            LOCALHOST,
+        );
+
+        assert!(responses[0].contains(
+            "LOCATION: http://127.0.0.1:1100/device/00000000-0000-0000-0000-000000000000/setup.xml"
+        ));
+    }
+
+    #[test]
+    fn alive_notify_respects_belkin_max_age() {
+        let device = rustmo_device(Arc::new(BinaryDevice));
+        let message = SsdpListener::build_belkin_notify(&device.info, "ssdp:alive");
+
+        assert!(message.contains("CACHE-CONTROL: max-age=86400\r\n"));
+        assert!(message.contains("NTS: ssdp:alive\r\n"));
+        assert!(message.contains("LOCATION: http://127.0.0.1:1100/setup.xml\r\n"));
+    }
+
+    #[test]
+    fn hue_bridge_alive_announces_each_notification_type() {
+        let messages = SsdpListener::build_hue_alive(&hue_bridge());
+
+        assert_eq!(messages.len(), 3);
+        assert!(messages
+            .iter()
+            .all(|message| message.contains("NTS: ssdp:alive\r\n")));
+        assert!(messages
+            .iter()
+            .all(|message| message.contains("CACHE-CONTROL: max-age=60\r\n")));
+        assert!(messages[0].contains("NT: upnp:rootdevice\r\n"));
+        assert!(messages[1].contains("NT: uuid:00000000-0000-0000-0000-000000000000\r\n"));
+        assert!(messages
+            .iter()
+            .all(|message| message.contains("hue-bridgeid: 001788FFFE23BFC2\r\n")));
+        assert!(messages[2].contains("NT: urn:schemas-upnp-org:device:basic:1\r\n"));
+    }
+
+    #[test]
+    fn location_points_at_the_interface_the_search_arrived_on() {
+        let device = rustmo_device(Arc::new(BinaryDevice));
+        let vlan = IpAddr::V4(Ipv4Addr::new(10, 20, 0, 5));
+        let responses = SsdpListener::build_discovery_responses(
+            &[device],
+            None,
+            DiscoverySearchTarget::Belkin,
+            vlan,
+        );
+
+        assert!(responses[0].contains("LOCATION: http://10.20.0.5:1100/setup.xml"));
+    }
+
+    #[test]
+    fn ipv6_notifications_use_the_ipv6_multicast_group() {
+        let device = rustmo_device(Arc::new(BinaryDevice));
+        let interface = IpAddr::V6("fd00::5".parse().unwrap());
+        let notify =
+            SsdpListener::build_belkin_notify(&device.info.on_interface(interface), "ssdp:alive");
+
+        assert!(notify.contains("HOST: [ff02::c]:1900\r\n"));
+        assert!(notify.contains("LOCATION: http://[fd00::5]:1100/setup.xml\r\n"));
+    }
+
+    #[test]
+    fn searches_are_only_answered_on_their_own_link() {
+        let interface = NetworkInterface {
+            address: IpAddr::V4(Ipv4Addr::new(10, 20, 0, 5)),
+            netmask: Some(IpAddr::V4(Ipv4Addr::new(255, 255, 255, 0))),
+            index: Some(3),
+        };
+
+        assert!(interface.is_on_link(&"10.20.0.77:50000".parse().unwrap()));
+        assert!(!interface.is_on_link(&"10.30.0.77:50000".parse().unwrap()));
+        assert!(!interface.is_on_link(&"[fd00::77]:50000".parse().unwrap()));
+
+        let interface = NetworkInterface {
+            address: IpAddr::V6("fd00::5".parse().unwrap()),
+            netmask: Some(IpAddr::V6("ffff:ffff:ffff:ffff::".parse().unwrap())),
+            index: Some(3),
+        };
+        let link_local = |scope_id| {
+            SocketAddr::V6(SocketAddrV6::new(
+                "fe80::77".parse().unwrap(),
+                50000,
+                0,
+                scope_id,
+            ))
+        };
+
+        assert!(interface.is_on_link(&link_local(3)));
+        assert!(!interface.is_on_link(&link_local(4)));
+        assert!(interface.is_on_link(&"[fd00::77]:50000".parse().unwrap()));
+    }
+
+    #[test]
+    fn link_local_addresses_are_bound_with_their_scope_id() {
+        let interface = NetworkInterface {
+            address: IpAddr::V6("fe80::5".parse().unwrap()),
+            netmask: None,
+            index: Some(3),
+        };
+        let SocketAddr::V6(address) = interface.socket_address(1100) else {
+            panic!("an IPv6 interface should have an IPv6 address");
+        };
+        assert_eq!(address.scope_id(), 3);
+
+        let interface = NetworkInterface {
+            address: IpAddr::V6("fd00::5".parse().unwrap()),
+            ..interface
+        };
+        let SocketAddr::V6(address) = interface.socket_address(1100) else {
+            panic!("an IPv6 interface should have an IPv6 address");
+        };
+        assert_eq!(address.scope_id(), 0);
+    }
+
+    #[test]
+    fn devices_are_only_discovered_on_their_own_interfaces() {
+        let iot = IpAddr::V4(Ipv4Addr::new(10, 20, 0, 5));
+        let media = IpAddr::V4(Ipv4Addr::new(10, 30, 0, 5));
+        let mut device = rustmo_device(Arc::new(BinaryDevice));
+        device.info.interfaces = vec![iot];
+        let devices = [device];
+
+        let search = |interface| {
+            SsdpListener::build_discovery_responses(
+                &devices,
+                None,
+                DiscoverySearchTarget::Belkin,
+                interface,
+            )
+        };
+
+        assert_eq!(search(iot).len(), 1);
+        assert!(search(media).is_empty());
+        assert!(SsdpListener::build_belkin_alive(&devices, media).is_empty());
+    }
+
     fn hue_bridge() -> RustmoDeviceInfo {
-        RustmoDeviceInfo {
-            name: "Rustmo Hue Bridge".to_string(),
-            ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
-            port: 80,
-            uuid: Uuid::nil(),
-        }
+        HueBridgeConfig::new(80)
+            .mac("00:17:88:23:bf:c2")
+            .uuid(Uuid::nil())
+            .device_info(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .unwrap()
     }
 }
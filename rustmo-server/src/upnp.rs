@@ -1,15 +1,18 @@
-use std::borrow::BorrowMut;
-use std::io::Read;
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::thread;
+use std::sync::Arc;
 
-use hyper::method::Method;
-use hyper::server::{Fresh, Handler, Request, Response};
-use regex::Regex;
+use parking_lot::{Mutex, RwLock};
 use serde_xml_rs::from_reader;
+use uuid::Uuid;
 
-use crate::virtual_device::{VirtualDeviceError, VirtualDeviceState};
-use crate::{RustmoDevice, RustmoDeviceInfo, VirtualDevicesList};
+use crate::gena::{parse_callbacks, parse_timeout, EventPublisher};
+use crate::http::{HttpRequest, HttpResponse, HttpServer, HttpService};
+use crate::sensors::VirtualSensorsList;
+use crate::ssdp::bind_address;
+use crate::virtual_device::{Color, VirtualDevice, VirtualDeviceState};
+use crate::whitelist::{HueWhitelist, PairingError};
+use crate::{DeviceRenamer, RustmoDevice, RustmoDeviceInfo, RustmoError, VirtualDevicesList};
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct BinaryState {
@@ -35,6 +38,38 @@ impl BinaryState {
     }
 }
 
+///
+/// What kind of Hue group a device group is exposed as
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HueGroupKind {
+    /// a room, which Hue apps (and Alexa) treat as a place, and which a light can only be in one of
+    Room,
+
+    /// an arbitrary collection of lights
+    LightGroup,
+}
+
+impl HueGroupKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HueGroupKind::Room => "Room",
+            HueGroupKind::LightGroup => "LightGroup",
+        }
+    }
+}
+
+///
+/// A device group, as exposed by the Hue bridge under `/groups`
+///
+#[derive(Debug, Clone)]
+pub(crate) struct HueGroup {
+    pub(crate) id: usize,
+    pub(crate) kind: HueGroupKind,
+    /// the uuid of each member device
+    pub(crate) members: Vec<Uuid>,
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 pub(crate) struct UpnpBody {
@@ -43,6 +78,15 @@ pub(crate) struct UpnpBody {
 
     #[serde(rename = "SetBinaryState")]
     pub(crate) set_binary_state: Option<BinaryState>,
+
+    #[serde(rename = "ChangeFriendlyName")]
+    pub(crate) change_friendly_name: Option<FriendlyName>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct FriendlyName {
+    #[serde(rename = "FriendlyName")]
+    pub(crate) friendly_name: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -52,7 +96,10 @@ pub(crate) struct UpnpEnvelope {
 }
 
 pub(crate) struct DeviceHttpServerHandler {
-    device: RustmoDevice,
+    device: Arc<RustmoDevice>,
+    events: EventPublisher,
+    /// how to rename the device, unless it isn't part of a `RustmoServer`
+    renamer: Option<DeviceRenamer>,
 }
 
 unsafe impl Sync for DeviceHttpServerHandler {}
@@ -60,184 +107,382 @@ unsafe impl Send for DeviceHttpServerHandler {}
 
 pub(crate) fn start_hue_bridge_http_server(
     bridge: RustmoDeviceInfo,
+    interfaces: &[IpAddr],
     bind_port: u16,
     devices: VirtualDevicesList,
-) -> bool {
-    let bind_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), bind_port);
-    let server = match hyper::Server::http(bind_address) {
-        Ok(server) => server,
-        Err(error) => {
-            tracing::warn!(
-                "unable to start Hue bridge server on {} advertised as {}:{}: {}",
-                bind_address,
-                bridge.ip_address,
-                bridge.port,
-                error
-            );
-            return false;
-        }
-    };
+    sensors: VirtualSensorsList,
+    renamer: DeviceRenamer,
+    whitelist: Arc<Mutex<HueWhitelist>>,
+) -> Vec<HttpServer> {
+    let handler = Arc::new(HueBridgeHttpServerHandler::new(
+        bridge.clone(),
+        devices,
+        sensors,
+        renamer,
+        whitelist,
+    ));
+    hue_bridge_bind_addresses(interfaces, bind_port)
+        .into_iter()
+        .filter_map(
+            |bind_address| match HttpServer::bind(bind_address, handler.clone()) {
+                Ok(server) => {
+                    tracing::info!(
+                        "starting Hue bridge HTTP server on {} advertised on port {}",
+                        bind_address,
+                        bridge.port
+                    );
+                    Some(server)
+                }
+                Err(error) => {
+                    tracing::warn!(
+                        "unable to start Hue bridge server on {} advertised on port {}: {}",
+                        bind_address,
+                        bridge.port,
+                        error
+                    );
+                    None
+                }
+            },
+        )
+        .collect()
+}
 
-    tracing::info!(
-        "starting Hue bridge HTTP server on {} advertised as http://{}:{}/description.xml",
-        bind_address,
-        bridge.ip_address,
-        bridge.port
+///
+/// The Hue bridge listens on every IPv4 address at once (as it always has) if any of
+/// `interfaces` is IPv4, and on each IPv6 interface individually
+///
+pub(crate) fn hue_bridge_bind_addresses(interfaces: &[IpAddr], bind_port: u16) -> Vec<SocketAddr> {
+    let mut addresses = Vec::new();
+    if interfaces.iter().any(IpAddr::is_ipv4) {
+        addresses.push(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            bind_port,
+        ));
+    }
+    addresses.extend(
+        interfaces
+            .iter()
+            .filter(|interface| interface.is_ipv6())
+            .map(|interface| bind_address(*interface, bind_port)),
     );
+    addresses
+}
 
-    thread::spawn(move || {
-        server
-            .handle(HueBridgeHttpServerHandler::new(bridge, devices))
-            .unwrap();
-    });
+impl HttpService for DeviceHttpServerHandler {
+    fn respond(&self, request: HttpRequest) -> HttpResponse {
+        let path = request.path.clone();
+        self.respond_to(&path, request)
+    }
+}
+
+///
+/// Routes requests for every device served from a shared listener to the device named by the
+/// `/device/{uuid}` prefix of the request's path
+///
+#[derive(Clone, Default)]
+pub(crate) struct SharedDeviceRoutes {
+    routes: Arc<RwLock<HashMap<Uuid, Arc<DeviceHttpServerHandler>>>>,
+}
+
+impl SharedDeviceRoutes {
+    pub(crate) fn route(&self, handler: DeviceHttpServerHandler) {
+        self.routes
+            .write()
+            .insert(handler.device.info.uuid, Arc::new(handler));
+    }
+
+    pub(crate) fn unroute(&self, uuid: &Uuid) {
+        self.routes.write().remove(uuid);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.routes.write().clear();
+    }
+}
+
+impl HttpService for SharedDeviceRoutes {
+    fn respond(&self, request: HttpRequest) -> HttpResponse {
+        let path = request.path.clone();
+        let handler = split_shared_device_path(&path)
+            .and_then(|(uuid, path)| Some((self.routes.read().get(&uuid)?.clone(), path)));
+
+        match handler {
+            Some((handler, path)) => handler.respond_to(path, request),
+            None => {
+                tracing::warn!("Unrecognized shared listener request: {:?}", request.path);
+                HttpResponse::not_found()
+            }
+        }
+    }
+}
 
-    true
+///
+/// `SharedDeviceListener` serves every device from a single port (and thread), with each
+/// device's URLs living under `/device/{uuid}`
+///
+pub(crate) struct SharedDeviceListener {
+    port: u16,
+    routes: SharedDeviceRoutes,
+    servers: Vec<HttpServer>,
 }
 
-impl Handler for DeviceHttpServerHandler {
-    fn handle<'r>(&'r self, mut request: Request<'r, '_>, mut response: Response<'r, Fresh>) {
+impl SharedDeviceListener {
+    ///
+    /// Listen on `port` of every one of `interfaces`
+    ///
+    pub(crate) fn bind(interfaces: &[IpAddr], port: u16) -> std::io::Result<Self> {
+        let routes = SharedDeviceRoutes::default();
+        let mut servers = Vec::with_capacity(interfaces.len());
+        for interface in interfaces {
+            match HttpServer::bind(bind_address(*interface, port), routes.clone()) {
+                Ok(server) => servers.push(server),
+                Err(e) => {
+                    servers.iter_mut().for_each(HttpServer::stop);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(SharedDeviceListener {
+            port,
+            routes,
+            servers,
+        })
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub(crate) fn routes(&self) -> &SharedDeviceRoutes {
+        &self.routes
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.servers.iter_mut().for_each(HttpServer::stop);
+        self.routes.clear();
+    }
+}
+
+///
+/// The path prefix for a device served from a shared listener
+///
+pub(crate) fn shared_device_base_path(uuid: &Uuid) -> String {
+    format!("/device/{uuid}")
+}
+
+///
+/// Split a shared listener request path like `/device/{uuid}/setup.xml` into the device's UUID
+/// and the rest of the path
+///
+fn split_shared_device_path(path: &str) -> Option<(Uuid, &str)> {
+    let path = path.strip_prefix("/device/")?;
+    let (uuid, rest) = match path.find('/') {
+        Some(idx) => path.split_at(idx),
+        None => (path, "/"),
+    };
+    Some((Uuid::parse_str(uuid).ok()?, rest))
+}
+
+impl DeviceHttpServerHandler {
+    fn respond_to(&self, path: &str, request: HttpRequest) -> HttpResponse {
         tracing::info!(
             "UPNP request: http://{}:{}{} from {}",
             self.device.info.ip_address,
             self.device.info.port,
-            request.uri,
+            request.path,
             request.remote_addr
         );
-        let request_method = request.method.clone();
-        let body = match request.uri.to_string().as_str() {
-            "/setup.xml" => Some(Body::Xml(self.handle_setup())),
-            "/description.xml" if self.device.supports_percent() => {
-                Some(Body::Xml(self.handle_hue_description()))
-            }
-            "/eventservice.xml" => Some(Body::Xml(self.handle_eventservice())),
-            "/metainfoservice.xml" => Some(Body::Xml(self.handle_metainfoservice())),
-            "/upnp/control/basicevent1" => {
-                Some(Body::Xml(self.handle_basicevent(request.borrow_mut())))
-            }
-            path if self.device.supports_percent() && path.starts_with("/api") => Some(Body::Json(
-                self.handle_hue_api(&request_method, path, request.borrow_mut()),
-            )),
+        let body = match path {
+            "/setup.xml" => Body::Xml(self.handle_setup()),
+            "/eventservice.xml" => Body::Xml(self.handle_eventservice()),
+            "/metainfoservice.xml" => Body::Xml(self.handle_metainfoservice()),
+            "/upnp/control/basicevent1" => match self.handle_basicevent(&request) {
+                Ok(body) => Body::Xml(body),
+                Err(fault) => {
+                    tracing::error!(
+                        "UPNP fault for {}: {} {}",
+                        self.device.info.name,
+                        fault.code,
+                        fault.description
+                    );
+                    Body::SoapFault(fault.to_xml())
+                }
+            },
+            "/upnp/event/basicevent1" => return self.handle_event_subscription(&request),
             _ => {
-                tracing::warn!("Unrecognized request: {:?}", request.uri);
-                *response.status_mut() = hyper::status::StatusCode::NotFound;
-                None
+                tracing::warn!("Unrecognized request: {:?}", request.path);
+                return HttpResponse::not_found();
             }
         };
 
-        if let Some(data) = body {
-            *response.status_mut() = hyper::status::StatusCode::Ok;
-            let content_type = data.content_type();
-            response
-                .headers_mut()
-                .append_raw("CONTENT-TYPE", content_type.as_bytes().to_vec());
-            response.send(data.as_slice()).unwrap();
-        }
+        body.into()
     }
 }
 
 pub(crate) struct HueBridgeHttpServerHandler {
     bridge: RustmoDeviceInfo,
     devices: VirtualDevicesList,
+    sensors: VirtualSensorsList,
+    renamer: DeviceRenamer,
+    whitelist: Arc<Mutex<HueWhitelist>>,
 }
 
 unsafe impl Sync for HueBridgeHttpServerHandler {}
 unsafe impl Send for HueBridgeHttpServerHandler {}
 
-impl Handler for HueBridgeHttpServerHandler {
-    fn handle<'r>(&'r self, mut request: Request<'r, '_>, mut response: Response<'r, Fresh>) {
+impl HttpService for HueBridgeHttpServerHandler {
+    fn respond(&self, request: HttpRequest) -> HttpResponse {
         tracing::info!(
             "HUE bridge request: http://{}:{}{} from {}",
             self.bridge.ip_address,
             self.bridge.port,
-            request.uri,
+            request.path,
             request.remote_addr
         );
 
-        let method = request.method.clone();
-        let path = request.uri.to_string();
-        let body = match path.as_str() {
-            "/description.xml" => Some(Body::Xml(self.handle_description())),
-            path if path.starts_with("/api") => Some(Body::Json(self.handle_api(
-                &method,
-                path,
-                request.borrow_mut(),
-            ))),
+        let body = match request.path.as_str() {
+            "/description.xml" => Body::Xml(self.handle_description(request.local_addr.ip())),
+            path if path.starts_with("/api") => Body::Json(self.handle_api(path, &request)),
             _ => {
-                tracing::warn!("Unrecognized Hue bridge request: {:?}", request.uri);
-                *response.status_mut() = hyper::status::StatusCode::NotFound;
-                None
+                tracing::warn!("Unrecognized Hue bridge request: {:?}", request.path);
+                return HttpResponse::not_found();
             }
         };
 
-        if let Some(data) = body {
-            *response.status_mut() = hyper::status::StatusCode::Ok;
-            response
-                .headers_mut()
-                .append_raw("CONTENT-TYPE", data.content_type().as_bytes().to_vec());
-            response.send(data.as_slice()).unwrap();
-        }
+        body.into()
     }
 }
 
 impl HueBridgeHttpServerHandler {
-    fn new(bridge: RustmoDeviceInfo, devices: VirtualDevicesList) -> Self {
-        Self { bridge, devices }
+    pub(crate) fn new(
+        bridge: RustmoDeviceInfo,
+        devices: VirtualDevicesList,
+        sensors: VirtualSensorsList,
+        renamer: DeviceRenamer,
+        whitelist: Arc<Mutex<HueWhitelist>>,
+    ) -> Self {
+        Self {
+            bridge,
+            devices,
+            sensors,
+            renamer,
+            whitelist,
+        }
     }
 
-    fn handle_description(&self) -> Vec<u8> {
+    fn handle_description(&self, interface: IpAddr) -> Vec<u8> {
+        let bridge = self.bridge.on_interface(interface);
         tracing::info!(
-            "HUE bridge description requested, advertised as http://{}:{}/description.xml",
-            self.bridge.ip_address,
-            self.bridge.port
+            "HUE bridge description requested, advertised as http://{}/description.xml",
+            SocketAddr::new(bridge.ip_address, bridge.port)
         );
-        make_hue_description(&self.bridge).into_bytes()
+        make_hue_description(&bridge).into_bytes()
     }
 
-    fn handle_api(&self, method: &Method, path: &str, request: &mut Request<'_, '_>) -> Vec<u8> {
-        tracing::info!("HUE bridge API request: {} {}", method, path);
-        match (method, path) {
-            (&Method::Post, "/api") | (&Method::Post, "/api/") => {
-                tracing::info!("HUE bridge link request accepted with username `rustmo`");
-                br#"[{"success":{"username":"rustmo"}}]"#.to_vec()
+    fn handle_api(&self, path: &str, request: &HttpRequest) -> Vec<u8> {
+        tracing::info!("HUE bridge API request: {} {}", request.method, path);
+        let bridge = self.bridge.on_interface(request.local_addr.ip());
+        if request.method == "POST" && matches!(path, "/api" | "/api/") {
+            return self.handle_pairing(request);
+        }
+
+        let (username, path) = split_hue_username(path);
+        if !self.whitelist.lock().is_authorized(username) {
+            if request.method == "GET" && path == "/config" {
+                return serde_json::to_vec(&make_hue_public_config_json(&bridge)).unwrap();
             }
-            (&Method::Get, "/api") | (&Method::Get, "/api/") => self.make_bridge_response(),
-            (&Method::Get, path) if is_hue_username_path(path) => self.make_bridge_response(),
-            (&Method::Get, path) if path.ends_with("/config") => {
-                make_hue_config_response(&self.bridge)
+            tracing::warn!("HUE bridge request from unauthorized user `{username}`");
+            return hue_error(1, path_or_root(path), "unauthorized user");
+        }
+
+        match (request.method.as_str(), path) {
+            ("GET", "") | ("GET", "/") => self.make_bridge_response(&bridge),
+            ("GET", "/config") => serde_json::to_vec(&self.hue_config_json(&bridge)).unwrap(),
+            ("GET", path) if path.ends_with("/groups") => self.make_groups_response(),
+            ("GET", path) if path.contains("/groups/") => self.make_group_response(path),
+            ("PUT", path) if path.contains("/groups/") && path.ends_with("/action") => {
+                self.handle_group_action(path, request)
             }
-            (&Method::Get, path) if path.ends_with("/groups") => br#"{}"#.to_vec(),
-            (&Method::Get, path) if path.ends_with("/lights") => self.make_lights_response(),
-            (&Method::Get, path) if path.contains("/lights/") && !path.ends_with("/state") => {
+            ("GET", path) if path.ends_with("/lights") => self.make_lights_response(),
+            ("GET", path) if path.contains("/lights/") && !path.ends_with("/state") => {
                 self.make_light_response(path)
             }
-            (&Method::Put, path) if path.ends_with("/state") => {
+            ("GET", path) if path.ends_with("/sensors") => self.make_sensors_response(),
+            ("GET", path) if path.contains("/sensors/") => self.make_sensor_response(path),
+            ("PUT", path) if path.contains("/lights/") && path.ends_with("/state") => {
                 self.handle_set_light_state(path, request)
             }
+            ("PUT", path) if path.contains("/lights/") => self.handle_rename_light(path, request),
             _ => br#"[]"#.to_vec(),
         }
     }
 
-    fn make_bridge_response(&self) -> Vec<u8> {
+    fn make_bridge_response(&self, bridge: &RustmoDeviceInfo) -> Vec<u8> {
         let light_count = self
             .devices
             .read()
             .iter()
-            .filter(|device| device.supports_percent())
+            .filter(|device| device.is_hue_light())
             .count();
         tracing::info!("HUE bridge full state requested, exposing {light_count} dimmable lights");
         serde_json::to_vec(&serde_json::json!({
             "lights": self.hue_lights_json(),
-            "config": make_hue_config_json(&self.bridge)
+            "groups": self.hue_groups_json(),
+            "sensors": self.hue_sensors_json(),
+            "config": self.hue_config_json(bridge)
         }))
         .unwrap()
     }
 
+    fn hue_config_json(&self, bridge: &RustmoDeviceInfo) -> serde_json::Value {
+        let whitelist = self.whitelist.lock();
+        let mut config = make_hue_config_json(bridge);
+        config["linkbutton"] = serde_json::json!(whitelist.is_link_button_pressed());
+        config["whitelist"] = whitelist.to_json();
+        config
+    }
+
+    ///
+    /// Hand out a new username to the app asking, as long as the link button has been pressed
+    ///
+    fn handle_pairing(&self, request: &HttpRequest) -> Vec<u8> {
+        let body = serde_json::from_slice::<serde_json::Value>(&request.body).unwrap_or_default();
+        let Some(devicetype) = body.get("devicetype").and_then(|value| value.as_str()) else {
+            return hue_error(5, "/", "invalid/missing parameters in body");
+        };
+
+        match self.whitelist.lock().pair(devicetype) {
+            Ok(username) => {
+                let mut success = serde_json::json!({ "username": username });
+                if body
+                    .get("generateclientkey")
+                    .and_then(|value| value.as_bool())
+                    == Some(true)
+                {
+                    success["clientkey"] =
+                        serde_json::json!(Uuid::new_v4().simple().to_string().to_uppercase());
+                }
+                serde_json::to_vec(&serde_json::json!([{ "success": success }])).unwrap()
+            }
+            Err(PairingError::LinkButtonNotPressed) => {
+                tracing::info!(
+                    "HUE bridge refused to pair with `{devicetype}`: link button not pressed"
+                );
+                hue_error(101, "", "link button not pressed")
+            }
+            Err(PairingError::Io(e)) => {
+                tracing::error!("unable to save Hue whitelist: {e}");
+                hue_error(901, "", "internal error, unable to save whitelist")
+            }
+        }
+    }
+
     fn make_lights_response(&self) -> Vec<u8> {
         let light_count = self
             .devices
             .read()
             .iter()
-            .filter(|device| device.supports_percent())
+            .filter(|device| device.is_hue_light())
             .count();
         tracing::info!("HUE bridge lights requested, exposing {light_count} dimmable lights");
         serde_json::to_vec(&self.hue_lights_json()).unwrap()
@@ -260,28 +505,76 @@ impl HueBridgeHttpServerHandler {
         serde_json::to_vec(&hue_light_json(device)).unwrap()
     }
 
-    fn handle_set_light_state(&self, path: &str, request: &mut Request<'_, '_>) -> Vec<u8> {
+    fn handle_set_light_state(&self, path: &str, request: &HttpRequest) -> Vec<u8> {
         let Some(id) = hue_light_id_from_path(path) else {
             return br#"[]"#.to_vec();
         };
 
-        let mut content = String::new();
-        request.read_to_string(&mut content).unwrap();
-        let command = serde_json::from_str::<serde_json::Value>(&content).unwrap_or_default();
-        let devices = self.devices.read();
-        let Some((_, device)) = percent_devices(&devices)
-            .into_iter()
-            .find(|(device_id, _)| *device_id == id)
-        else {
+        let command =
+            serde_json::from_slice::<serde_json::Value>(&request.body).unwrap_or_default();
+        // the device may be slow to respond, so don't hold the device list while it does
+        let light = {
+            let devices = self.devices.read();
+            percent_devices(&devices)
+                .into_iter()
+                .find(|(device_id, _)| *device_id == id)
+                .map(|(_, device)| (device.info.clone(), device.device.clone()))
+        };
+        let Some((info, device)) = light else {
             tracing::warn!("HUE bridge state request for unknown light id {id}");
             return br#"[]"#.to_vec();
         };
 
-        tracing::info!(
-            "HUE bridge state update for light {id}: {}",
-            device.info.name
-        );
-        handle_hue_set_state(device, id, command)
+        tracing::info!("HUE bridge state update for light {id}: {}", info.name);
+        handle_hue_set_state(
+            &info,
+            device.as_ref(),
+            &format!("/lights/{id}/state"),
+            command,
+        )
+    }
+
+    ///
+    /// Rename a light, which is how Hue apps change its `name` attribute
+    ///
+    fn handle_rename_light(&self, path: &str, request: &HttpRequest) -> Vec<u8> {
+        let Some(id) = hue_light_id_from_path(path) else {
+            return br#"[]"#.to_vec();
+        };
+        let address = format!("/lights/{id}");
+
+        let command =
+            serde_json::from_slice::<serde_json::Value>(&request.body).unwrap_or_default();
+        let Some(new_name) = command.get("name").and_then(|name| name.as_str()) else {
+            return hue_error(5, &address, "invalid/missing parameters in body");
+        };
+        let name = {
+            let devices = self.devices.read();
+            let Some((_, device)) = percent_devices(&devices)
+                .into_iter()
+                .find(|(device_id, _)| *device_id == id)
+            else {
+                tracing::warn!("HUE bridge rename request for unknown light id {id}");
+                return hue_error(3, &address, &format!("resource, {address}, not available"));
+            };
+            device.info.name.get()
+        };
+
+        tracing::info!("HUE bridge rename light {id}: `{name}` to `{new_name}`");
+        match self.renamer.rename(&name, new_name) {
+            Ok(()) => serde_json::to_vec(&serde_json::json!([
+                { "success": { format!("{address}/name"): new_name.trim() } }
+            ]))
+            .unwrap(),
+            Err(e) => {
+                tracing::warn!("unable to rename `{name}`: {e}");
+                hue_error(
+                    7,
+                    &format!("{address}/name"),
+                    &format!("invalid value, {new_name}, for parameter, name"),
+                )
+            }
+        }
     }
 
     fn hue_lights_json(&self) -> serde_json::Map<String, serde_json::Value> {
@@ -291,52 +584,340 @@ impl HueBridgeHttpServerHandler {
             .map(|(id, device)| (id.to_string(), hue_light_json(device)))
             .collect()
     }
+
+    fn make_groups_response(&self) -> Vec<u8> {
+        let groups = self.hue_groups_json();
+        tracing::info!(
+            "HUE bridge groups requested, exposing {} groups",
+            groups.len()
+        );
+        serde_json::to_vec(&groups).unwrap()
+    }
+
+    fn make_group_response(&self, path: &str) -> Vec<u8> {
+        let Some(id) = hue_group_id_from_path(path) else {
+            return br#"[]"#.to_vec();
+        };
+        let Some((_, group)) = self.hue_group_snapshots(Some(id)).pop() else {
+            tracing::warn!("HUE bridge group request for unknown group id {id}");
+            return hue_error(3, &format!("/groups/{id}"), "resource not available");
+        };
+
+        tracing::info!("HUE bridge group {id} requested: {}", group.name);
+        serde_json::to_vec(&group.hue_json()).unwrap()
+    }
+
+    fn make_sensors_response(&self) -> Vec<u8> {
+        let sensors = self.hue_sensors_json();
+        tracing::info!(
+            "HUE bridge sensors requested, exposing {} sensors",
+            sensors.len()
+        );
+        serde_json::to_vec(&sensors).unwrap()
+    }
+
+    fn make_sensor_response(&self, path: &str) -> Vec<u8> {
+        let Some(id) = hue_sensor_id_from_path(path) else {
+            return br#"[]"#.to_vec();
+        };
+        let sensors = self.sensors.read();
+        let Some(sensor) = sensors.iter().find(|sensor| sensor.hue_id == id) else {
+            tracing::warn!("HUE bridge sensor request for unknown sensor id {id}");
+            return hue_error(3, &format!("/sensors/{id}"), "resource not available");
+        };
+
+        tracing::info!("HUE bridge sensor {id} requested: {}", sensor.name);
+        serde_json::to_vec(&sensor.hue_json()).unwrap()
+    }
+
+    fn hue_sensors_json(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.sensors
+            .read()
+            .iter()
+            .map(|sensor| (sensor.hue_id.to_string(), sensor.hue_json()))
+            .collect()
+    }
+
+    fn handle_group_action(&self, path: &str, request: &HttpRequest) -> Vec<u8> {
+        let Some(id) = hue_group_id_from_path(path) else {
+            return br#"[]"#.to_vec();
+        };
+
+        let command =
+            serde_json::from_slice::<serde_json::Value>(&request.body).unwrap_or_default();
+        // the fan out can take as long as the slowest member, so don't hold the device list
+        let group = {
+            let devices = self.devices.read();
+            find_group(&devices, id).map(|(_, device)| (device.info.clone(), device.device.clone()))
+        };
+        let Some((info, device)) = group else {
+            tracing::warn!("HUE bridge action for unknown group id {id}");
+            return hue_error(3, &format!("/groups/{id}/action"), "resource not available");
+        };
+
+        // the group is a `CompositeDevice`, which fans the change out to each of its members
+        tracing::info!("HUE bridge action for group {id}: {}", info.name);
+        handle_hue_set_state(
+            &info,
+            device.as_ref(),
+            &format!("/groups/{id}/action"),
+            command,
+        )
+    }
+
+    fn hue_groups_json(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.hue_group_snapshots(None)
+            .into_iter()
+            .map(|(id, group)| (id.to_string(), group.hue_json()))
+            .collect()
+    }
+
+    ///
+    /// Snapshot every group (or just the group with the given id), in id order.  The device list
+    /// is only locked while the snapshots are taken, not while the groups' state is checked
+    ///
+    fn hue_group_snapshots(&self, id: Option<usize>) -> Vec<(usize, HueGroupSnapshot)> {
+        let devices = self.devices.read();
+        let mut groups = devices
+            .iter()
+            .filter_map(|device| device.hue_group.as_ref().map(|group| (group, device)))
+            .filter(|(group, _)| id.is_none_or(|id| group.id == id))
+            .map(|(group, device)| (group.id, HueGroupSnapshot::new(&devices, group, device)))
+            .collect::<Vec<_>>();
+        groups.sort_by_key(|(id, _)| *id);
+        groups
+    }
 }
 
 enum Body {
     Json(Vec<u8>),
     Xml(Vec<u8>),
+    SoapFault(Vec<u8>),
 }
 
 impl Body {
-    fn as_slice(&self) -> &[u8] {
+    fn content_type(&self) -> &'static str {
         match self {
-            Body::Json(body) | Body::Xml(body) => body.as_slice(),
+            Body::Json(_) => "application/json",
+            Body::Xml(_) | Body::SoapFault(_) => "text/xml",
         }
     }
 
-    fn content_type(&self) -> &'static str {
+    fn status(&self) -> u16 {
         match self {
-            Body::Json(_) => "application/json",
-            Body::Xml(_) => "text/xml",
+            Body::Json(_) | Body::Xml(_) => 200,
+            // UPnP requires every SOAP fault be sent as a 500
+            Body::SoapFault(_) => 500,
+        }
+    }
+}
+
+impl From<Body> for HttpResponse {
+    fn from(body: Body) -> Self {
+        let content_type = body.content_type();
+        let status = body.status();
+        let body = match body {
+            Body::Json(body) | Body::Xml(body) | Body::SoapFault(body) => body,
+        };
+        HttpResponse::new(status).with_body(content_type, body)
+    }
+}
+
+///
+/// A UPnP control error, sent back to the control point as a SOAP `<s:Fault>`
+///
+#[derive(Debug)]
+pub(crate) struct UpnpFault {
+    pub(crate) code: u16,
+    pub(crate) description: String,
+}
+
+impl UpnpFault {
+    fn invalid_action<S: Into<String>>(description: S) -> Self {
+        UpnpFault {
+            code: 401,
+            description: description.into(),
+        }
+    }
+
+    fn invalid_args<S: Into<String>>(description: S) -> Self {
+        UpnpFault {
+            code: 402,
+            description: description.into(),
+        }
+    }
+
+    fn action_failed<S: Into<String>>(description: S) -> Self {
+        UpnpFault {
+            code: 501,
+            description: description.into(),
         }
     }
+
+    fn to_xml(&self) -> Vec<u8> {
+        format!(
+            "<s:Envelope xmlns:s='http://schemas.xmlsoap.org/soap/envelope/'
+                        s:encodingStyle='http://schemas.xmlsoap.org/soap/encoding/'>
+                <s:Body>
+                    <s:Fault>
+                        <faultcode>s:Client</faultcode>
+                        <faultstring>UPnPError</faultstring>
+                        <detail>
+                            <UPnPError xmlns='urn:schemas-upnp-org:control-1-0'>
+                                <errorCode>{code}</errorCode>
+                                <errorDescription>{description}</errorDescription>
+                            </UPnPError>
+                        </detail>
+                    </s:Fault>
+                </s:Body>
+            </s:Envelope>",
+            code = self.code,
+            description = xml_escape(&self.description)
+        )
+        .into_bytes()
+    }
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+///
+/// Pull the action name out of a `SOAPACTION` header such as
+/// `"urn:Belkin:service:basicevent:1#SetBinaryState"`
+///
+fn soap_action(header: &str) -> Option<&str> {
+    let action = header.trim().trim_matches('"');
+    let (_, action) = action.rsplit_once('#')?;
+    if action.is_empty() {
+        None
+    } else {
+        Some(action)
+    }
 }
 
 impl DeviceHttpServerHandler {
     pub(crate) fn new(device: RustmoDevice) -> Self {
-        DeviceHttpServerHandler { device }
+        let device = Arc::new(device);
+        let events = EventPublisher::new(Arc::downgrade(&device), device.info.changes.clone());
+        DeviceHttpServerHandler {
+            device,
+            events,
+            renamer: None,
+        }
     }
 
-    fn handle_basicevent(&self, request: &mut Request<'_, '_>) -> Vec<u8> {
-        let re = Regex::new("^\".*[#](.*)\"$").unwrap();
-        let action =
-            String::from_utf8(request.headers.get_raw("SOAPACTION").unwrap()[0].clone()).unwrap();
-        let action = re
-            .captures(action.as_str())
-            .unwrap()
-            .get(1)
-            .unwrap()
-            .as_str();
+    pub(crate) fn with_renamer(mut self, renamer: Option<DeviceRenamer>) -> Self {
+        self.renamer = renamer;
+        self
+    }
+
+    ///
+    /// Handle GENA `SUBSCRIBE` (including renewals) and `UNSUBSCRIBE` requests for the
+    /// `basicevent1` service
+    ///
+    fn handle_event_subscription(&self, request: &HttpRequest) -> HttpResponse {
+        let header = |name: &str| request.header(name);
+        let timeout = header("TIMEOUT").and_then(parse_timeout);
+
+        match (request.method.as_str(), header("SID")) {
+            ("SUBSCRIBE", Some(sid)) => {
+                if header("CALLBACK").is_some() || header("NT").is_some() {
+                    HttpResponse::new(400)
+                } else if let Some(timeout) = self.events.renew(sid, timeout) {
+                    tracing::info!("GENA renew: {} sid={}", self.device.info.name, sid);
+                    subscription_response(sid, timeout)
+                } else {
+                    HttpResponse::new(412)
+                }
+            }
+            ("SUBSCRIBE", None) => {
+                let callbacks = header("CALLBACK").map(parse_callbacks).unwrap_or_default();
+                if callbacks.is_empty() || header("NT") != Some("upnp:event") {
+                    HttpResponse::new(412)
+                } else {
+                    let (sid, timeout) = self.events.subscribe(callbacks, timeout);
+                    tracing::info!(
+                        "GENA subscribe: {} by {} sid={}",
+                        self.device.info.name,
+                        request.remote_addr.ip(),
+                        sid
+                    );
+                    subscription_response(&sid, timeout).after_sent(self.events.accepted(sid))
+                }
+            }
+            ("UNSUBSCRIBE", Some(sid)) => {
+                if header("CALLBACK").is_some() || header("NT").is_some() {
+                    HttpResponse::new(400)
+                } else if self.events.unsubscribe(sid) {
+                    tracing::info!("GENA unsubscribe: {} sid={}", self.device.info.name, sid);
+                    HttpResponse::new(200)
+                } else {
+                    HttpResponse::new(412)
+                }
+            }
+            ("UNSUBSCRIBE", None) => HttpResponse::new(412),
+            (method, _) => {
+                tracing::warn!("Unsupported event subscription method: {}", method);
+                HttpResponse::new(405)
+            }
+        }
+    }
 
-        let mut content = String::new();
-        request.read_to_string(&mut content).unwrap();
-        content = content.replace("\"s:", "\" s:");
+    fn handle_basicevent(&self, request: &HttpRequest) -> Result<Vec<u8>, UpnpFault> {
+        let header = request
+            .header("SOAPACTION")
+            .ok_or_else(|| UpnpFault::invalid_action("missing SOAPACTION header"))?;
+        let action = soap_action(header).ok_or_else(|| {
+            UpnpFault::invalid_action(format!("malformed SOAPACTION header: {header}"))
+        })?;
+
+        let content = request.body_string().replace("\"s:", "\" s:");
+
+        let envelope: UpnpEnvelope = from_reader(content.as_bytes())
+            .map_err(|e| UpnpFault::invalid_args(format!("malformed SOAP envelope: {e}")))?;
+        match action {
+            "GetFriendlyName" => {
+                tracing::info!(
+                    "UPNP get friendly name: {} by {}",
+                    self.device.info.name,
+                    request.remote_addr.ip()
+                );
+                return Ok(DeviceHttpServerHandler::make_friendly_name_response(
+                    "Get",
+                    &self.device.info.name.get(),
+                ));
+            }
+            "ChangeFriendlyName" => {
+                let new_name = envelope
+                    .body
+                    .change_friendly_name
+                    .and_then(|change| change.friendly_name)
+                    .ok_or_else(|| {
+                        UpnpFault::invalid_args("No FriendlyName data for ChangeFriendlyName")
+                    })?;
+                tracing::info!(
+                    "UPNP change friendly name: {} to {} by {}",
+                    self.device.info.name,
+                    new_name,
+                    request.remote_addr.ip()
+                );
+                self.rename(&new_name)?;
+                return Ok(DeviceHttpServerHandler::make_friendly_name_response(
+                    "Change",
+                    &self.device.info.name.get(),
+                ));
+            }
+            _ => {}
+        }
 
-        let envelope: UpnpEnvelope =
-            from_reader(content.as_bytes()).unwrap_or_else(|e| panic!("{}:\n{}", e, content));
         let get_or_set;
-        let mut percent = None;
+        let percent;
         let on_off = match action {
             "GetBinaryState" => {
                 tracing::info!(
@@ -378,28 +959,70 @@ impl DeviceHttpServerHandler {
                             self.device.turn_off()
                         }
                     }
-                    None => Err(VirtualDeviceError::new(
-                        "No BinaryState data for SetBinaryState",
-                    )),
+                    None => {
+                        return Err(UpnpFault::invalid_args(
+                            "No BinaryState data for SetBinaryState",
+                        ))
+                    }
                 }
             }
-            capture => {
-                tracing::error!("Unknown capture value: /{}/ from /{}/", capture, action);
-                return Vec::new();
+            action => {
+                return Err(UpnpFault::invalid_action(format!(
+                    "unsupported action: {action}"
+                )))
             }
         };
 
         match on_off {
             Ok(state) => {
-                DeviceHttpServerHandler::make_basicevent_response(state, get_or_set, percent)
+                if get_or_set == "Set" {
+                    self.events.state_changed();
+                }
+                Ok(DeviceHttpServerHandler::make_basicevent_response(
+                    state, get_or_set, percent,
+                ))
             }
             Err(e) => {
                 tracing::error!("Problem with {}: {}", self.device.info.name, e.0);
-                vec![]
+                Err(UpnpFault::action_failed(e.0))
             }
         }
     }
 
+    fn rename(&self, new_name: &str) -> Result<(), UpnpFault> {
+        match &self.renamer {
+            Some(renamer) => renamer
+                .rename(&self.device.info.name.get(), new_name)
+                .map_err(|e| match e {
+                    RustmoError::InvalidDeviceName(_) => UpnpFault::invalid_args(e.to_string()),
+                    e => UpnpFault::action_failed(e.to_string()),
+                }),
+            None if new_name.trim().is_empty() => {
+                Err(UpnpFault::invalid_args("FriendlyName can't be empty"))
+            }
+            None => {
+                self.device.info.name.set(new_name.trim().to_string());
+                Ok(())
+            }
+        }
+    }
+
+    fn make_friendly_name_response(get_or_change: &str, name: &str) -> Vec<u8> {
+        format!(
+            "<s:Envelope xmlns:s='http://schemas.xmlsoap.org/soap/envelope/'
+                        s:encodingStyle='http://schemas.xmlsoap.org/soap/encoding/'>
+                <s:Body>
+                    <u:{action}FriendlyNameResponse xmlns:u='urn:Belkin:service:basicevent:1'>
+                        <FriendlyName>{name}</FriendlyName>
+                    </u:{action}FriendlyNameResponse>
+                </s:Body>
+            </s:Envelope>",
+            action = get_or_change,
+            name = xml_escape(name)
+        )
+        .into_bytes()
+    }
+
     fn make_basicevent_response(
         state: VirtualDeviceState,
         get_or_set: &str,
@@ -431,15 +1054,20 @@ impl DeviceHttpServerHandler {
 
     fn handle_setup(&self) -> Vec<u8> {
         tracing::info!("UPNP set: {}", self.device.info.name);
+        let (device_type, model_name, model_description) = if self.device.is_wemo_dimmer() {
+            ("dimmer", "Dimmer", "Belkin Dimmer 1.0")
+        } else {
+            ("controllee", "Socket", "Belkin Plugin Socket 1.0")
+        };
         format!(
             "<root>
                 <device>
-                    <deviceType>urn:Belkin:device:controllee:1</deviceType>
+                    <deviceType>urn:Belkin:device:{device_type}:1</deviceType>
                     <friendlyName>{device_name}</friendlyName>
                     <manufacturer>Belkin International Inc.</manufacturer>
-                    <modelName>Socket</modelName>
+                    <modelName>{model_name}</modelName>
                     <modelNumber>3.1415</modelNumber>
-                    <modelDescription>Belkin Plugin Socket 1.0</modelDescription>
+                    <modelDescription>{model_description}</modelDescription>
                     <UDN>uuid:{uuid}</UDN>
                     <serialNumber>221517K0101769</serialNumber>
                     <binaryState>0</binaryState>
@@ -447,87 +1075,21 @@ impl DeviceHttpServerHandler {
                         <service>
                             <serviceType>urn:Belkin:service:basicevent:1</serviceType>
                             <serviceId>urn:Belkin:serviceId:basicevent1</serviceId>
-                            <controlURL>/upnp/control/basicevent1</controlURL>
-                            <eventSubURL>/upnp/event/basicevent1</eventSubURL>
-                            <SCPDURL>/eventservice.xml</SCPDURL>
+                            <controlURL>{base_path}/upnp/control/basicevent1</controlURL>
+                            <eventSubURL>{base_path}/upnp/event/basicevent1</eventSubURL>
+                            <SCPDURL>{base_path}/eventservice.xml</SCPDURL>
                         </service>
                     </serviceList>
                 </device>
             </root>",
-            device_name = self.device.info.name,
-            uuid = self.device.info.uuid
+            device_name = xml_escape(&self.device.info.name.get()),
+            uuid = self.device.info.uuid,
+            base_path = self.device.info.base_path
         )
         .as_bytes()
         .to_vec()
     }
 
-    fn handle_hue_description(&self) -> Vec<u8> {
-        tracing::info!("HUE description: {}", self.device.info.name);
-        make_hue_description(&self.device.info).into_bytes()
-    }
-
-    fn handle_hue_api(
-        &self,
-        method: &Method,
-        path: &str,
-        request: &mut Request<'_, '_>,
-    ) -> Vec<u8> {
-        tracing::info!(
-            "HUE request: {} {} for {}",
-            method,
-            path,
-            self.device.info.name
-        );
-
-        match (method, path) {
-            (&Method::Post, "/api") | (&Method::Post, "/api/") => {
-                br#"[{"success":{"username":"rustmo"}}]"#.to_vec()
-            }
-            (&Method::Get, "/api") | (&Method::Get, "/api/") => self.make_hue_bridge_response(),
-            (&Method::Get, path) if is_hue_username_path(path) => self.make_hue_bridge_response(),
-            (&Method::Get, path) if path.ends_with("/config") => self.make_hue_config_response(),
-            (&Method::Get, path) if path.ends_with("/groups") => br#"{}"#.to_vec(),
-            (&Method::Get, path) if path.ends_with("/lights") => self.make_hue_lights_response(),
-            (&Method::Get, path) if path.ends_with("/lights/1") => self.make_hue_light_response(),
-            (&Method::Put, path) if path.ends_with("/lights/1/state") => {
-                self.handle_hue_set_state(request)
-            }
-            _ => br#"[]"#.to_vec(),
-        }
-    }
-
-    fn handle_hue_set_state(&self, request: &mut Request<'_, '_>) -> Vec<u8> {
-        let mut content = String::new();
-        request.read_to_string(&mut content).unwrap();
-        let command = serde_json::from_str::<serde_json::Value>(&content).unwrap_or_default();
-        handle_hue_set_state(&self.device, 1, command)
-    }
-
-    fn make_hue_bridge_response(&self) -> Vec<u8> {
-        serde_json::to_vec(&serde_json::json!({
-            "lights": {
-                "1": hue_light_json(&self.device)
-            },
-            "config": make_hue_config_json(&self.device.info)
-        }))
-        .unwrap()
-    }
-
-    fn make_hue_lights_response(&self) -> Vec<u8> {
-        serde_json::to_vec(&serde_json::json!({
-            "1": hue_light_json(&self.device)
-        }))
-        .unwrap()
-    }
-
-    fn make_hue_light_response(&self) -> Vec<u8> {
-        serde_json::to_vec(&hue_light_json(&self.device)).unwrap()
-    }
-
-    fn make_hue_config_response(&self) -> Vec<u8> {
-        make_hue_config_response(&self.device.info)
-    }
-
     fn handle_eventservice(&self) -> Vec<u8> {
         tracing::info!("UPNP eventservice: {}", self.device.info.name);
         let dimmer_set_argument = if self.device.supports_percent() {
@@ -580,6 +1142,28 @@ impl DeviceHttpServerHandler {
                         </argument>
                     </argumentList>
                 </action>
+                <action>
+                    <name>ChangeFriendlyName</name>
+                    <argumentList>
+                        <argument>
+                            <retval/>
+                            <name>FriendlyName</name>
+                            <relatedStateVariable>FriendlyName</relatedStateVariable>
+                            <direction>in</direction>
+                        </argument>
+                    </argumentList>
+                </action>
+                <action>
+                    <name>GetFriendlyName</name>
+                    <argumentList>
+                        <argument>
+                            <retval/>
+                            <name>FriendlyName</name>
+                            <relatedStateVariable>FriendlyName</relatedStateVariable>
+                            <direction>out</direction>
+                        </argument>
+                    </argumentList>
+                </action>
             </actionList>
             <serviceStateTable>
                 <stateVariable sendEvents='yes'>
@@ -592,6 +1176,10 @@ impl DeviceHttpServerHandler {
                     <dataType>string</dataType>
                     <defaultValue>0</defaultValue>
                 </stateVariable>
+                <stateVariable sendEvents='no'>
+                    <name>FriendlyName</name>
+                    <dataType>string</dataType>
+                </stateVariable>
                 {dimmer_state_variable}
             </serviceStateTable>
         </scpd>",
@@ -632,6 +1220,13 @@ impl DeviceHttpServerHandler {
     }
 }
 
+fn subscription_response(sid: &str, timeout: std::time::Duration) -> HttpResponse {
+    HttpResponse::new(200)
+        .with_header("SID", sid)
+        .with_header("TIMEOUT", format!("Second-{}", timeout.as_secs()))
+        .with_header("SERVER", "Theater, UPnP/1.0, Unspecified")
+}
+
 fn make_hue_description(info: &RustmoDeviceInfo) -> String {
     format!(
         "<?xml version=\"1.0\" encoding=\"UTF-8\" ?>
@@ -640,10 +1235,10 @@ fn make_hue_description(info: &RustmoDeviceInfo) -> String {
                 <major>1</major>
                 <minor>0</minor>
             </specVersion>
-            <URLBase>http://{ip}:{port}/</URLBase>
+            <URLBase>http://{address}{base_path}/</URLBase>
             <device>
                 <deviceType>urn:schemas-upnp-org:device:Basic:1</deviceType>
-                <friendlyName>Philips hue ({ip})</friendlyName>
+                <friendlyName>{name} ({ip})</friendlyName>
                 <manufacturer>Royal Philips Electronics</manufacturer>
                 <manufacturerURL>http://www.philips.com</manufacturerURL>
                 <modelDescription>Philips hue Personal Wireless Lighting</modelDescription>
@@ -664,20 +1259,37 @@ fn make_hue_description(info: &RustmoDeviceInfo) -> String {
                 <presentationURL>index.html</presentationURL>
             </device>
         </root>",
+        address = SocketAddr::new(info.ip_address, info.port),
+        name = info
+            .hue_bridge
+            .as_ref()
+            .map_or("Philips hue".to_string(), |_| info.name.get()),
         ip = info.ip_address,
-        port = info.port,
+        base_path = info.base_path,
         serial = hue_serial(info),
         uuid = info.uuid
     )
 }
 
-fn make_hue_config_response(info: &RustmoDeviceInfo) -> Vec<u8> {
-    serde_json::to_vec(&make_hue_config_json(info)).unwrap()
+///
+/// The subset of the bridge's config that anyone may see, even without pairing
+///
+fn make_hue_public_config_json(info: &RustmoDeviceInfo) -> serde_json::Value {
+    let config = make_hue_config_json(info);
+    serde_json::json!({
+        "name": config["name"],
+        "bridgeid": config["bridgeid"],
+        "mac": config["mac"],
+        "swversion": config["swversion"],
+        "apiversion": config["apiversion"],
+        "factorynew": false,
+        "replacesbridgeid": null
+    })
 }
 
 fn make_hue_config_json(info: &RustmoDeviceInfo) -> serde_json::Value {
     serde_json::json!({
-        "name": "Philips hue",
+        "name": info.name,
         "bridgeid": hue_serial(info),
         "mac": hue_mac(info),
         "dhcp": true,
@@ -691,39 +1303,103 @@ fn make_hue_config_json(info: &RustmoDeviceInfo) -> serde_json::Value {
     })
 }
 
+/// the coolest and warmest color temperatures, in mireds, that Hue lights support
+pub(crate) const HUE_MIN_MIREDS: u16 = 153;
+pub(crate) const HUE_MAX_MIREDS: u16 = 500;
+
 fn hue_light_json(device: &RustmoDevice) -> serde_json::Value {
     let percent = device.check_percent().unwrap_or(None).unwrap_or(0);
     let on = device.check_is_on().unwrap_or(VirtualDeviceState::Off) == VirtualDeviceState::On;
-    serde_json::json!({
+    let mut json = serde_json::json!({
         "state": {
             "on": on,
             "bri": percent_to_hue_brightness(percent),
             "reachable": true,
             "mode": "homeautomation"
         },
-        "type": "Dimmable light",
+        "type": hue_light_type(device),
         "name": device.info.name,
         "modelid": "HASS123",
         "manufacturername": "Home Assistant",
         "uniqueid": hue_light_unique_id(&device.info),
         "swversion": "123"
-    })
+    });
+
+    let mut colormode = None;
+    if device.supports_color_temperature() {
+        let mireds = device.check_color_temperature().unwrap_or(None);
+        json["state"]["ct"] = serde_json::json!(mireds
+            .unwrap_or(HUE_MIN_MIREDS)
+            .clamp(HUE_MIN_MIREDS, HUE_MAX_MIREDS));
+        json["capabilities"] = serde_json::json!({
+            "control": {
+                "ct": {"min": HUE_MIN_MIREDS, "max": HUE_MAX_MIREDS}
+            }
+        });
+        colormode = mireds.map(|_| "ct");
+    }
+    if device.supports_color() {
+        let color = device.check_color().unwrap_or(None);
+        let current = color.unwrap_or(Color::HueSaturation {
+            hue: 0,
+            saturation: 0,
+        });
+        let (hue, saturation) = current.to_hue_saturation();
+        let (x, y) = current.to_xy();
+        json["state"]["hue"] = serde_json::json!(hue);
+        json["state"]["sat"] = serde_json::json!(saturation);
+        json["state"]["xy"] = serde_json::json!([x, y]);
+        colormode = match color {
+            Some(Color::HueSaturation { .. }) => Some("hs"),
+            Some(Color::Xy { .. }) => Some("xy"),
+            None => colormode,
+        };
+    }
+    if device.supports_color_temperature() || device.supports_color() {
+        json["state"]["colormode"] =
+            serde_json::json!(colormode.unwrap_or(if device.supports_color_temperature() {
+                "ct"
+            } else {
+                "hs"
+            }));
+    }
+
+    json
 }
 
-fn handle_hue_set_state(device: &RustmoDevice, id: usize, command: serde_json::Value) -> Vec<u8> {
+fn hue_light_type(device: &RustmoDevice) -> &'static str {
+    match (device.supports_color(), device.supports_color_temperature()) {
+        (true, true) => "Extended color light",
+        (true, false) => "Color light",
+        (false, true) => "Color temperature light",
+        (false, false) => "Dimmable light",
+    }
+}
+
+///
+/// Apply a Hue state change to `device`, answering with the state `resource` (like
+/// `/lights/1/state` or `/groups/1/action`) that each change was made to.  Callers shouldn't
+/// hold the device list while they do, as the change may take as long as the device does
+///
+fn handle_hue_set_state(
+    info: &RustmoDeviceInfo,
+    device: &dyn VirtualDevice,
+    resource: &str,
+    command: serde_json::Value,
+) -> Vec<u8> {
     let mut responses = Vec::new();
 
     if let Some(on) = command.get("on").and_then(|value| value.as_bool()) {
-        tracing::info!("HUE set `{}` on={}", device.info.name, on);
+        tracing::info!("HUE set `{}` on={}", info.name, on);
         let result = if on {
             device.turn_on()
         } else {
             device.turn_off()
         };
         if let Err(error) = result {
-            tracing::error!("Problem with {}: {}", device.info.name, error.0);
+            tracing::error!("Problem with {}: {}", info.name, error.0);
         }
-        responses.push(serde_json::json!({"success": {format!("/lights/{id}/state/on"): on}}));
+        responses.push(serde_json::json!({"success": {format!("{resource}/on"): on}}));
     }
 
     if let Some(bri) = command.get("bri").and_then(|value| value.as_u64()) {
@@ -731,45 +1407,251 @@ fn handle_hue_set_state(device: &RustmoDevice, id: usize, command: serde_json::V
         let percent = hue_brightness_to_percent(brightness);
         tracing::info!(
             "HUE set `{}` bri={} percent={}",
-            device.info.name,
+            info.name,
             brightness,
             percent
         );
         if let Err(error) = device.set_percent(percent) {
-            tracing::error!("Problem with {}: {}", device.info.name, error.0);
+            tracing::error!("Problem with {}: {}", info.name, error.0);
+        }
+        responses.push(serde_json::json!({"success": {format!("{resource}/bri"): brightness}}));
+    }
+
+    if let Some(ct) = command.get("ct").and_then(|value| value.as_u64()) {
+        if device.supports_color_temperature() {
+            let mireds = ct.clamp(HUE_MIN_MIREDS as u64, HUE_MAX_MIREDS as u64) as u16;
+            tracing::info!("HUE set `{}` ct={}", info.name, mireds);
+            if let Err(error) = device.set_color_temperature(mireds) {
+                tracing::error!("Problem with {}: {}", info.name, error.0);
+            }
+            responses.push(serde_json::json!({"success": {format!("{resource}/ct"): mireds}}));
+        } else {
+            responses.push(hue_parameter_not_available(resource, "ct"));
         }
-        responses
-            .push(serde_json::json!({"success": {format!("/lights/{id}/state/bri"): brightness}}));
     }
 
+    let xy = command
+        .get("xy")
+        .and_then(|value| value.as_array())
+        .and_then(|xy| Some((xy.first()?.as_f64()?, xy.get(1)?.as_f64()?)));
+    let hue = command.get("hue").and_then(|value| value.as_u64());
+    let sat = command.get("sat").and_then(|value| value.as_u64());
+    if xy.is_some() || hue.is_some() || sat.is_some() {
+        if device.supports_color() {
+            let color = match xy {
+                Some((x, y)) => {
+                    let (x, y) = (x.clamp(0.0, 1.0) as f32, y.clamp(0.0, 1.0) as f32);
+                    responses
+                        .push(serde_json::json!({"success": {format!("{resource}/xy"): [x, y]}}));
+                    Color::Xy { x, y }
+                }
+                None => {
+                    // hue and saturation may be changed independently of each other
+                    let (current_hue, current_saturation) = device
+                        .check_color()
+                        .unwrap_or(None)
+                        .map(|color| color.to_hue_saturation())
+                        .unwrap_or((0, 254));
+                    let new_hue = hue.map_or(current_hue, |hue| hue.min(65535) as u16);
+                    let saturation = sat.map_or(current_saturation, |sat| sat.min(254) as u8);
+                    if hue.is_some() {
+                        responses.push(
+                            serde_json::json!({"success": {format!("{resource}/hue"): new_hue}}),
+                        );
+                    }
+                    if sat.is_some() {
+                        responses.push(
+                            serde_json::json!({"success": {format!("{resource}/sat"): saturation}}),
+                        );
+                    }
+                    Color::HueSaturation {
+                        hue: new_hue,
+                        saturation,
+                    }
+                }
+            };
+            tracing::info!("HUE set `{}` color={:?}", info.name, color);
+            if let Err(error) = device.set_color(color) {
+                tracing::error!("Problem with {}: {}", info.name, error.0);
+            }
+        } else {
+            for (parameter, value) in [
+                ("xy", xy.is_some()),
+                ("hue", hue.is_some()),
+                ("sat", sat.is_some()),
+            ] {
+                if value {
+                    responses.push(hue_parameter_not_available(resource, parameter));
+                }
+            }
+        }
+    }
+
+    // let the device's GENA subscribers know too
+    info.changes.notify();
     serde_json::to_vec(&responses).unwrap()
 }
 
+///
+/// The Hue error for a state change that `resource` can't make
+///
+fn hue_parameter_not_available(resource: &str, parameter: &str) -> serde_json::Value {
+    serde_json::json!({
+        "error": {
+            "type": 6,
+            "address": format!("{resource}/{parameter}"),
+            "description": format!("parameter, {parameter}, not available")
+        }
+    })
+}
+
 fn hue_light_id_from_path(path: &str) -> Option<usize> {
     let lights = path.split("/lights/").nth(1)?;
     lights.split('/').next()?.parse().ok()
 }
 
-fn is_hue_username_path(path: &str) -> bool {
-    let Some(username) = path.strip_prefix("/api/") else {
-        return false;
-    };
+fn hue_sensor_id_from_path(path: &str) -> Option<usize> {
+    let sensors = path.split("/sensors/").nth(1)?;
+    sensors.split('/').next()?.parse().ok()
+}
 
-    !username.is_empty() && !username.contains('/')
+fn hue_group_id_from_path(path: &str) -> Option<usize> {
+    let groups = path.split("/groups/").nth(1)?;
+    groups.split('/').next()?.parse().ok()
 }
 
-fn percent_devices(devices: &[RustmoDevice]) -> Vec<(usize, &RustmoDevice)> {
-    devices
+fn find_group(devices: &[RustmoDevice], id: usize) -> Option<(&HueGroup, &RustmoDevice)> {
+    devices.iter().find_map(|device| {
+        device
+            .hue_group
+            .as_ref()
+            .filter(|group| group.id == id)
+            .map(|group| (group, device))
+    })
+}
+
+///
+/// What the Hue bridge needs to describe a device group, taken while the device list is locked
+/// so that the group and its members can be queried after it's released
+///
+struct HueGroupSnapshot {
+    name: String,
+    kind: HueGroupKind,
+    lights: Vec<usize>,
+    device: Arc<dyn VirtualDevice>,
+    members: Vec<Arc<dyn VirtualDevice>>,
+}
+
+impl HueGroupSnapshot {
+    fn new(devices: &[RustmoDevice], group: &HueGroup, device: &RustmoDevice) -> Self {
+        let members = devices
+            .iter()
+            .filter(|member| group.members.contains(&member.info.uuid))
+            .collect::<Vec<_>>();
+        let mut lights = members
+            .iter()
+            .filter(|member| member.is_hue_light())
+            .filter_map(|member| member.info.hue_id)
+            .collect::<Vec<_>>();
+        lights.sort();
+
+        HueGroupSnapshot {
+            name: device.info.name.to_string(),
+            kind: group.kind,
+            lights,
+            device: device.device.clone(),
+            members: members
+                .into_iter()
+                .map(|member| member.device.clone())
+                .collect(),
+        }
+    }
+
+    ///
+    /// The group's Hue representation.  Only its dimmable members are Hue lights, but every
+    /// member counts towards whether the group is on
+    ///
+    fn hue_json(&self) -> serde_json::Value {
+        let is_on = |device: &Arc<dyn VirtualDevice>| {
+            device.check_is_on().unwrap_or(VirtualDeviceState::Off) == VirtualDeviceState::On
+        };
+        let any_on = is_on(&self.device);
+        let all_on = any_on && self.members.iter().all(is_on);
+        let percent = self.device.check_percent().unwrap_or(None).unwrap_or(0);
+
+        let mut json = serde_json::json!({
+            "name": self.name,
+            "lights": self.lights.iter().map(|id| id.to_string()).collect::<Vec<_>>(),
+            "sensors": [],
+            "type": self.kind.as_str(),
+            "state": {
+                "all_on": all_on,
+                "any_on": any_on
+            },
+            "recycle": false,
+            "action": {
+                "on": any_on,
+                "bri": percent_to_hue_brightness(percent),
+                "alert": "none"
+            }
+        });
+        if self.kind == HueGroupKind::Room {
+            json["class"] = serde_json::json!("Other");
+        }
+        json
+    }
+}
+
+///
+/// Split a Hue API path like `/api/{username}/lights/1` into the username and the rest of the
+/// path
+///
+fn split_hue_username(path: &str) -> (&str, &str) {
+    let path = path
+        .strip_prefix("/api")
+        .unwrap_or(path)
+        .trim_start_matches('/');
+    match path.find('/') {
+        Some(idx) => path.split_at(idx),
+        None => (path, ""),
+    }
+}
+
+fn path_or_root(path: &str) -> &str {
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
+
+///
+/// A Hue API error response
+///
+fn hue_error(error_type: u16, address: &str, description: &str) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!([{
+        "error": {
+            "type": error_type,
+            "address": address,
+            "description": description
+        }
+    }]))
+    .unwrap()
+}
+
+pub(crate) fn percent_devices(devices: &[RustmoDevice]) -> Vec<(usize, &RustmoDevice)> {
+    let mut lights = devices
         .iter()
-        .filter(|device| device.supports_percent())
-        .enumerate()
-        .map(|(index, device)| (index + 1, device))
-        .collect()
+        .filter(|device| device.is_hue_light())
+        .filter_map(|device| device.info.hue_id.map(|hue_id| (hue_id, device)))
+        .collect::<Vec<_>>();
+    lights.sort_by_key(|(hue_id, _)| *hue_id);
+    lights
 }
 
-fn hue_serial(info: &RustmoDeviceInfo) -> String {
-    if info.name == "Rustmo Hue Bridge" {
-        "001788FFFE23BFC2".to_string()
+pub(crate) fn hue_serial(info: &RustmoDeviceInfo) -> String {
+    if let Some(bridge) = &info.hue_bridge {
+        bridge.bridge_id.clone()
     } else {
         info.uuid
             .simple()
@@ -782,6 +1664,10 @@ fn hue_serial(info: &RustmoDeviceInfo) -> String {
 }
 
 fn hue_mac(info: &RustmoDeviceInfo) -> String {
+    if let Some(bridge) = &info.hue_bridge {
+        return bridge.mac.clone();
+    }
+
     let serial = hue_serial(info);
     serial
         .as_bytes()
@@ -821,8 +1707,7 @@ mod tests {
     use std::net::{IpAddr, Ipv4Addr};
 
     use super::*;
-    use crate::virtual_device::VirtualDevice;
-    use uuid::Uuid;
+    use crate::virtual_device::{DimmerExposure, VirtualDevice, VirtualDeviceError};
 
     struct TestDevice;
 
@@ -876,16 +1761,51 @@ mod tests {
         assert!(response.contains("<brightness>90</brightness>"));
     }
 
+    #[test]
+    fn soap_action_is_parsed_with_or_without_quotes() {
+        assert_eq!(
+            soap_action("\"urn:Belkin:service:basicevent:1#SetBinaryState\""),
+            Some("SetBinaryState")
+        );
+        assert_eq!(
+            soap_action("urn:Belkin:service:basicevent:1#GetBinaryState"),
+            Some("GetBinaryState")
+        );
+        assert_eq!(soap_action("\"urn:Belkin:service:basicevent:1\""), None);
+        assert_eq!(soap_action("\"urn:Belkin:service:basicevent:1#\""), None);
+    }
+
+    #[test]
+    fn upnp_fault_is_a_soap_fault_with_an_error_code() {
+        let fault = UpnpFault::action_failed("projector <unreachable>");
+        let fault = String::from_utf8(fault.to_xml()).unwrap();
+
+        assert!(fault.contains("<s:Fault>"));
+        assert!(fault.contains("<faultstring>UPnPError</faultstring>"));
+        assert!(fault.contains("<errorCode>501</errorCode>"));
+        assert!(
+            fault.contains("<errorDescription>projector &lt;unreachable&gt;</errorDescription>")
+        );
+        assert_eq!(HttpResponse::from(Body::SoapFault(Vec::new())).status, 500);
+    }
+
     #[test]
     fn percent_device_setup_keeps_belkin_switch_identity() {
         let handler = DeviceHttpServerHandler::new(RustmoDevice {
             info: crate::RustmoDeviceInfo {
-                name: "Sconces".to_string(),
+                name: crate::DeviceName::new("Sconces"),
                 ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
                 port: 1100,
                 uuid: Uuid::nil(),
+                hue_id: None,
+                base_path: String::new(),
+                hue_bridge: None,
+                changes: Default::default(),
+                interfaces: Vec::new(),
             },
-            device: Box::new(TestDevice),
+            device: Arc::new(TestDevice),
+            http_servers: Vec::new(),
+            hue_group: None,
         });
 
         let setup = String::from_utf8(handler.handle_setup()).unwrap();
@@ -895,6 +1815,439 @@ mod tests {
         assert!(!setup.contains("urn:Belkin:device:dimmer:1"));
     }
 
+    #[test]
+    fn wemo_dimmers_describe_themselves_as_dimmers() {
+        struct Dimmer;
+
+        impl VirtualDevice for Dimmer {
+            fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                Ok(VirtualDeviceState::On)
+            }
+
+            fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                Ok(VirtualDeviceState::Off)
+            }
+
+            fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                Ok(VirtualDeviceState::On)
+            }
+
+            fn supports_percent(&self) -> bool {
+                true
+            }
+
+            fn check_percent(&self) -> Result<Option<u8>, VirtualDeviceError> {
+                Ok(Some(40))
+            }
+
+            fn dimmer_exposure(&self) -> DimmerExposure {
+                DimmerExposure::WemoDimmer
+            }
+        }
+
+        let handler = DeviceHttpServerHandler::new(RustmoDevice {
+            info: crate::RustmoDeviceInfo {
+                name: crate::DeviceName::new("Sconces"),
+                ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 1100,
+                uuid: Uuid::nil(),
+                hue_id: None,
+                base_path: String::new(),
+                hue_bridge: None,
+                changes: Default::default(),
+                interfaces: Vec::new(),
+            },
+            device: Arc::new(Dimmer),
+            http_servers: Vec::new(),
+            hue_group: None,
+        });
+
+        let setup = String::from_utf8(handler.handle_setup()).unwrap();
+        assert!(setup.contains("<deviceType>urn:Belkin:device:dimmer:1</deviceType>"));
+        assert!(setup.contains("<modelName>Dimmer</modelName>"));
+
+        let service = String::from_utf8(handler.handle_eventservice()).unwrap();
+        assert!(service.contains("<name>brightness</name>"));
+
+        let response = handler.respond(HttpRequest {
+            method: "GET".to_string(),
+            path: "/description.xml".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            remote_addr: "127.0.0.1:5000".parse().unwrap(),
+            local_addr: "127.0.0.1:1100".parse().unwrap(),
+        });
+        assert_eq!(response.status, 404);
+    }
+
+    #[test]
+    fn friendly_name_can_be_changed_over_soap() {
+        let handler = DeviceHttpServerHandler::new(RustmoDevice {
+            info: crate::RustmoDeviceInfo {
+                name: crate::DeviceName::new("Sconces"),
+                ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 1100,
+                uuid: Uuid::nil(),
+                hue_id: None,
+                base_path: String::new(),
+                hue_bridge: None,
+                changes: Default::default(),
+                interfaces: Vec::new(),
+            },
+            device: Arc::new(TestDevice),
+            http_servers: Vec::new(),
+            hue_group: None,
+        });
+        let soap = |action: &str, body: &str| {
+            let request = HttpRequest {
+                method: "POST".to_string(),
+                path: "/upnp/control/basicevent1".to_string(),
+                headers: vec![(
+                    "SOAPACTION".to_string(),
+                    format!("\"urn:Belkin:service:basicevent:1#{action}\""),
+                )],
+                body: format!(
+                    "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+                    <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">
+                        <s:Body>
+                            <u:{action} xmlns:u=\"urn:Belkin:service:basicevent:1\">{body}</u:{action}>
+                        </s:Body>
+                    </s:Envelope>"
+                )
+                .into_bytes(),
+                remote_addr: "127.0.0.1:5000".parse().unwrap(),
+                local_addr: "127.0.0.1:1100".parse().unwrap(),
+            };
+            handler
+                .handle_basicevent(&request)
+                .map(|body| String::from_utf8(body).unwrap())
+        };
+
+        let renamed = soap(
+            "ChangeFriendlyName",
+            "<FriendlyName>Cove &amp; Sconces</FriendlyName>",
+        )
+        .unwrap();
+        assert!(renamed.contains("<FriendlyName>Cove &amp; Sconces</FriendlyName>"));
+        let name = soap("GetFriendlyName", "").unwrap();
+        assert!(name.contains("<u:GetFriendlyNameResponse"));
+        assert!(name.contains("<FriendlyName>Cove &amp; Sconces</FriendlyName>"));
+        assert_eq!(handler.device.info.name.get(), "Cove & Sconces");
+
+        let fault = soap("ChangeFriendlyName", "<FriendlyName> </FriendlyName>").unwrap_err();
+        assert_eq!(fault.code, 402);
+    }
+
+    #[test]
+    fn shared_listener_paths_are_split_by_device_uuid() {
+        let uuid = Uuid::new_v4();
+
+        assert_eq!(
+            split_shared_device_path(&format!("/device/{uuid}/setup.xml")),
+            Some((uuid, "/setup.xml"))
+        );
+        assert_eq!(
+            split_shared_device_path(&format!("/device/{uuid}")),
+            Some((uuid, "/"))
+        );
+        assert_eq!(
+            split_shared_device_path("/device/not-a-uuid/setup.xml"),
+            None
+        );
+        assert_eq!(split_shared_device_path("/setup.xml"), None);
+    }
+
+    #[test]
+    fn shared_listener_setup_urls_are_prefixed() {
+        let uuid = Uuid::new_v4();
+        let handler = DeviceHttpServerHandler::new(RustmoDevice {
+            info: crate::RustmoDeviceInfo {
+                name: crate::DeviceName::new("Sconces"),
+                ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 1100,
+                uuid,
+                hue_id: None,
+                base_path: shared_device_base_path(&uuid),
+                hue_bridge: None,
+                changes: Default::default(),
+                interfaces: Vec::new(),
+            },
+            device: Arc::new(TestDevice),
+            http_servers: Vec::new(),
+            hue_group: None,
+        });
+
+        let setup = String::from_utf8(handler.handle_setup()).unwrap();
+
+        assert!(setup.contains(&format!(
+            "<controlURL>/device/{uuid}/upnp/control/basicevent1</controlURL>"
+        )));
+        assert!(setup.contains(&format!(
+            "<eventSubURL>/device/{uuid}/upnp/event/basicevent1</eventSubURL>"
+        )));
+        assert!(setup.contains(&format!(
+            "<SCPDURL>/device/{uuid}/eventservice.xml</SCPDURL>"
+        )));
+    }
+
+    #[test]
+    fn hue_light_ids_come_from_device_info() {
+        let light = |name: &str, hue_id: Option<usize>| RustmoDevice {
+            info: crate::RustmoDeviceInfo {
+                name: crate::DeviceName::new(name),
+                ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 1100,
+                uuid: Uuid::nil(),
+                hue_id,
+                base_path: String::new(),
+                hue_bridge: None,
+                changes: Default::default(),
+                interfaces: Vec::new(),
+            },
+            device: Arc::new(TestDevice),
+            http_servers: Vec::new(),
+            hue_group: None,
+        };
+        let devices = [
+            light("Step Lights", Some(7)),
+            light("Unregistered", None),
+            light("Sconces", Some(2)),
+        ];
+
+        let lights = percent_devices(&devices)
+            .into_iter()
+            .map(|(id, device)| (id, device.info.name.get()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            lights,
+            vec![(2, "Sconces".to_string()), (7, "Step Lights".to_string())]
+        );
+    }
+
+    #[test]
+    fn paired_apps_see_hue_groups_and_fan_out_actions() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        use crate::virtual_device::wrappers::CompositeDevice;
+        use crate::virtual_device::SynchronizedDevice;
+
+        #[derive(Default)]
+        struct Lamp(AtomicBool);
+
+        impl VirtualDevice for Lamp {
+            fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                self.0.store(true, Ordering::SeqCst);
+                self.check_is_on()
+            }
+
+            fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                self.0.store(false, Ordering::SeqCst);
+                self.check_is_on()
+            }
+
+            fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                Ok(match self.0.load(Ordering::SeqCst) {
+                    true => VirtualDeviceState::On,
+                    false => VirtualDeviceState::Off,
+                })
+            }
+
+            fn supports_percent(&self) -> bool {
+                true
+            }
+        }
+
+        let device = |name: &str, hue_id, device: Arc<dyn VirtualDevice>| RustmoDevice {
+            info: crate::RustmoDeviceInfo {
+                name: crate::DeviceName::new(name),
+                ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 1100,
+                uuid: Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes()),
+                hue_id,
+                base_path: String::new(),
+                hue_bridge: None,
+                changes: Default::default(),
+                interfaces: Vec::new(),
+            },
+            device,
+            http_servers: Vec::new(),
+            hue_group: None,
+        };
+        let sconces = SynchronizedDevice::new(Lamp::default());
+        let cove = SynchronizedDevice::new(Lamp::default());
+        let sconces_light = device("Sconces", Some(1), Arc::new(sconces.clone()));
+        let cove_light = device("Cove", Some(2), Arc::new(cove.clone()));
+        let members = vec![sconces_light.device.clone(), cove_light.device.clone()];
+        let mut theater = device(
+            "Theater",
+            None,
+            Arc::new(SynchronizedDevice::new(CompositeDevice::from_members(
+                members,
+            ))),
+        );
+        theater.hue_group = Some(HueGroup {
+            id: 1,
+            kind: HueGroupKind::Room,
+            members: vec![sconces_light.info.uuid, cove_light.info.uuid],
+        });
+        let devices = Arc::new(RwLock::new(vec![
+            sconces_light,
+            cove_light,
+            device("Other", Some(3), Arc::new(TestDevice)),
+            theater,
+        ]));
+        let whitelist = Arc::new(Mutex::new(HueWhitelist::default()));
+        let bridge = HueBridgeHttpServerHandler::new(
+            devices.read()[3].info.clone(),
+            devices.clone(),
+            Default::default(),
+            DeviceRenamer::new(&devices, &Default::default()),
+            whitelist.clone(),
+        );
+        let request = |method: &str, path: &str, body: &str| {
+            let response = bridge.respond(HttpRequest {
+                method: method.to_string(),
+                path: path.to_string(),
+                headers: Vec::new(),
+                body: body.as_bytes().to_vec(),
+                remote_addr: "127.0.0.1:5000".parse().unwrap(),
+                local_addr: "127.0.0.1:80".parse().unwrap(),
+            });
+            serde_json::from_slice::<serde_json::Value>(&response.body).unwrap()
+        };
+
+        let devicetype = r#"{"devicetype":"Echo#Theater"}"#;
+        let refused = request("POST", "/api", devicetype);
+        assert_eq!(refused[0]["error"]["type"], 101);
+
+        whitelist.lock().press_link_button();
+        let paired = request("POST", "/api", devicetype);
+        let username = paired[0]["success"]["username"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let unauthorized = request("GET", "/api/rustmo/groups", "");
+        assert_eq!(unauthorized[0]["error"]["type"], 1);
+        assert_eq!(unauthorized[0]["error"]["address"], "/groups");
+
+        let groups = request("GET", &format!("/api/{username}/groups"), "");
+        assert_eq!(groups["1"]["type"], "Room");
+        assert_eq!(groups["1"]["lights"], serde_json::json!(["1", "2"]));
+        assert_eq!(groups["1"]["state"]["any_on"], false);
+
+        let response = request(
+            "PUT",
+            &format!("/api/{username}/groups/1/action"),
+            r#"{"on":true}"#,
+        );
+        assert_eq!(
+            response,
+            serde_json::json!([{"success": {"/groups/1/action/on": true}}])
+        );
+        assert_eq!(sconces.check_is_on(), Ok(VirtualDeviceState::On));
+        assert_eq!(cove.check_is_on(), Ok(VirtualDeviceState::On));
+
+        let group = request("GET", &format!("/api/{username}/groups/1"), "");
+        assert_eq!(group["name"], "Theater");
+        assert_eq!(group["state"]["all_on"], true);
+        let unknown = request("GET", &format!("/api/{username}/groups/2"), "");
+        assert_eq!(unknown[0]["error"]["type"], 3);
+        assert_eq!(unknown[0]["error"]["address"], "/groups/2");
+
+        let taken = request(
+            "PUT",
+            &format!("/api/{username}/lights/2"),
+            r#"{"name":"sconces"}"#,
+        );
+        assert_eq!(taken[0]["error"]["type"], 7);
+        let renamed = request(
+            "PUT",
+            &format!("/api/{username}/lights/2"),
+            r#"{"name":"Cove Lights"}"#,
+        );
+        assert_eq!(
+            renamed,
+            serde_json::json!([{"success": {"/lights/2/name": "Cove Lights"}}])
+        );
+        let light = request("GET", &format!("/api/{username}/lights/2"), "");
+        assert_eq!(light["name"], "Cove Lights");
+    }
+
+    #[test]
+    fn color_temperature_lights_accept_ct_but_not_color() {
+        #[derive(Default)]
+        struct BiasLight(parking_lot::Mutex<Option<u16>>);
+
+        impl VirtualDevice for BiasLight {
+            fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                Ok(VirtualDeviceState::On)
+            }
+
+            fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                Ok(VirtualDeviceState::Off)
+            }
+
+            fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                Ok(VirtualDeviceState::On)
+            }
+
+            fn supports_percent(&self) -> bool {
+                true
+            }
+
+            fn supports_color_temperature(&self) -> bool {
+                true
+            }
+
+            fn set_color_temperature(
+                &self,
+                mireds: u16,
+            ) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                *self.0.lock() = Some(mireds);
+                Ok(VirtualDeviceState::On)
+            }
+
+            fn check_color_temperature(&self) -> Result<Option<u16>, VirtualDeviceError> {
+                Ok(*self.0.lock())
+            }
+        }
+
+        let device = RustmoDevice {
+            info: crate::RustmoDeviceInfo {
+                name: crate::DeviceName::new("Bias Light"),
+                ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 1100,
+                uuid: Uuid::nil(),
+                hue_id: Some(1),
+                base_path: String::new(),
+                hue_bridge: None,
+                changes: Default::default(),
+                interfaces: Vec::new(),
+            },
+            device: Arc::new(BiasLight::default()),
+            http_servers: Vec::new(),
+            hue_group: None,
+        };
+
+        let response = handle_hue_set_state(
+            &device.info,
+            device.device.as_ref(),
+            "/lights/1/state",
+            serde_json::json!({"ct": 600, "xy": [0.3, 0.3]}),
+        );
+        let response = serde_json::from_slice::<serde_json::Value>(&response).unwrap();
+        assert_eq!(response[0]["success"]["/lights/1/state/ct"], 500);
+        assert_eq!(response[1]["error"]["type"], 6);
+        assert_eq!(response[1]["error"]["address"], "/lights/1/state/xy");
+
+        let light = hue_light_json(&device);
+        assert_eq!(light["type"], "Color temperature light");
+        assert_eq!(light["state"]["ct"], 500);
+        assert_eq!(light["state"]["colormode"], "ct");
+        assert!(light["state"].get("xy").is_none());
+    }
+
     #[test]
     fn hue_brightness_maps_to_percent() {
         assert_eq!(hue_brightness_to_percent(0), 0);
@@ -4,13 +4,14 @@ use std::num::{ParseFloatError, ParseIntError};
 use std::ops::Deref;
 use std::str::Utf8Error;
 use std::sync::Arc;
+use std::time::Duration;
 
 use parking_lot::{Mutex, MutexGuard};
 use postgres::Error;
 
 use crate::RustmoError;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct VirtualDeviceError(pub String);
 
 impl VirtualDeviceError {
@@ -160,15 +161,448 @@ pub trait VirtualDevice: Sync + Send + 'static {
     fn check_percent(&self) -> Result<Option<u8>, VirtualDeviceError> {
         Ok(None)
     }
+
+    /// how should Alexa see this device, if it's dimmable?
+    ///
+    /// Dimmable devices are Hue lights behind the emulated Hue bridge unless they say otherwise,
+    /// but can be WeMo Dimmers instead, which don't need the bridge.
+    fn dimmer_exposure(&self) -> DimmerExposure {
+        DimmerExposure::HueLight
+    }
+
+    /// can this device's color temperature be changed?
+    ///
+    /// Only dimmable devices are exposed as Hue lights, so this only matters if
+    /// `::supports_percent()` is also true.
+    fn supports_color_temperature(&self) -> bool {
+        false
+    }
+
+    /// set the device's color temperature, in mireds (153, the coolest, to 500, the warmest).
+    fn set_color_temperature(&self, mireds: u16) -> Result<VirtualDeviceState, VirtualDeviceError> {
+        let _ = mireds;
+        Err(VirtualDeviceError::new(
+            "color temperature is not supported",
+        ))
+    }
+
+    /// return the current color temperature, in mireds, when the device can report it.
+    fn check_color_temperature(&self) -> Result<Option<u16>, VirtualDeviceError> {
+        Ok(None)
+    }
+
+    /// can this device's color be changed?
+    ///
+    /// Only dimmable devices are exposed as Hue lights, so this only matters if
+    /// `::supports_percent()` is also true.
+    fn supports_color(&self) -> bool {
+        false
+    }
+
+    /// set the device's color.  See [`Color`] for converting it to whichever form the device
+    /// wants.
+    fn set_color(&self, color: Color) -> Result<VirtualDeviceState, VirtualDeviceError> {
+        let _ = color;
+        Err(VirtualDeviceError::new("color is not supported"))
+    }
+
+    /// return the current color when the device can report it, or `None` when it isn't showing
+    /// a color (when it's set to a color temperature, for example).
+    fn check_color(&self) -> Result<Option<Color>, VirtualDeviceError> {
+        Ok(None)
+    }
+}
+
+///
+/// How a dimmable device (one that `::supports_percent()`) is exposed to Alexa
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DimmerExposure {
+    /// a light behind the emulated Hue bridge, which supports color temperature and color too
+    #[default]
+    HueLight,
+
+    /// a Belkin WeMo Dimmer (`urn:Belkin:device:dimmer:1`), which works without the Hue bridge
+    WemoDimmer,
+}
+
+///
+/// A light's color, in either of the forms the Hue API uses
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    /// a `hue` around the color wheel, starting and ending at red (0 to 65535), and a
+    /// `saturation` (0 to 254)
+    HueSaturation { hue: u16, saturation: u8 },
+
+    /// a point in the CIE 1931 color space
+    Xy { x: f32, y: f32 },
+}
+
+impl Color {
+    ///
+    /// This color as a point in the CIE 1931 color space
+    ///
+    pub fn to_xy(&self) -> (f32, f32) {
+        let (hue, saturation) = match *self {
+            Color::Xy { x, y } => return (x, y),
+            Color::HueSaturation { hue, saturation } => (hue, saturation),
+        };
+
+        let (r, g, b) = hsv_to_rgb(
+            hue as f32 / 65535.0 * 360.0,
+            saturation.min(254) as f32 / 254.0,
+        );
+        let (r, g, b) = (gamma_expand(r), gamma_expand(g), gamma_expand(b));
+        let x = r * 0.664511 + g * 0.154324 + b * 0.162028;
+        let y = r * 0.283881 + g * 0.668433 + b * 0.047685;
+        let z = r * 0.000088 + g * 0.072310 + b * 0.986039;
+        let sum = x + y + z;
+        if sum == 0.0 {
+            // black has no chromaticity, so call it white
+            return (0.3127, 0.3290);
+        }
+        (x / sum, y / sum)
+    }
+
+    ///
+    /// This color as a hue (0 to 65535) and saturation (0 to 254)
+    ///
+    pub fn to_hue_saturation(&self) -> (u16, u8) {
+        let (x, y) = match *self {
+            Color::HueSaturation { hue, saturation } => return (hue, saturation.min(254)),
+            Color::Xy { x, y } => (x, y),
+        };
+        if y <= 0.0 {
+            return (0, 0);
+        }
+
+        let (big_x, big_y, big_z) = (x / y, 1.0, (1.0 - x - y) / y);
+        let r = big_x * 1.656492 - big_y * 0.354851 - big_z * 0.255038;
+        let g = -big_x * 0.707196 + big_y * 1.655397 + big_z * 0.036152;
+        let b = big_x * 0.051713 - big_y * 0.121364 + big_z * 1.01153;
+        let (r, g, b) = (r.max(0.0), g.max(0.0), b.max(0.0));
+        let max = r.max(g).max(b);
+        if max == 0.0 {
+            return (0, 0);
+        }
+        let (r, g, b) = (
+            gamma_compress(r / max),
+            gamma_compress(g / max),
+            gamma_compress(b / max),
+        );
+
+        let (hue, saturation) = rgb_to_hue_saturation(r, g, b);
+        (
+            (hue / 360.0 * 65535.0).round() as u16,
+            (saturation * 254.0).round() as u8,
+        )
+    }
+}
+
+fn gamma_expand(c: f32) -> f32 {
+    if c > 0.04045 {
+        ((c + 0.055) / 1.055).powf(2.4)
+    } else {
+        c / 12.92
+    }
+}
+
+fn gamma_compress(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// a fully bright color with `hue` in degrees and `saturation` from 0 to 1, as RGB from 0 to 1
+fn hsv_to_rgb(hue: f32, saturation: f32) -> (f32, f32, f32) {
+    let sector = (hue % 360.0) / 60.0;
+    let f = sector - sector.floor();
+    let (p, q, t) = (
+        1.0 - saturation,
+        1.0 - saturation * f,
+        1.0 - saturation * (1.0 - f),
+    );
+    match sector as u8 {
+        0 => (1.0, t, p),
+        1 => (q, 1.0, p),
+        2 => (p, 1.0, t),
+        3 => (p, q, 1.0),
+        4 => (t, p, 1.0),
+        _ => (1.0, p, q),
+    }
+}
+
+/// the hue, in degrees, and saturation, from 0 to 1, of an RGB color with components from 0 to 1
+fn rgb_to_hue_saturation(r: f32, g: f32, b: f32) -> (f32, f32) {
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    if delta == 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let hue = if max == r {
+        60.0 * (((g - b) / delta) % 6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    (hue.rem_euclid(360.0), delta / max)
+}
+
+///
+/// The `VirtualSensor` trait allows implementors to create sensors that the Hue bridge exposes
+/// under `/sensors`, such as whether a room is occupied or whether a movie is playing.
+///
+/// Alexa can't ask a sensor anything, but its routines can be triggered when a sensor's reading
+/// changes.  Readings are taken whenever the bridge is asked about the sensor, so `::read()`
+/// should be quick.
+///
+pub trait VirtualSensor: Sync + Send + 'static {
+    /// what this sensor measures.  It shouldn't change
+    fn kind(&self) -> SensorKind;
+
+    /// take a reading, which must be of this sensor's `::kind()`
+    fn read(&self) -> Result<SensorReading, VirtualDeviceError>;
+}
+
+///
+/// What a `VirtualSensor` measures, which determines the type of Hue sensor it appears as
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorKind {
+    /// whether anyone is present, as a `CLIPPresence` sensor
+    Presence,
+
+    /// the temperature, as a `CLIPTemperature` sensor
+    Temperature,
+
+    /// the light level, as a `CLIPLightLevel` sensor
+    LightLevel,
+
+    /// any status you like, as a `CLIPGenericStatus` sensor
+    GenericStatus,
+}
+
+///
+/// A reading taken by a `VirtualSensor`
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensorReading {
+    /// is anyone there?
+    Presence(bool),
+
+    /// the temperature, in degrees Celsius
+    Temperature(f32),
+
+    /// the illuminance, in lux
+    LightLevel(f32),
+
+    /// an arbitrary status, such as 1 while a movie is playing and 0 otherwise
+    GenericStatus(i32),
+}
+
+impl SensorReading {
+    ///
+    /// The kind of sensor that takes this reading
+    ///
+    pub fn kind(&self) -> SensorKind {
+        match self {
+            SensorReading::Presence(_) => SensorKind::Presence,
+            SensorReading::Temperature(_) => SensorKind::Temperature,
+            SensorReading::LightLevel(_) => SensorKind::LightLevel,
+            SensorReading::GenericStatus(_) => SensorKind::GenericStatus,
+        }
+    }
+}
+
+///
+/// How many of a `CompositeDevice`'s members have to succeed for an operation on the group to
+/// succeed
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Quorum {
+    /// every member
+    #[default]
+    All,
+
+    /// more than half of them
+    Majority,
+
+    /// at least this many, or every member if the group is smaller
+    AtLeast(usize),
+}
+
+impl Quorum {
+    ///
+    /// How many of a group of `members` have to succeed
+    ///
+    pub fn required(&self, members: usize) -> usize {
+        match self {
+            Quorum::All => members,
+            Quorum::Majority => (members / 2 + 1).min(members),
+            Quorum::AtLeast(count) => (*count).min(members),
+        }
+    }
+}
+
+///
+/// Why a member of a `CompositeDevice` didn't do what was asked of it
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemberFailure {
+    /// it returned an error, or panicked
+    Failed(VirtualDeviceError),
+
+    /// it didn't finish before the group's member deadline.  It's left to finish in the
+    /// background, and logged when it does.  Until then it isn't called again, and every
+    /// operation reports it as timed out
+    TimedOut,
+}
+
+///
+/// What happened the last time a `CompositeDevice` fanned an operation out to its members
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupReport {
+    /// the `VirtualDevice` method called on each member, such as `turn_on`
+    pub operation: &'static str,
+
+    /// how many members the group has
+    pub members: usize,
+
+    /// how many members had to succeed, according to the group's `Quorum`
+    pub required: usize,
+
+    /// the members that failed, by their position in the group
+    pub failures: Vec<(usize, MemberFailure)>,
+}
+
+impl GroupReport {
+    pub fn succeeded(&self) -> usize {
+        self.members - self.failures.len()
+    }
+
+    pub fn is_quorate(&self) -> bool {
+        self.succeeded() >= self.required
+    }
+}
+
+impl Display for GroupReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}`: {} of {} members succeeded ({} required)",
+            self.operation,
+            self.succeeded(),
+            self.members,
+            self.required
+        )?;
+        for (index, failure) in &self.failures {
+            match failure {
+                MemberFailure::Failed(e) => write!(f, "; member {index} failed: {e}")?,
+                MemberFailure::TimedOut => write!(f, "; member {index} timed out")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// a closure run by a `SequenceStep::Action`, or fired by a `MomentaryDevice`
+pub type DeviceAction = Box<dyn Fn() -> Result<(), VirtualDeviceError> + Send + Sync>;
+
+///
+/// One step of a sequence device (see `RustmoServer::add_device_sequence()`).  Turning the
+/// sequence on runs its steps in order, and turning it off runs them in reverse
+///
+pub enum SequenceStep {
+    /// turn the device on, or in reverse, off
+    Device(Box<dyn VirtualDevice>),
+
+    /// wait this long, in either direction
+    Delay(Duration),
+
+    /// wait (up to the given timeout) until the device reports that it's on.  Skipped in reverse,
+    /// where the device is turned off after the steps that depended on it
+    WaitUntilOn(Box<dyn VirtualDevice>, Duration),
+
+    /// call the first closure, or in reverse, the second one (if there is one)
+    Action(DeviceAction, Option<DeviceAction>),
+}
+
+impl SequenceStep {
+    pub fn device<T: VirtualDevice>(device: T) -> Self {
+        SequenceStep::Device(Box::new(device))
+    }
+
+    pub fn wait_until_on<T: VirtualDevice>(device: T, timeout: Duration) -> Self {
+        SequenceStep::WaitUntilOn(Box::new(device), timeout)
+    }
+
+    ///
+    /// A step that calls `action` when the sequence is turned on, and does nothing when it's
+    /// turned off
+    ///
+    pub fn action<F>(action: F) -> Self
+    where
+        F: Fn() -> Result<(), VirtualDeviceError> + Send + Sync + 'static,
+    {
+        SequenceStep::Action(Box::new(action), None)
+    }
+
+    ///
+    /// A step that calls `on` when the sequence is turned on, and `off` when it's turned off
+    ///
+    pub fn reversible_action<On, Off>(on: On, off: Off) -> Self
+    where
+        On: Fn() -> Result<(), VirtualDeviceError> + Send + Sync + 'static,
+        Off: Fn() -> Result<(), VirtualDeviceError> + Send + Sync + 'static,
+    {
+        SequenceStep::Action(Box::new(on), Some(Box::new(off)))
+    }
 }
 
 pub(crate) mod wrappers {
     use std::ops::{Deref, DerefMut};
-    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
     use std::thread;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
+
+    use once_cell::sync::Lazy;
+    use parking_lot::Mutex;
+
+    use crate::virtual_device::{
+        Color, DeviceAction, DimmerExposure, GroupReport, MemberFailure, Quorum, SequenceStep,
+        SynchronizedDevice, VirtualDevice, VirtualDeviceError, VirtualDeviceState,
+    };
+
+    /// how long a `CompositeDevice` waits for its members by default, leaving time to answer
+    /// Alexa before it gives up (after 5 seconds)
+    const DEFAULT_MEMBER_DEADLINE: Duration = Duration::from_secs(4);
+
+    /// how long a `DeadlineDevice` waits for its device by default, leaving time to answer Alexa
+    const DEFAULT_DEADLINE: Duration = Duration::from_secs(4);
 
-    use crate::virtual_device::{VirtualDevice, VirtualDeviceError, VirtualDeviceState};
+    /// how often a `SequenceStep::WaitUntilOn` checks whether its device has turned on
+    const WAIT_UNTIL_ON_POLL_INTERVAL: Duration = Duration::from_millis(400);
+
+    /// calls that never finish keep their thread busy, so there are plenty
+    const WORKER_THREADS: usize = 32;
+
+    /// the threads `CompositeDevice`s call their members on, kept apart from rayon's global pool
+    /// so slow devices can't hold up anything else
+    static WORKER_POOL: Lazy<rayon::ThreadPool> = Lazy::new(|| {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(WORKER_THREADS)
+            .thread_name(|i| format!("rustmo-worker-{i}"))
+            .build()
+            .expect("unable to start the worker thread pool")
+    });
 
     ///
     /// Wrapper for `VirtualDevice` that pretends the device is instantly turned on when
@@ -239,6 +673,37 @@ pub(crate) mod wrappers {
         fn check_percent(&self) -> Result<Option<u8>, VirtualDeviceError> {
             self.device.check_percent()
         }
+
+        fn dimmer_exposure(&self) -> DimmerExposure {
+            self.device.dimmer_exposure()
+        }
+
+        fn supports_color_temperature(&self) -> bool {
+            self.device.supports_color_temperature()
+        }
+
+        fn set_color_temperature(
+            &self,
+            mireds: u16,
+        ) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.device.set_color_temperature(mireds)
+        }
+
+        fn check_color_temperature(&self) -> Result<Option<u16>, VirtualDeviceError> {
+            self.device.check_color_temperature()
+        }
+
+        fn supports_color(&self) -> bool {
+            self.device.supports_color()
+        }
+
+        fn set_color(&self, color: Color) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.device.set_color(color)
+        }
+
+        fn check_color(&self) -> Result<Option<Color>, VirtualDeviceError> {
+            self.device.check_color()
+        }
     }
 
     ///
@@ -344,110 +809,959 @@ pub(crate) mod wrappers {
         fn check_percent(&self) -> Result<Option<u8>, VirtualDeviceError> {
             self.device.check_percent()
         }
+
+        fn dimmer_exposure(&self) -> DimmerExposure {
+            self.device.dimmer_exposure()
+        }
+
+        fn supports_color_temperature(&self) -> bool {
+            self.device.supports_color_temperature()
+        }
+
+        fn set_color_temperature(
+            &self,
+            mireds: u16,
+        ) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.device.set_color_temperature(mireds)
+        }
+
+        fn check_color_temperature(&self) -> Result<Option<u16>, VirtualDeviceError> {
+            self.device.check_color_temperature()
+        }
+
+        fn supports_color(&self) -> bool {
+            self.device.supports_color()
+        }
+
+        fn set_color(&self, color: Color) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.device.set_color(color)
+        }
+
+        fn check_color(&self) -> Result<Option<Color>, VirtualDeviceError> {
+            self.device.check_color()
+        }
+    }
+
+    ///
+    /// What a wrapped device supports, read once when it's wrapped so that it can be answered
+    /// without waiting on the device
+    ///
+    #[derive(Debug, Clone, Copy)]
+    struct Capabilities {
+        percent: bool,
+        dimmer_exposure: DimmerExposure,
+        color_temperature: bool,
+        color: bool,
+    }
+
+    impl Capabilities {
+        fn of(device: &dyn VirtualDevice) -> Self {
+            Capabilities {
+                percent: device.supports_percent(),
+                dimmer_exposure: device.dimmer_exposure(),
+                color_temperature: device.supports_color_temperature(),
+                color: device.supports_color(),
+            }
+        }
+
+        ///
+        /// A group supports what all of its members do, and is only a WeMo dimmer if every
+        /// member is
+        ///
+        fn of_group(devices: &[Arc<dyn VirtualDevice>]) -> Self {
+            let members = devices
+                .iter()
+                .map(|device| Capabilities::of(device.as_ref()))
+                .collect::<Vec<_>>();
+            let all = |supports: fn(&Capabilities) -> bool| {
+                !members.is_empty() && members.iter().all(supports)
+            };
+            Capabilities {
+                percent: all(|member| member.percent),
+                dimmer_exposure: if all(|member| {
+                    member.dimmer_exposure == DimmerExposure::WemoDimmer
+                }) {
+                    DimmerExposure::WemoDimmer
+                } else {
+                    DimmerExposure::HueLight
+                },
+                color_temperature: all(|member| member.color_temperature),
+                color: all(|member| member.color),
+            }
+        }
     }
 
     ///
     /// Wrapper for `VirtualDevice` that allows a list of devices to work together as a single
     /// device.
     ///
-    /// All state changes and inqueries to the underlying devices happen in parallel
+    /// State changes and inquiries go to the underlying devices in parallel.  Members that fail,
+    /// or don't finish before the member deadline, are recorded in a `GroupReport` and logged,
+    /// and the group only fails if fewer than its `Quorum` of members succeeded.  What the group
+    /// supports is read from its members when it's put together.
+    ///
+    /// Each member has at most one call in flight, so a member that hangs ties up a single
+    /// worker thread rather than one for every request Alexa makes of the group
     ///
     pub struct CompositeDevice {
-        pub(crate) devices: Vec<Box<dyn VirtualDevice>>,
+        pub(crate) devices: Vec<Arc<dyn VirtualDevice>>,
+        /// whether each member is still busy with an earlier call
+        in_flight: Vec<Arc<AtomicBool>>,
+        capabilities: Capabilities,
+        quorum: Quorum,
+        member_deadline: Duration,
+        last_report: Mutex<Option<GroupReport>>,
     }
 
-    impl VirtualDevice for CompositeDevice {
-        fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
-            self.devices.iter().for_each(|device| {
-                if device.check_is_on().unwrap_or(VirtualDeviceState::Off)
-                    == VirtualDeviceState::Off
-                {
-                    device.turn_on().ok().unwrap();
-                }
-            });
+    impl CompositeDevice {
+        pub fn new(devices: Vec<Box<dyn VirtualDevice>>) -> Self {
+            Self::from_members(devices.into_iter().map(Arc::from).collect())
+        }
 
-            self.check_is_on()
+        pub(crate) fn from_members(devices: Vec<Arc<dyn VirtualDevice>>) -> Self {
+            CompositeDevice {
+                in_flight: devices.iter().map(|_| Default::default()).collect(),
+                capabilities: Capabilities::of_group(&devices),
+                devices,
+                quorum: Quorum::default(),
+                member_deadline: DEFAULT_MEMBER_DEADLINE,
+                last_report: Mutex::new(None),
+            }
         }
 
-        fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
-            self.devices.iter().for_each(|device| {
-                if device.check_is_on().unwrap_or(VirtualDeviceState::Off) == VirtualDeviceState::On
-                {
-                    device.turn_off().ok().unwrap();
-                }
-            });
+        pub fn quorum(&self) -> Quorum {
+            self.quorum
+        }
 
-            self.check_is_on()
+        ///
+        /// Change how many members have to succeed for the group to succeed, which is all of them by
+        /// default
+        ///
+        pub fn set_quorum(&mut self, quorum: Quorum) {
+            self.quorum = quorum;
         }
 
-        fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
-            if self
-                .devices
-                .iter()
-                .any(|d| d.check_is_on() == Ok(VirtualDeviceState::On))
-            {
-                Ok(VirtualDeviceState::On)
-            } else {
-                Ok(VirtualDeviceState::Off)
-            }
+        pub fn member_deadline(&self) -> Duration {
+            self.member_deadline
         }
 
-        fn supports_percent(&self) -> bool {
-            !self.devices.is_empty() && self.devices.iter().all(|d| d.supports_percent())
+        ///
+        /// Change how long the group waits for each member, which is 4 seconds by default
+        ///
+        pub fn set_member_deadline(&mut self, deadline: Duration) {
+            self.member_deadline = deadline;
         }
 
-        fn set_percent(&self, percent: u8) -> Result<VirtualDeviceState, VirtualDeviceError> {
-            for device in &self.devices {
-                device.set_percent(percent)?;
+        ///
+        /// What happened the last time an operation was fanned out to the group's members, if
+        /// one has been
+        ///
+        pub fn last_report(&self) -> Option<GroupReport> {
+            self.last_report.lock().clone()
+        }
+
+        ///
+        /// Call `operation` on every member in parallel, and wait (up to the member deadline)
+        /// for them to finish.  Returns the results of the members that succeeded, if there
+        /// were enough of them
+        ///
+        fn fan_out<R, F>(
+            &self,
+            name: &'static str,
+            operation: F,
+        ) -> Result<Vec<R>, VirtualDeviceError>
+        where
+            R: Send + 'static,
+            F: Fn(&dyn VirtualDevice) -> Result<R, VirtualDeviceError> + Send + Sync + 'static,
+        {
+            let operation = Arc::new(operation);
+            let (sender, receiver) = mpsc::channel();
+            for (index, device) in self.devices.iter().enumerate() {
+                let in_flight = Arc::clone(&self.in_flight[index]);
+                if in_flight.swap(true, Ordering::SeqCst) {
+                    tracing::warn!(
+                        "group member {} is still busy, so it won't be asked to `{}`",
+                        index,
+                        name
+                    );
+                    continue;
+                }
+
+                let device = Arc::clone(device);
+                let operation = Arc::clone(&operation);
+                let sender = sender.clone();
+                WORKER_POOL.spawn(move || {
+                    let started = Instant::now();
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| operation(&*device)))
+                        .unwrap_or_else(|_| Err(VirtualDeviceError::new("panicked")));
+                    in_flight.store(false, Ordering::SeqCst);
+                    if let Err(mpsc::SendError((_, result))) = sender.send((index, result)) {
+                        tracing::warn!(
+                            "group member {} finished `{}` after {:?}, past its deadline: {}",
+                            index,
+                            name,
+                            started.elapsed(),
+                            match result {
+                                Ok(_) => "succeeded".to_string(),
+                                Err(e) => format!("failed: {e}"),
+                            }
+                        );
+                    }
+                });
+            }
+            drop(sender);
+
+            let deadline = Instant::now() + self.member_deadline;
+            let mut results = self.devices.iter().map(|_| None).collect::<Vec<_>>();
+            for _ in 0..self.devices.len() {
+                match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok((index, result)) => results[index] = Some(result),
+                    Err(_) => break,
+                }
             }
 
-            self.check_is_on()
-        }
+            let mut succeeded = Vec::with_capacity(results.len());
+            let mut failures = Vec::new();
+            for (index, result) in results.into_iter().enumerate() {
+                match result {
+                    Some(Ok(result)) => succeeded.push(result),
+                    Some(Err(e)) => failures.push((index, MemberFailure::Failed(e))),
+                    None => failures.push((index, MemberFailure::TimedOut)),
+                }
+            }
+            let report = GroupReport {
+                operation: name,
+                members: self.devices.len(),
+                required: self.quorum.required(self.devices.len()),
+                failures,
+            };
 
-        fn check_percent(&self) -> Result<Option<u8>, VirtualDeviceError> {
-            let percents = self
-                .devices
-                .iter()
-                .map(|device| device.check_percent())
-                .collect::<Result<Vec<_>, _>>()?;
-            Ok(percents.into_iter().flatten().max())
+            let result = if !report.is_quorate() {
+                tracing::error!("group {}", report);
+                Err(VirtualDeviceError::from(report.to_string()))
+            } else {
+                if !report.failures.is_empty() {
+                    tracing::warn!("group {}", report);
+                }
+                Ok(succeeded)
+            };
+            *self.last_report.lock() = Some(report);
+            result
         }
-    }
 
-    ///
-    /// Wrapper for `VirtualDevice` that allows a device to be implemented using closures
-    pub struct FunctionalDevice<TurnOn, TurnOff, CheckIsOn>
-    where
-        TurnOn: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
-        TurnOff: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
-        CheckIsOn: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
-    {
-        pub(crate) turn_on: TurnOn,
-        pub(crate) turn_off: TurnOff,
-        pub(crate) check_is_on: CheckIsOn,
+        ///
+        /// Call a member `operation` that returns the member's state, and report the group as
+        /// on if any member that succeeded is
+        ///
+        fn fan_out_state<F>(
+            &self,
+            name: &'static str,
+            operation: F,
+        ) -> Result<VirtualDeviceState, VirtualDeviceError>
+        where
+            F: Fn(&dyn VirtualDevice) -> Result<VirtualDeviceState, VirtualDeviceError>
+                + Send
+                + Sync
+                + 'static,
+        {
+            let states = self.fan_out(name, operation)?;
+            if states.contains(&VirtualDeviceState::On) {
+                Ok(VirtualDeviceState::On)
+            } else {
+                Ok(VirtualDeviceState::Off)
+            }
+        }
     }
 
-    impl<TurnOn, TurnOff, CheckIsOn> VirtualDevice for FunctionalDevice<TurnOn, TurnOff, CheckIsOn>
-    where
-        TurnOn: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
-        TurnOff: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
-        CheckIsOn: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
-    {
+    impl VirtualDevice for CompositeDevice {
         fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
-            (self.turn_on)()
+            self.fan_out_state("turn_on", |device| {
+                match device.check_is_on().unwrap_or(VirtualDeviceState::Off) {
+                    VirtualDeviceState::Off => device.turn_on(),
+                    VirtualDeviceState::On => Ok(VirtualDeviceState::On),
+                }
+            })
         }
 
         fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
-            (self.turn_off)()
+            self.fan_out_state("turn_off", |device| {
+                match device.check_is_on().unwrap_or(VirtualDeviceState::On) {
+                    VirtualDeviceState::On => device.turn_off(),
+                    VirtualDeviceState::Off => Ok(VirtualDeviceState::Off),
+                }
+            })
         }
 
         fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
-            (self.check_is_on)()
+            self.fan_out_state("check_is_on", |device| device.check_is_on())
         }
-    }
-}
 
-///
+        fn supports_percent(&self) -> bool {
+            self.capabilities.percent
+        }
+
+        fn set_percent(&self, percent: u8) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.fan_out_state("set_percent", move |device| device.set_percent(percent))
+        }
+
+        fn check_percent(&self) -> Result<Option<u8>, VirtualDeviceError> {
+            let percents = self.fan_out("check_percent", |device| device.check_percent())?;
+            Ok(percents.into_iter().flatten().max())
+        }
+
+        fn dimmer_exposure(&self) -> DimmerExposure {
+            self.capabilities.dimmer_exposure
+        }
+
+        fn supports_color_temperature(&self) -> bool {
+            self.capabilities.color_temperature
+        }
+
+        fn set_color_temperature(
+            &self,
+            mireds: u16,
+        ) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.fan_out_state("set_color_temperature", move |device| {
+                device.set_color_temperature(mireds)
+            })
+        }
+
+        fn check_color_temperature(&self) -> Result<Option<u16>, VirtualDeviceError> {
+            // report the first member's, since a group doesn't have a single temperature
+            let temperatures = self.fan_out("check_color_temperature", |device| {
+                device.check_color_temperature()
+            })?;
+            Ok(temperatures.into_iter().flatten().next())
+        }
+
+        fn supports_color(&self) -> bool {
+            self.capabilities.color
+        }
+
+        fn set_color(&self, color: Color) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.fan_out_state("set_color", move |device| device.set_color(color))
+        }
+
+        fn check_color(&self) -> Result<Option<Color>, VirtualDeviceError> {
+            let colors = self.fan_out("check_color", |device| device.check_color())?;
+            Ok(colors.into_iter().flatten().next())
+        }
+    }
+
+    ///
+    /// Wrapper for `VirtualDevice` that runs a list of steps one after the other when turned on,
+    /// and in reverse when turned off.  A step that fails stops the sequence.
+    ///
+    /// It's on when every device turned on by a `SequenceStep::Device` is, or if there are none,
+    /// when it was last turned on
+    ///
+    pub struct SequenceDevice {
+        pub(crate) steps: Vec<SequenceStep>,
+        believed_on: AtomicBool,
+    }
+
+    impl SequenceDevice {
+        pub fn new(steps: Vec<SequenceStep>) -> Self {
+            SequenceDevice {
+                steps,
+                believed_on: AtomicBool::new(false),
+            }
+        }
+
+        fn run_step(
+            step: &SequenceStep,
+            desired_state: VirtualDeviceState,
+        ) -> Result<(), VirtualDeviceError> {
+            match (step, desired_state) {
+                (SequenceStep::Device(device), VirtualDeviceState::On) => {
+                    device.turn_on()?;
+                }
+                (SequenceStep::Device(device), VirtualDeviceState::Off) => {
+                    device.turn_off()?;
+                }
+                (SequenceStep::Delay(delay), _) => thread::sleep(*delay),
+                (SequenceStep::WaitUntilOn(device, timeout), VirtualDeviceState::On) => {
+                    let deadline = Instant::now() + *timeout;
+                    while device.check_is_on().ok() != Some(VirtualDeviceState::On) {
+                        if Instant::now() >= deadline {
+                            return Err(VirtualDeviceError::from(format!(
+                                "timed out after {timeout:?} waiting for a device to turn on"
+                            )));
+                        }
+                        thread::sleep(WAIT_UNTIL_ON_POLL_INTERVAL);
+                    }
+                }
+                (SequenceStep::WaitUntilOn(..), VirtualDeviceState::Off) => {}
+                (SequenceStep::Action(on, _), VirtualDeviceState::On) => on()?,
+                (SequenceStep::Action(_, Some(off)), VirtualDeviceState::Off) => off()?,
+                (SequenceStep::Action(_, None), VirtualDeviceState::Off) => {}
+            }
+            Ok(())
+        }
+
+        fn run<'a>(
+            steps: impl Iterator<Item = (usize, &'a SequenceStep)>,
+            desired_state: VirtualDeviceState,
+        ) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            for (index, step) in steps {
+                tracing::debug!("turning sequence {:?}: step {}", desired_state, index);
+                Self::run_step(step, desired_state).map_err(|e| {
+                    tracing::error!(
+                        "turning sequence {:?} failed at step {}: {}",
+                        desired_state,
+                        index,
+                        e
+                    );
+                    VirtualDeviceError::from(format!("step {index} failed: {e}"))
+                })?;
+            }
+            Ok(desired_state)
+        }
+    }
+
+    impl VirtualDevice for SequenceDevice {
+        fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            let state = Self::run(self.steps.iter().enumerate(), VirtualDeviceState::On)?;
+            self.believed_on.store(true, Ordering::SeqCst);
+            Ok(state)
+        }
+
+        fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            let state = Self::run(self.steps.iter().enumerate().rev(), VirtualDeviceState::Off)?;
+            self.believed_on.store(false, Ordering::SeqCst);
+            Ok(state)
+        }
+
+        fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            let mut devices = self
+                .steps
+                .iter()
+                .filter_map(|step| match step {
+                    SequenceStep::Device(device) => Some(device),
+                    _ => None,
+                })
+                .peekable();
+            if devices.peek().is_none() {
+                return Ok(if self.believed_on.load(Ordering::SeqCst) {
+                    VirtualDeviceState::On
+                } else {
+                    VirtualDeviceState::Off
+                });
+            }
+
+            for device in devices {
+                if device.check_is_on()? == VirtualDeviceState::Off {
+                    return Ok(VirtualDeviceState::Off);
+                }
+            }
+            Ok(VirtualDeviceState::On)
+        }
+    }
+
+    ///
+    /// Wrapper for `VirtualDevice` that calls the device on a worker thread, and stops waiting
+    /// for it after a deadline so Alexa always gets an answer in time.
+    ///
+    /// A change that misses the deadline is answered with the state that was asked for, and a
+    /// query with the last state the device was known to be in.  The call carries on in the
+    /// background, and is logged (and its state remembered) when it finishes.
+    ///
+    /// Each `DeadlineDevice` has a worker thread of its own, which makes its calls one at a time
+    /// in the order they were made.  Queries made while an earlier call is still running aren't
+    /// queued behind it, and are answered with the last known state straight away.  What the
+    /// device supports is read when it's wrapped, so those never wait at all
+    ///
+    pub struct DeadlineDevice<T> {
+        pub(crate) device: SynchronizedDevice<T>,
+        capabilities: Capabilities,
+        deadline: Mutex<Duration>,
+        worker: mpsc::Sender<DeadlineJob>,
+        /// how many calls are queued or running on the worker thread
+        pending: Arc<AtomicUsize>,
+        /// numbers each call, so a call's result never replaces that of a later call
+        next_call: AtomicU64,
+        last_state: Arc<Mutex<Known<VirtualDeviceState>>>,
+        last_percent: Arc<Mutex<Known<u8>>>,
+    }
+
+    type DeadlineJob = Box<dyn FnOnce() + Send>;
+
+    ///
+    /// The last value a `DeadlineDevice` learned of, and which call it came from
+    ///
+    struct Known<V> {
+        call: u64,
+        value: Option<V>,
+    }
+
+    impl<V> Default for Known<V> {
+        fn default() -> Self {
+            Known {
+                call: 0,
+                value: None,
+            }
+        }
+    }
+
+    impl<V> Known<V> {
+        ///
+        /// Remember the `value` learned from `call`, unless a later call already said otherwise
+        ///
+        fn update(&mut self, call: u64, value: V) {
+            if call >= self.call {
+                self.call = call;
+                self.value = Some(value);
+            }
+        }
+    }
+
+    impl<T: VirtualDevice> DeadlineDevice<T> {
+        pub fn new(device: T) -> Self {
+            let (worker, jobs) = mpsc::channel::<DeadlineJob>();
+            // the thread exits once the device (and with it the sender) is gone
+            thread::Builder::new()
+                .name("rustmo-deadline".to_string())
+                .spawn(move || jobs.into_iter().for_each(|job| job()))
+                .expect("unable to start a DeadlineDevice worker thread");
+
+            DeadlineDevice {
+                capabilities: Capabilities::of(&device),
+                device: SynchronizedDevice::new(device),
+                deadline: Mutex::new(DEFAULT_DEADLINE),
+                worker,
+                pending: Default::default(),
+                next_call: AtomicU64::new(1),
+                last_state: Default::default(),
+                last_percent: Default::default(),
+            }
+        }
+
+        pub fn deadline(&self) -> Duration {
+            *self.deadline.lock()
+        }
+
+        ///
+        /// Change how long to wait for the device, which is 4 seconds by default.  This can be
+        /// changed while it's being served, as it's shared rather than behind a mutex
+        ///
+        pub fn set_deadline(&self, deadline: Duration) {
+            *self.deadline.lock() = deadline;
+        }
+
+        ///
+        /// Number the next call, so its results can be told apart from those of earlier calls
+        ///
+        fn next_call(&self) -> u64 {
+            self.next_call.fetch_add(1, Ordering::SeqCst)
+        }
+
+        ///
+        /// Queue `operation` on the worker thread, and wait up to the deadline for its result.
+        /// Returns `None` if it didn't finish in time, or if it's a `query` and the worker is
+        /// still busy with an earlier call
+        ///
+        fn call<R, F>(
+            &self,
+            name: &'static str,
+            query: bool,
+            operation: F,
+        ) -> Option<Result<R, VirtualDeviceError>>
+        where
+            R: Send + 'static,
+            F: FnOnce(&SynchronizedDevice<T>) -> Result<R, VirtualDeviceError> + Send + 'static,
+        {
+            if query && self.pending.load(Ordering::SeqCst) > 0 {
+                tracing::debug!("`{}` skipped while an earlier call is running", name);
+                return None;
+            }
+
+            let device = self.device.clone();
+            let pending = Arc::clone(&self.pending);
+            let (sender, receiver) = mpsc::channel();
+            pending.fetch_add(1, Ordering::SeqCst);
+            let job: DeadlineJob = Box::new(move || {
+                let started = Instant::now();
+                let result = panic::catch_unwind(AssertUnwindSafe(|| operation(&device)))
+                    .unwrap_or_else(|_| Err(VirtualDeviceError::new("panicked")));
+                pending.fetch_sub(1, Ordering::SeqCst);
+                if let Err(mpsc::SendError(result)) = sender.send(result) {
+                    tracing::warn!(
+                        "`{}` finished after {:?}, past its deadline: {}",
+                        name,
+                        started.elapsed(),
+                        match result {
+                            Ok(_) => "succeeded".to_string(),
+                            Err(e) => format!("failed: {e}"),
+                        }
+                    );
+                }
+            });
+            if self.worker.send(job).is_err() {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                return Some(Err(VirtualDeviceError::new("the worker thread is gone")));
+            }
+
+            let deadline = self.deadline();
+            match receiver.recv_timeout(deadline) {
+                Ok(result) => Some(result),
+                Err(_) => {
+                    tracing::warn!("`{}` didn't finish within {:?}", name, deadline);
+                    None
+                }
+            }
+        }
+
+        ///
+        /// Call an `operation` that returns the device's state, remembering the state whenever
+        /// it finishes (unless a later call has finished first).  If it doesn't finish in time,
+        /// answer with the `intended` state, if there is one, or else the last known state
+        ///
+        fn call_for_state<F>(
+            &self,
+            name: &'static str,
+            query: bool,
+            intended: Option<VirtualDeviceState>,
+            operation: F,
+        ) -> Result<VirtualDeviceState, VirtualDeviceError>
+        where
+            F: FnOnce(&SynchronizedDevice<T>) -> Result<VirtualDeviceState, VirtualDeviceError>
+                + Send
+                + 'static,
+        {
+            let call = self.next_call();
+            let last_state = Arc::clone(&self.last_state);
+            let result = self.call(name, query, move |device| {
+                let result = operation(device);
+                if let Ok(state) = result {
+                    last_state.lock().update(call, state);
+                }
+                result
+            });
+
+            result.unwrap_or_else(|| {
+                let mut last_state = self.last_state.lock();
+                if let Some(intended) = intended {
+                    last_state.update(call, intended);
+                }
+                last_state.value.ok_or_else(|| {
+                    VirtualDeviceError::from(format!(
+                        "`{name}` didn't answer in time, and the state is unknown"
+                    ))
+                })
+            })
+        }
+    }
+
+    impl<T: VirtualDevice> VirtualDevice for DeadlineDevice<T> {
+        fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.call_for_state("turn_on", false, Some(VirtualDeviceState::On), |device| {
+                device.turn_on()
+            })
+        }
+
+        fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.call_for_state("turn_off", false, Some(VirtualDeviceState::Off), |device| {
+                device.turn_off()
+            })
+        }
+
+        fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.call_for_state("check_is_on", true, None, |device| device.check_is_on())
+        }
+
+        fn supports_percent(&self) -> bool {
+            self.capabilities.percent
+        }
+
+        fn set_percent(&self, percent: u8) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            let intended = if percent == 0 {
+                VirtualDeviceState::Off
+            } else {
+                VirtualDeviceState::On
+            };
+            let call = self.next_call();
+            let last_percent = Arc::clone(&self.last_percent);
+            self.call_for_state("set_percent", false, Some(intended), move |device| {
+                let result = device.set_percent(percent);
+                if result.is_ok() {
+                    last_percent.lock().update(call, percent);
+                }
+                result
+            })
+            .inspect(|_| self.last_percent.lock().update(call, percent))
+        }
+
+        fn check_percent(&self) -> Result<Option<u8>, VirtualDeviceError> {
+            let call = self.next_call();
+            let last_percent = Arc::clone(&self.last_percent);
+            let result = self.call("check_percent", true, move |device| {
+                let result = device.check_percent();
+                if let Ok(Some(percent)) = result {
+                    last_percent.lock().update(call, percent);
+                }
+                result
+            });
+            result.unwrap_or_else(|| Ok(self.last_percent.lock().value))
+        }
+
+        fn dimmer_exposure(&self) -> DimmerExposure {
+            self.capabilities.dimmer_exposure
+        }
+
+        fn supports_color_temperature(&self) -> bool {
+            self.capabilities.color_temperature
+        }
+
+        fn set_color_temperature(
+            &self,
+            mireds: u16,
+        ) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.call_for_state("set_color_temperature", false, None, move |device| {
+                device.set_color_temperature(mireds)
+            })
+        }
+
+        fn check_color_temperature(&self) -> Result<Option<u16>, VirtualDeviceError> {
+            // an unknown temperature is better than no answer
+            self.call("check_color_temperature", true, |device| {
+                device.check_color_temperature()
+            })
+            .unwrap_or(Ok(None))
+        }
+
+        fn supports_color(&self) -> bool {
+            self.capabilities.color
+        }
+
+        fn set_color(&self, color: Color) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.call_for_state("set_color", false, None, move |device| {
+                device.set_color(color)
+            })
+        }
+
+        fn check_color(&self) -> Result<Option<Color>, VirtualDeviceError> {
+            self.call("check_color", true, |device| device.check_color())
+                .unwrap_or(Ok(None))
+        }
+    }
+
+    /// how long a `MomentaryDevice` reports that it's on by default, long enough for Alexa to see
+    /// that it was turned on
+    const DEFAULT_MOMENTARY_WINDOW: Duration = Duration::from_secs(5);
+
+    ///
+    /// Wrapper for a one-shot action, like pressing a remote's "menu" button, that fires when
+    /// Alexa turns the device on.  It reports that it's on for a short window afterwards, so
+    /// Alexa sees the change it asked for, and then goes back to off by itself.  Turning it off
+    /// doesn't fire anything, but ends that window early
+    ///
+    pub struct MomentaryDevice {
+        pub(crate) action: DeviceAction,
+        window: Duration,
+        fired_at: Mutex<Option<Instant>>,
+    }
+
+    impl MomentaryDevice {
+        pub fn new(action: DeviceAction) -> Self {
+            MomentaryDevice {
+                action,
+                window: DEFAULT_MOMENTARY_WINDOW,
+                fired_at: Mutex::new(None),
+            }
+        }
+
+        pub fn window(&self) -> Duration {
+            self.window
+        }
+
+        ///
+        /// Change how long the device reports that it's on after it fires, which is 5 seconds
+        /// by default
+        ///
+        pub fn set_window(&mut self, window: Duration) {
+            self.window = window;
+        }
+    }
+
+    impl VirtualDevice for MomentaryDevice {
+        fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            (self.action)()?;
+            *self.fired_at.lock() = Some(Instant::now());
+            Ok(VirtualDeviceState::On)
+        }
+
+        fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            *self.fired_at.lock() = None;
+            Ok(VirtualDeviceState::Off)
+        }
+
+        fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            match *self.fired_at.lock() {
+                Some(fired_at) if fired_at.elapsed() < self.window => Ok(VirtualDeviceState::On),
+                _ => Ok(VirtualDeviceState::Off),
+            }
+        }
+    }
+
+    ///
+    /// Wrapper for `VirtualDevice` that allows a device to be implemented using closures
+    pub struct FunctionalDevice<TurnOn, TurnOff, CheckIsOn>
+    where
+        TurnOn: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
+        TurnOff: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
+        CheckIsOn: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
+    {
+        pub(crate) turn_on: TurnOn,
+        pub(crate) turn_off: TurnOff,
+        pub(crate) check_is_on: CheckIsOn,
+    }
+
+    impl<TurnOn, TurnOff, CheckIsOn> VirtualDevice for FunctionalDevice<TurnOn, TurnOff, CheckIsOn>
+    where
+        TurnOn: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
+        TurnOff: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
+        CheckIsOn: Fn() -> Result<VirtualDeviceState, VirtualDeviceError> + Sync + Send + 'static,
+    {
+        fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            (self.turn_on)()
+        }
+
+        fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            (self.turn_off)()
+        }
+
+        fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            (self.check_is_on)()
+        }
+    }
+}
+
+/// the closure a `SelectorGroup` asks which of its members is active
+type SelectorQuery<K> = Box<dyn Fn() -> Result<Option<K>, VirtualDeviceError> + Send + Sync>;
+
+/// a `SelectorGroup` member's device, shared by the member and the group
+type SelectorDevice = SynchronizedDevice<Box<dyn VirtualDevice>>;
+
+struct SelectorGroupInner<K> {
+    query: SelectorQuery<K>,
+    members: Mutex<Vec<(K, SelectorDevice)>>,
+}
+
+///
+/// A set of mutually exclusive devices, like a receiver's inputs, only one of which can be on
+/// at a time.
+///
+/// Each member, identified by a key (such as an input number), is added to a `RustmoServer` as
+/// a device in its own right.  Turning one on turns off the member that was active before it,
+/// and a member is on when the group's query closure (such as one that asks the receiver for
+/// its current input) returns its key
+///
+pub struct SelectorGroup<K> {
+    inner: Arc<SelectorGroupInner<K>>,
+}
+
+impl<K> Clone for SelectorGroup<K> {
+    fn clone(&self) -> Self {
+        SelectorGroup {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<K> SelectorGroup<K>
+where
+    K: Clone + Debug + PartialEq + Send + Sync + 'static,
+{
+    ///
+    /// A group with no members yet, whose active member's key is returned by `query`, or `None`
+    /// if no member is active
+    ///
+    pub fn new<Q>(query: Q) -> Self
+    where
+        Q: Fn() -> Result<Option<K>, VirtualDeviceError> + Send + Sync + 'static,
+    {
+        SelectorGroup {
+            inner: Arc::new(SelectorGroupInner {
+                query: Box::new(query),
+                members: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    ///
+    /// Add `device` to the group as the member identified by `key`.  The returned
+    /// `SelectorMember` is what should be added to the `RustmoServer`.
+    ///
+    /// The member's own `::check_is_on()` is never called, as the group's query closure
+    /// answers for it
+    ///
+    pub fn member<T: VirtualDevice>(&self, key: K, device: T) -> SelectorMember<K> {
+        let device = SynchronizedDevice::new(Box::new(device) as Box<dyn VirtualDevice>);
+        let member = SelectorMember {
+            key,
+            device: device.clone(),
+            group: Arc::clone(&self.inner),
+        };
+        self.inner.members.lock().push((member.key.clone(), device));
+        member
+    }
+}
+
+///
+/// A member of a `SelectorGroup`
+///
+pub struct SelectorMember<K> {
+    key: K,
+    device: SelectorDevice,
+    group: Arc<SelectorGroupInner<K>>,
+}
+
+impl<K> VirtualDevice for SelectorMember<K>
+where
+    K: Debug + PartialEq + Send + Sync + 'static,
+{
+    fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+        let active = (self.group.query)();
+        self.device.turn_on()?;
+
+        let siblings = self
+            .group
+            .members
+            .lock()
+            .iter()
+            .filter(|(key, _)| *key != self.key)
+            .filter(|(key, _)| match &active {
+                Ok(active) => active.as_ref() == Some(key),
+                // we don't know which was active, so make sure none of them are
+                Err(_) => true,
+            })
+            .map(|(key, device)| (format!("{key:?}"), device.clone()))
+            .collect::<Vec<_>>();
+        for (key, sibling) in siblings {
+            tracing::debug!("turning off selector member {} for {:?}", key, self.key);
+            if let Err(e) = sibling.turn_off() {
+                tracing::warn!("unable to turn off selector member {}: {}", key, e);
+            }
+        }
+
+        Ok(VirtualDeviceState::On)
+    }
+
+    fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+        self.device.turn_off()?;
+        Ok(VirtualDeviceState::Off)
+    }
+
+    fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+        if (self.group.query)()?.as_ref() == Some(&self.key) {
+            Ok(VirtualDeviceState::On)
+        } else {
+            Ok(VirtualDeviceState::Off)
+        }
+    }
+}
+
+///
 /// [`SynchronizedDevice`] represents a `VirtualDevice` implementation
 /// that is reference counted and guarded by a mutex, so that it can
 /// be shared across threads
@@ -509,6 +1823,34 @@ where
     fn check_percent(&self) -> Result<Option<u8>, VirtualDeviceError> {
         self.lock().check_percent()
     }
+
+    fn dimmer_exposure(&self) -> DimmerExposure {
+        self.lock().dimmer_exposure()
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.lock().supports_color_temperature()
+    }
+
+    fn set_color_temperature(&self, mireds: u16) -> Result<VirtualDeviceState, VirtualDeviceError> {
+        self.lock().set_color_temperature(mireds)
+    }
+
+    fn check_color_temperature(&self) -> Result<Option<u16>, VirtualDeviceError> {
+        self.lock().check_color_temperature()
+    }
+
+    fn supports_color(&self) -> bool {
+        self.lock().supports_color()
+    }
+
+    fn set_color(&self, color: Color) -> Result<VirtualDeviceState, VirtualDeviceError> {
+        self.lock().set_color(color)
+    }
+
+    fn check_color(&self) -> Result<Option<Color>, VirtualDeviceError> {
+        self.lock().check_color()
+    }
 }
 
 impl VirtualDevice for Box<dyn VirtualDevice> {
@@ -535,4 +1877,412 @@ impl VirtualDevice for Box<dyn VirtualDevice> {
     fn check_percent(&self) -> Result<Option<u8>, VirtualDeviceError> {
         self.deref().check_percent()
     }
+
+    fn dimmer_exposure(&self) -> DimmerExposure {
+        self.deref().dimmer_exposure()
+    }
+
+    fn supports_color_temperature(&self) -> bool {
+        self.deref().supports_color_temperature()
+    }
+
+    fn set_color_temperature(&self, mireds: u16) -> Result<VirtualDeviceState, VirtualDeviceError> {
+        self.deref().set_color_temperature(mireds)
+    }
+
+    fn check_color_temperature(&self) -> Result<Option<u16>, VirtualDeviceError> {
+        self.deref().check_color_temperature()
+    }
+
+    fn supports_color(&self) -> bool {
+        self.deref().supports_color()
+    }
+
+    fn set_color(&self, color: Color) -> Result<VirtualDeviceState, VirtualDeviceError> {
+        self.deref().set_color(color)
+    }
+
+    fn check_color(&self) -> Result<Option<Color>, VirtualDeviceError> {
+        self.deref().check_color()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::wrappers::{
+        CompositeDevice, DeadlineDevice, FunctionalDevice, MomentaryDevice, SequenceDevice,
+    };
+    use super::*;
+
+    #[test]
+    fn deadline_devices_answer_in_time() {
+        let delay = Duration::from_millis(200);
+        let switch = Arc::new(Switch::default());
+        let (on, off, check) = (switch.clone(), switch.clone(), switch.clone());
+        let slow = DeadlineDevice::new(FunctionalDevice {
+            turn_on: move || {
+                thread::sleep(delay);
+                on.turn_on()
+            },
+            turn_off: move || {
+                thread::sleep(delay);
+                off.turn_off()
+            },
+            check_is_on: move || {
+                thread::sleep(delay);
+                check.check_is_on()
+            },
+        });
+        slow.set_deadline(Duration::from_millis(50));
+
+        // nothing is known about the device yet
+        assert!(slow.check_is_on().is_err());
+
+        let started = Instant::now();
+        assert_eq!(slow.turn_on(), Ok(VirtualDeviceState::On));
+        assert_eq!(slow.check_is_on(), Ok(VirtualDeviceState::On));
+        assert!(started.elapsed() < delay);
+        assert_eq!(switch.check_is_on(), Ok(VirtualDeviceState::Off));
+
+        // the first query finishes while `turn_on` is running, but doesn't undo it, and queries
+        // made in the meantime aren't queued
+        thread::sleep(delay);
+        assert_eq!(slow.check_is_on(), Ok(VirtualDeviceState::On));
+
+        // the late calls carry on, one after the other
+        thread::sleep(delay * 4);
+        assert_eq!(switch.check_is_on(), Ok(VirtualDeviceState::On));
+
+        slow.set_deadline(Duration::from_secs(2));
+        assert_eq!(slow.turn_off(), Ok(VirtualDeviceState::Off));
+        assert_eq!(switch.check_is_on(), Ok(VirtualDeviceState::Off));
+    }
+
+    #[test]
+    fn deadline_devices_answer_capabilities_while_a_call_hangs() {
+        struct HungDimmer;
+        impl VirtualDevice for HungDimmer {
+            fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                thread::sleep(Duration::from_secs(2));
+                Ok(VirtualDeviceState::On)
+            }
+
+            fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                Ok(VirtualDeviceState::Off)
+            }
+
+            fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+                Ok(VirtualDeviceState::Off)
+            }
+
+            fn supports_percent(&self) -> bool {
+                true
+            }
+        }
+
+        let dimmer = DeadlineDevice::new(HungDimmer);
+        dimmer.set_deadline(Duration::from_millis(50));
+        assert_eq!(dimmer.turn_on(), Ok(VirtualDeviceState::On));
+
+        let started = Instant::now();
+        assert!(dimmer.supports_percent());
+        assert_eq!(dimmer.dimmer_exposure(), DimmerExposure::default());
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn momentary_devices_fire_and_revert_to_off() {
+        let presses = Arc::new(Mutex::new(0));
+        let counter = presses.clone();
+        let mut menu = MomentaryDevice::new(Box::new(move || {
+            *counter.lock() += 1;
+            Ok(())
+        }));
+        menu.set_window(Duration::from_millis(50));
+
+        assert_eq!(menu.check_is_on(), Ok(VirtualDeviceState::Off));
+        assert_eq!(menu.turn_on(), Ok(VirtualDeviceState::On));
+        assert_eq!(menu.check_is_on(), Ok(VirtualDeviceState::On));
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(menu.check_is_on(), Ok(VirtualDeviceState::Off));
+        assert_eq!(menu.turn_off(), Ok(VirtualDeviceState::Off));
+        assert_eq!(*presses.lock(), 1);
+
+        let broken = MomentaryDevice::new(Box::new(|| Err(VirtualDeviceError::new("no IR"))));
+        assert!(broken.turn_on().is_err());
+        assert_eq!(broken.check_is_on(), Ok(VirtualDeviceState::Off));
+    }
+
+    #[test]
+    fn turning_on_a_selector_member_turns_off_the_active_one() {
+        let input = Arc::new(Mutex::new(Some(1)));
+        let turned_off = Arc::new(Mutex::new(Vec::new()));
+        let query_input = input.clone();
+        let inputs = SelectorGroup::new(move || Ok(*query_input.lock()));
+        let members = (1..=3)
+            .map(|key| {
+                let (input, turned_off) = (input.clone(), turned_off.clone());
+                inputs.member(
+                    key,
+                    FunctionalDevice {
+                        turn_on: move || {
+                            *input.lock() = Some(key);
+                            Ok(VirtualDeviceState::On)
+                        },
+                        turn_off: move || {
+                            turned_off.lock().push(key);
+                            Ok(VirtualDeviceState::Off)
+                        },
+                        check_is_on: || unreachable!(),
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(members[0].check_is_on(), Ok(VirtualDeviceState::On));
+        assert_eq!(members[2].turn_on(), Ok(VirtualDeviceState::On));
+        assert_eq!(*turned_off.lock(), vec![1]);
+        assert_eq!(members[0].check_is_on(), Ok(VirtualDeviceState::Off));
+        assert_eq!(members[1].check_is_on(), Ok(VirtualDeviceState::Off));
+        assert_eq!(members[2].check_is_on(), Ok(VirtualDeviceState::On));
+    }
+
+    #[test]
+    fn selector_members_are_all_turned_off_if_the_active_one_is_unknown() {
+        let turned_off = Arc::new(Mutex::new(Vec::new()));
+        let sources = SelectorGroup::new(|| Err(VirtualDeviceError::new("no answer")));
+        let members = ["kaleidescape", "apple tv", "roku"]
+            .into_iter()
+            .map(|key| {
+                let turned_off = turned_off.clone();
+                sources.member(
+                    key,
+                    FunctionalDevice {
+                        turn_on: || Ok(VirtualDeviceState::On),
+                        turn_off: move || {
+                            turned_off.lock().push(key);
+                            Ok(VirtualDeviceState::Off)
+                        },
+                        check_is_on: || unreachable!(),
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(members[1].turn_on(), Ok(VirtualDeviceState::On));
+        assert_eq!(*turned_off.lock(), vec!["kaleidescape", "roku"]);
+        assert!(members[1].check_is_on().is_err());
+    }
+
+    fn member(delay: Duration, fails: bool) -> Box<dyn VirtualDevice> {
+        Box::new(FunctionalDevice {
+            turn_on: move || {
+                thread::sleep(delay);
+                if fails {
+                    Err(VirtualDeviceError::new("unreachable"))
+                } else {
+                    Ok(VirtualDeviceState::On)
+                }
+            },
+            turn_off: || Ok(VirtualDeviceState::Off),
+            check_is_on: || Ok(VirtualDeviceState::Off),
+        })
+    }
+
+    #[test]
+    fn colors_convert_between_hue_saturation_and_xy() {
+        let red = Color::HueSaturation {
+            hue: 0,
+            saturation: 254,
+        };
+        let (x, y) = red.to_xy();
+        assert!((x - 0.7006).abs() < 0.01 && (y - 0.2993).abs() < 0.01);
+
+        let (hue, saturation) = Color::Xy { x, y }.to_hue_saturation();
+        assert!(!(500..65035).contains(&hue), "hue={hue}");
+        assert!(saturation > 245, "saturation={saturation}");
+
+        let green = Color::HueSaturation {
+            hue: 21845,
+            saturation: 254,
+        };
+        let (x, y) = green.to_xy();
+        let (hue, _) = Color::Xy { x, y }.to_hue_saturation();
+        assert!((21345..22345).contains(&hue), "hue={hue}");
+    }
+
+    #[test]
+    fn composite_members_are_called_in_parallel() {
+        let delay = Duration::from_millis(200);
+        let group = CompositeDevice::new(vec![
+            member(delay, false),
+            member(delay, false),
+            member(delay, false),
+        ]);
+
+        let started = Instant::now();
+        assert_eq!(group.turn_on(), Ok(VirtualDeviceState::On));
+        assert!(
+            started.elapsed() < delay * 2,
+            "took {:?}",
+            started.elapsed()
+        );
+        assert!(group.last_report().unwrap().failures.is_empty());
+    }
+
+    #[test]
+    fn composite_succeeds_with_a_quorum() {
+        let mut group = CompositeDevice::new(vec![
+            member(Duration::ZERO, false),
+            member(Duration::ZERO, true),
+            member(Duration::ZERO, false),
+        ]);
+        assert!(group.turn_on().is_err());
+
+        group.set_quorum(Quorum::Majority);
+        assert_eq!(group.turn_on(), Ok(VirtualDeviceState::On));
+        let report = group.last_report().unwrap();
+        assert_eq!(report.operation, "turn_on");
+        assert_eq!((report.succeeded(), report.required), (2, 2));
+        assert_eq!(
+            report.failures,
+            vec![(
+                1,
+                MemberFailure::Failed(VirtualDeviceError::new("unreachable"))
+            )]
+        );
+    }
+
+    #[test]
+    fn busy_composite_members_are_not_called_again() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let hangs: Box<dyn VirtualDevice> = Box::new(FunctionalDevice {
+            turn_on: move || {
+                counted.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(300));
+                Ok(VirtualDeviceState::On)
+            },
+            turn_off: || Ok(VirtualDeviceState::Off),
+            check_is_on: || Ok(VirtualDeviceState::Off),
+        });
+        let mut group = CompositeDevice::new(vec![member(Duration::ZERO, false), hangs]);
+        group.set_member_deadline(Duration::from_millis(50));
+        group.set_quorum(Quorum::AtLeast(1));
+
+        for _ in 0..3 {
+            assert_eq!(group.turn_on(), Ok(VirtualDeviceState::On));
+            let report = group.last_report().unwrap();
+            assert_eq!(report.failures, vec![(1, MemberFailure::TimedOut)]);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        thread::sleep(Duration::from_millis(400));
+        assert!(group.turn_on().is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[derive(Default)]
+    struct Switch(AtomicBool);
+
+    impl VirtualDevice for Switch {
+        fn turn_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.0.store(true, Ordering::SeqCst);
+            Ok(VirtualDeviceState::On)
+        }
+
+        fn turn_off(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            self.0.store(false, Ordering::SeqCst);
+            Ok(VirtualDeviceState::Off)
+        }
+
+        fn check_is_on(&self) -> Result<VirtualDeviceState, VirtualDeviceError> {
+            Ok(match self.0.load(Ordering::SeqCst) {
+                true => VirtualDeviceState::On,
+                false => VirtualDeviceState::Off,
+            })
+        }
+    }
+
+    #[test]
+    fn sequences_run_in_order_and_in_reverse() {
+        let receiver = SynchronizedDevice::new(Switch::default());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (on_log, off_log) = (log.clone(), log.clone());
+        let input_receiver = receiver.clone();
+        let sequence = SequenceDevice::new(vec![
+            SequenceStep::device(receiver.clone()),
+            SequenceStep::wait_until_on(receiver.clone(), Duration::from_secs(1)),
+            SequenceStep::Delay(Duration::from_millis(10)),
+            SequenceStep::reversible_action(
+                move || {
+                    // the receiver has to be on before its input can be changed
+                    assert_eq!(input_receiver.check_is_on(), Ok(VirtualDeviceState::On));
+                    on_log.lock().push("input 3");
+                    Ok(())
+                },
+                move || {
+                    off_log.lock().push("input 1");
+                    Ok(())
+                },
+            ),
+        ]);
+
+        assert_eq!(sequence.turn_on(), Ok(VirtualDeviceState::On));
+        assert_eq!(sequence.check_is_on(), Ok(VirtualDeviceState::On));
+        assert_eq!(sequence.turn_off(), Ok(VirtualDeviceState::Off));
+        assert_eq!(receiver.check_is_on(), Ok(VirtualDeviceState::Off));
+        assert_eq!(*log.lock(), vec!["input 3", "input 1"]);
+    }
+
+    #[test]
+    fn sequences_stop_at_a_failed_step() {
+        let receiver = SynchronizedDevice::new(Switch::default());
+        let sequence = SequenceDevice::new(vec![
+            SequenceStep::action(|| Err(VirtualDeviceError::new("no power"))),
+            SequenceStep::device(receiver.clone()),
+        ]);
+        assert!(sequence.turn_on().is_err());
+        assert_eq!(receiver.check_is_on(), Ok(VirtualDeviceState::Off));
+
+        let sequence = SequenceDevice::new(vec![SequenceStep::wait_until_on(
+            receiver,
+            Duration::from_millis(50),
+        )]);
+        assert!(sequence.turn_on().is_err());
+    }
+
+    #[test]
+    fn composite_members_that_hang_or_panic_fail() {
+        let panics: Box<dyn VirtualDevice> = Box::new(FunctionalDevice {
+            turn_on: || panic!("projector on fire"),
+            turn_off: || Ok(VirtualDeviceState::Off),
+            check_is_on: || Ok(VirtualDeviceState::Off),
+        });
+        let mut group = CompositeDevice::new(vec![
+            member(Duration::ZERO, false),
+            member(Duration::from_secs(2), false),
+            panics,
+        ]);
+        group.set_member_deadline(Duration::from_millis(100));
+        group.set_quorum(Quorum::AtLeast(1));
+
+        let started = Instant::now();
+        assert_eq!(group.turn_on(), Ok(VirtualDeviceState::On));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let report = group.last_report().unwrap();
+        assert_eq!(report.failures.len(), 2);
+        assert_eq!(report.failures[0], (1, MemberFailure::TimedOut));
+        assert_eq!(
+            report.failures[1],
+            (
+                2,
+                MemberFailure::Failed(VirtualDeviceError::new("panicked"))
+            )
+        );
+    }
 }
@@ -0,0 +1,183 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::json_file::{load_entries, save_entries};
+
+/// how long the Hue bridge's link button stays "pressed"
+pub(crate) const LINK_BUTTON_WINDOW: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct WhitelistEntry {
+    username: String,
+    devicetype: String,
+}
+
+///
+/// Why a Hue app's request to pair with the bridge was refused
+///
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PairingError {
+    /// the link button hasn't been pressed in the last 30 seconds
+    LinkButtonNotPressed,
+
+    /// the whitelist couldn't be saved
+    Io(String),
+}
+
+///
+/// `HueWhitelist` holds the usernames that Hue apps (such as Alexa) have been given by pairing
+/// with the emulated Hue bridge.
+///
+/// Apps can only pair while the bridge's link button is "pressed", which lasts for 30 seconds
+/// after `::press_link_button()`.  Any API request made with a username that isn't on the
+/// whitelist is refused.
+///
+/// When backed by a file, the whitelist is persisted as JSON every time it changes, so apps
+/// don't need to pair again after a restart.
+///
+#[derive(Default)]
+pub(crate) struct HueWhitelist {
+    path: Option<PathBuf>,
+    entries: Vec<WhitelistEntry>,
+    link_button_pressed_at: Option<Instant>,
+}
+
+impl HueWhitelist {
+    ///
+    /// Load the whitelist from `path`, which doesn't need to exist yet.  Usernames already known
+    /// to this whitelist are kept, and written back to it
+    ///
+    pub(crate) fn load<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = load_entries::<WhitelistEntry>(&path)?;
+
+        for known in &self.entries {
+            if !entries.iter().any(|entry| entry.username == known.username) {
+                entries.push(known.clone());
+            }
+        }
+
+        tracing::info!(
+            "loaded {} Hue usernames from {}",
+            entries.len(),
+            path.display()
+        );
+        self.path = Some(path);
+        self.entries = entries;
+        self.save()
+    }
+
+    ///
+    /// Open the 30 second window during which Hue apps may pair with the bridge
+    ///
+    pub(crate) fn press_link_button(&mut self) {
+        tracing::info!(
+            "Hue bridge link button pressed, pairing is allowed for {} seconds",
+            LINK_BUTTON_WINDOW.as_secs()
+        );
+        self.link_button_pressed_at = Some(Instant::now());
+    }
+
+    pub(crate) fn is_link_button_pressed(&self) -> bool {
+        self.link_button_pressed_at
+            .is_some_and(|pressed_at| pressed_at.elapsed() < LINK_BUTTON_WINDOW)
+    }
+
+    ///
+    /// Generate (and persist) a new username for the app identifying itself as `devicetype`, as
+    /// long as the link button has been pressed
+    ///
+    pub(crate) fn pair(&mut self, devicetype: &str) -> Result<String, PairingError> {
+        if !self.is_link_button_pressed() {
+            return Err(PairingError::LinkButtonNotPressed);
+        }
+
+        let username = format!(
+            "{}{}",
+            Uuid::new_v4().simple(),
+            &Uuid::new_v4().simple().to_string()[..8]
+        );
+        self.entries.push(WhitelistEntry {
+            username: username.clone(),
+            devicetype: devicetype.to_string(),
+        });
+        self.save().map_err(|e| PairingError::Io(e.to_string()))?;
+        tracing::info!("Hue bridge paired with `{}`", devicetype);
+        Ok(username)
+    }
+
+    pub(crate) fn is_authorized(&self, username: &str) -> bool {
+        self.entries.iter().any(|entry| entry.username == username)
+    }
+
+    ///
+    /// The whitelist as the bridge's `config` describes it
+    ///
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        self.entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.username.clone(),
+                    serde_json::json!({ "name": entry.devicetype }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        match self.path.as_ref() {
+            Some(path) => save_entries(path, &self.entries),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn pairing_requires_the_link_button() {
+        let mut whitelist = HueWhitelist::default();
+        assert_eq!(
+            whitelist.pair("Echo#Kitchen"),
+            Err(PairingError::LinkButtonNotPressed)
+        );
+
+        whitelist.press_link_button();
+        let username = whitelist.pair("Echo#Kitchen").unwrap();
+        assert!(whitelist.is_authorized(&username));
+        assert!(!whitelist.is_authorized("rustmo"));
+
+        whitelist.link_button_pressed_at = Instant::now().checked_sub(LINK_BUTTON_WINDOW);
+        assert_eq!(
+            whitelist.pair("Echo#Theater"),
+            Err(PairingError::LinkButtonNotPressed)
+        );
+    }
+
+    #[test]
+    fn usernames_survive_a_restart() {
+        let path =
+            std::env::temp_dir().join(format!("rustmo-whitelist-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut whitelist = HueWhitelist::default();
+        whitelist.load(&path).unwrap();
+        whitelist.press_link_button();
+        let username = whitelist.pair("Echo#Kitchen").unwrap();
+
+        let mut whitelist = HueWhitelist::default();
+        whitelist.load(&path).unwrap();
+        assert!(whitelist.is_authorized(&username));
+        assert!(!whitelist.is_link_button_pressed());
+
+        fs::remove_file(&path).unwrap();
+    }
+}